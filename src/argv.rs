@@ -0,0 +1,265 @@
+use clap::Command;
+use std::ffi::OsString;
+
+/// Argument ids whose values are secret, and whether only the part after
+/// the first `=` of the value is (as in `KEY=VALUE`).
+const SECRETS: &[(&str, bool)] = &[("password", false), ("set_env", true)];
+
+/// Byte written over secrets, as the original sshpass does.
+const FILLER: u8 = b'z';
+
+/// A secret in the command line: the argument index and the byte offset
+/// where the secret starts within that argument.
+#[derive(Debug, PartialEq)]
+pub struct Span {
+    pub index: usize,
+    pub offset: usize,
+}
+
+/// Finds the secrets among the options of `args`, as parsed by `command`,
+/// following subcommands into their own options. Stops at the first other
+/// positional argument, which starts the command to run.
+pub fn secret_spans(command: &Command, args: &[OsString]) -> Vec<Span> {
+    let mut spans = Vec::new();
+    let mut command = command;
+    let mut index = 1;
+    while index < args.len() {
+        let arg = args[index].as_encoded_bytes();
+        index += 1;
+        if arg == b"--" || arg == b"-" {
+            break;
+        }
+        if !arg.starts_with(b"-") {
+            match command.find_subcommand(&args[index - 1]) {
+                Some(subcommand) => {
+                    command = subcommand;
+                    continue;
+                }
+                None => break,
+            }
+        }
+
+        let (option, attached) = if let Some(long) = arg.strip_prefix(b"--") {
+            let (name, value) = match long.iter().position(|&b| b == b'=') {
+                Some(pos) => (&long[..pos], Some(pos + 3)),
+                None => (long, None),
+            };
+            let option = command
+                .get_arguments()
+                .find(|a| a.get_long().is_some_and(|l| l.as_bytes() == name));
+            (option, value)
+        } else {
+            // Flags can be clustered, an option with a value ends the cluster.
+            // All short flags are ASCII.
+            let mut found = (None, None);
+            for (pos, &c) in arg.iter().enumerate().skip(1) {
+                let option = command
+                    .get_arguments()
+                    .find(|a| a.get_short() == Some(char::from(c)) && c.is_ascii());
+                if option.is_some_and(|a| a.get_action().takes_values()) {
+                    let rest = pos + 1;
+                    let rest = if arg[rest..].starts_with(b"=") {
+                        rest + 1
+                    } else {
+                        rest
+                    };
+                    found = (
+                        option,
+                        (rest < arg.len() || arg.ends_with(b"=")).then_some(rest),
+                    );
+                    break;
+                }
+            }
+            found
+        };
+
+        let Some(option) = option.filter(|a| a.get_action().takes_values()) else {
+            continue;
+        };
+        let value = match attached {
+            Some(offset) => Some(Span {
+                index: index - 1,
+                offset,
+            }),
+            None if option.is_require_equals_set() => None,
+            None if index < args.len() => {
+                index += 1;
+                Some(Span {
+                    index: index - 1,
+                    offset: 0,
+                })
+            }
+            None => None,
+        };
+        let Some(mut span) = value else {
+            continue;
+        };
+        let Some(&(_, after_equals)) = SECRETS.iter().find(|(id, _)| option.get_id() == *id) else {
+            continue;
+        };
+        if after_equals {
+            let value = &args[span.index].as_encoded_bytes()[span.offset..];
+            match value.iter().position(|&b| b == b'=') {
+                Some(pos) => span.offset += pos + 1,
+                None => continue,
+            }
+        }
+        spans.push(span);
+    }
+    spans
+}
+
+/// Overwrites the secrets in the process's own argument memory so that
+/// they no longer show in `ps` or `/proc/<pid>/cmdline`. `args` must be
+/// the arguments as read at startup. Returns false where that memory is
+/// out of reach.
+pub fn conceal(args: &[OsString], spans: &[Span]) -> bool {
+    let Some(argv) = original::argv() else {
+        return false;
+    };
+    for span in spans {
+        let Some(&ptr) = argv.get(span.index) else {
+            return false;
+        };
+        // SAFETY: argv entries are NUL-terminated strings owned by the
+        // process for its whole lifetime.
+        let arg = unsafe { std::ffi::CStr::from_ptr(ptr) };
+        if arg.to_bytes() != args[span.index].as_encoded_bytes() {
+            return false;
+        }
+        let len = arg.to_bytes().len();
+        // SAFETY: stays within the string found above, before its NUL.
+        unsafe {
+            std::ptr::write_bytes(ptr.cast::<u8>().add(span.offset), FILLER, len - span.offset);
+        }
+    }
+    true
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+mod original {
+    use std::ffi::c_char;
+    use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+    static ARGC: AtomicUsize = AtomicUsize::new(0);
+    static ARGV: AtomicPtr<*mut c_char> = AtomicPtr::new(std::ptr::null_mut());
+
+    /// glibc passes the real argc and argv to `.init_array` functions;
+    /// Rust's standard library only keeps a copy of the strings.
+    #[used]
+    #[unsafe(link_section = ".init_array.00099")]
+    static CAPTURE: extern "C" fn(i32, *mut *mut c_char, *mut *mut c_char) = capture;
+
+    extern "C" fn capture(argc: i32, argv: *mut *mut c_char, _envp: *mut *mut c_char) {
+        ARGC.store(argc.max(0) as usize, Ordering::Relaxed);
+        ARGV.store(argv, Ordering::Relaxed);
+    }
+
+    pub fn argv() -> Option<Vec<*mut c_char>> {
+        let argv = ARGV.load(Ordering::Relaxed);
+        if argv.is_null() {
+            return None;
+        }
+        let argc = ARGC.load(Ordering::Relaxed);
+        // SAFETY: argv holds argc valid pointers, as handed over by glibc.
+        Some((0..argc).map(|i| unsafe { *argv.add(i) }).collect())
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+mod original {
+    pub fn argv() -> Option<Vec<*mut std::ffi::c_char>> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Arg, ArgAction};
+
+    fn command() -> Command {
+        Command::new("sshpass")
+            .arg(Arg::new("password").short('p'))
+            .arg(Arg::new("prompt").short('P'))
+            .arg(
+                Arg::new("env")
+                    .short('e')
+                    .num_args(0..=1)
+                    .require_equals(true),
+            )
+            .arg(Arg::new("set_env").long("env").action(ArgAction::Append))
+            .arg(Arg::new("check").long("check").action(ArgAction::SetTrue))
+            .arg(Arg::new("verbose").short('v').action(ArgAction::SetTrue))
+            .arg(Arg::new("command").num_args(1..).trailing_var_arg(true))
+            .subcommand(
+                Command::new("rotate")
+                    .arg(Arg::new("file").short('f'))
+                    .arg(Arg::new("set_env").long("env").action(ArgAction::Append))
+                    .arg(Arg::new("command").num_args(1..).trailing_var_arg(true)),
+            )
+    }
+
+    fn spans(args: &[&str]) -> Vec<(usize, usize)> {
+        let args: Vec<OsString> = args.iter().map(OsString::from).collect();
+        secret_spans(&command(), &args)
+            .into_iter()
+            .map(|s| (s.index, s.offset))
+            .collect()
+    }
+
+    #[test]
+    fn password_forms() {
+        assert_eq!(spans(&["sshpass", "-p", "pw", "ssh", "host"]), [(2, 0)]);
+        assert_eq!(spans(&["sshpass", "-ppw", "ssh"]), [(1, 2)]);
+        assert_eq!(spans(&["sshpass", "-p=pw", "ssh"]), [(1, 3)]);
+        assert_eq!(spans(&["sshpass", "-vppw", "ssh"]), [(1, 3)]);
+    }
+
+    #[test]
+    fn values_of_other_options_are_skipped() {
+        assert_eq!(spans(&["sshpass", "-P", "-p", "ssh", "host"]), []);
+        assert_eq!(spans(&["sshpass", "-e", "-p", "pw", "ssh"]), [(3, 0)]);
+        assert_eq!(spans(&["sshpass", "--check", "-p", "pw", "ssh"]), [(3, 0)]);
+    }
+
+    #[test]
+    fn command_arguments_are_left_alone() {
+        assert_eq!(spans(&["sshpass", "-e", "mysql", "-p", "pw"]), []);
+        assert_eq!(spans(&["sshpass", "-e", "--", "-p", "pw"]), []);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn arguments_need_not_be_utf8() {
+        use std::os::unix::ffi::OsStringExt;
+        let args = [
+            OsString::from("sshpass"),
+            OsString::from_vec(b"-vpcaf\xe9".to_vec()),
+            OsString::from_vec(b"\xe9cho".to_vec()),
+        ];
+        let spans: Vec<_> = secret_spans(&command(), &args)
+            .into_iter()
+            .map(|s| (s.index, s.offset))
+            .collect();
+        assert_eq!(spans, [(1, 3)]);
+    }
+
+    #[test]
+    fn only_environment_values_are_secret() {
+        assert_eq!(spans(&["sshpass", "--env", "TOKEN=t", "ssh"]), [(2, 6)]);
+        assert_eq!(spans(&["sshpass", "--env=TOKEN=t", "ssh"]), [(1, 12)]);
+    }
+
+    #[test]
+    fn subcommand_options() {
+        assert_eq!(
+            spans(&["sshpass", "rotate", "-f", "pw", "--env", "TOKEN=t", "ssh"]),
+            [(5, 6)]
+        );
+        assert_eq!(
+            spans(&["sshpass", "-e", "ssh", "rotate", "--env", "A=b"]),
+            []
+        );
+    }
+}
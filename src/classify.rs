@@ -0,0 +1,226 @@
+use regex::bytes::Regex;
+
+/// The host name could not be resolved. Fix the configuration.
+pub const RETURN_HOST_UNRESOLVED: i32 = 10;
+/// The server refused the connection. Try again later.
+pub const RETURN_CONNECTION_REFUSED: i32 = 11;
+/// The connection attempt timed out. Try again later.
+pub const RETURN_CONNECTION_TIMED_OUT: i32 = 12;
+/// The server does not offer password authentication. Fix the configuration.
+pub const RETURN_PASSWORD_AUTH_UNAVAILABLE: i32 = 13;
+/// The server disconnected after too many authentication attempts.
+pub const RETURN_TOO_MANY_AUTH_FAILURES: i32 = 14;
+/// The account is locked or disabled on the server.
+pub const RETURN_ACCOUNT_LOCKED: i32 = 15;
+
+const MAX_LINE: usize = 4096;
+
+/// Lines classified before anything was answered. Logins by key, agent or
+/// control master never send a password, so the login phase cannot wait
+/// for one to end.
+const LOGIN_LINES: usize = 40;
+
+/// Lines classified after a password was sent. Anything later is treated
+/// as session output, which may legitimately contain the same phrases.
+const LINES_AFTER_PASSWORD: usize = 8;
+
+/// Well-known ssh and PAM messages. Entries without an exit code are
+/// reported as warnings and do not end the session.
+const CATALOGUE: &[(&str, Option<i32>)] = &[
+    (r"Could not resolve hostname", Some(RETURN_HOST_UNRESOLVED)),
+    (r"Name or service not known", Some(RETURN_HOST_UNRESOLVED)),
+    (r"Connection refused", Some(RETURN_CONNECTION_REFUSED)),
+    (r"Connection timed out", Some(RETURN_CONNECTION_TIMED_OUT)),
+    (r"Operation timed out", Some(RETURN_CONNECTION_TIMED_OUT)),
+    (
+        r"Permission denied \((?:publickey|gssapi-with-mic|gssapi-keyex|hostbased|,)+\)",
+        Some(RETURN_PASSWORD_AUTH_UNAVAILABLE),
+    ),
+    (
+        r"Too many authentication failures",
+        Some(RETURN_TOO_MANY_AUTH_FAILURES),
+    ),
+    (r"(?i)account (?:is )?locked", Some(RETURN_ACCOUNT_LOCKED)),
+    (
+        r"(?i)your account has (?:expired|been locked)",
+        Some(RETURN_ACCOUNT_LOCKED),
+    ),
+    (r"(?i)your password will expire in \d+ days?", None),
+    (r"(?i)warning: your password has expired", None),
+];
+
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    /// A fatal condition that maps to the given exit code.
+    Fail(i32),
+    /// A noteworthy message that does not end the session.
+    Warn(String),
+}
+
+/// Recognises well-known failure and warning messages in the login phase
+/// of the child output.
+pub struct Classifier {
+    rules: Vec<(Regex, Option<i32>)>,
+    pending: Vec<u8>,
+    lines_left: usize,
+}
+
+impl Classifier {
+    pub fn new() -> Self {
+        let rules = CATALOGUE
+            .iter()
+            .map(|&(pattern, code)| (Regex::new(pattern).expect("valid catalogue regex"), code))
+            .collect();
+        Self {
+            rules,
+            pending: Vec::new(),
+            lines_left: LOGIN_LINES,
+        }
+    }
+
+    /// Limits classification to the next few lines of output.
+    pub fn password_sent(&mut self) {
+        self.lines_left = self.lines_left.min(LINES_AFTER_PASSWORD);
+    }
+
+    /// Ends the login phase, for example once the login marker showed.
+    pub fn logged_in(&mut self) {
+        self.lines_left = 0;
+        self.pending.clear();
+    }
+
+    /// Whether the output is still considered part of the login dialog.
+    pub fn in_login_phase(&self) -> bool {
+        self.lines_left > 0
+    }
+
+    /// Feeds child output and classifies every line it completes.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Event> {
+        if !self.in_login_phase() {
+            return Vec::new();
+        }
+        self.pending.extend_from_slice(data);
+        let mut events = Vec::new();
+        while let Some(pos) = self.pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=pos).collect();
+            if let Some(event) = self.classify(&line) {
+                events.push(event);
+            }
+            self.lines_left -= 1;
+            if self.lines_left == 0 {
+                self.pending.clear();
+                break;
+            }
+        }
+        if self.pending.len() > MAX_LINE {
+            self.pending.drain(..self.pending.len() - MAX_LINE);
+        }
+        events
+    }
+
+    fn classify(&self, line: &[u8]) -> Option<Event> {
+        let (found, code) = self
+            .rules
+            .iter()
+            .find_map(|(re, code)| re.find(line).map(|m| (m, *code)))?;
+        Some(match code {
+            Some(code) => Event::Fail(code),
+            None => Event::Warn(String::from_utf8_lossy(found.as_bytes()).into_owned()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connection_refused() {
+        let mut c = Classifier::new();
+        assert_eq!(
+            c.feed(b"ssh: connect to host 10.0.0.1 port 22: Connection refused\r\n"),
+            vec![Event::Fail(RETURN_CONNECTION_REFUSED)]
+        );
+    }
+
+    #[test]
+    fn unresolved_host_split_across_buffers() {
+        let mut c = Classifier::new();
+        assert!(c.feed(b"ssh: Could not resol").is_empty());
+        assert_eq!(
+            c.feed(b"ve hostname nope: Name or service not known\r\n"),
+            vec![Event::Fail(RETURN_HOST_UNRESOLVED)]
+        );
+    }
+
+    #[test]
+    fn publickey_only_is_distinct_from_wrong_password() {
+        let mut c = Classifier::new();
+        assert_eq!(
+            c.feed(b"user@host: Permission denied (publickey).\r\n"),
+            vec![Event::Fail(RETURN_PASSWORD_AUTH_UNAVAILABLE)]
+        );
+        assert!(
+            c.feed(b"user@host: Permission denied (publickey,password).\r\n")
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn too_many_failures_and_lockout() {
+        let mut c = Classifier::new();
+        assert_eq!(
+            c.feed(
+                b"Received disconnect from 1.2.3.4 port 22:2: Too many authentication failures\r\n"
+            ),
+            vec![Event::Fail(RETURN_TOO_MANY_AUTH_FAILURES)]
+        );
+        assert_eq!(
+            c.feed(b"The account is locked due to 3 failed logins.\r\n"),
+            vec![Event::Fail(RETURN_ACCOUNT_LOCKED)]
+        );
+    }
+
+    #[test]
+    fn expiry_is_a_warning() {
+        let mut c = Classifier::new();
+        assert_eq!(
+            c.feed(b"WARNING: Your password will expire in 5 days\r\n"),
+            vec![Event::Warn("Your password will expire in 5 days".into())]
+        );
+    }
+
+    #[test]
+    fn partial_line_is_not_classified_twice() {
+        let mut c = Classifier::new();
+        assert!(c.feed(b"Your password will expire in 2 days").is_empty());
+        assert_eq!(c.feed(b"\r\n").len(), 1);
+        assert!(c.feed(b"$ ").is_empty());
+    }
+
+    #[test]
+    fn session_output_is_not_classified() {
+        let mut c = Classifier::new();
+        c.password_sent();
+        assert!(
+            c.feed(b"\r\n".repeat(LINES_AFTER_PASSWORD).as_slice())
+                .is_empty()
+        );
+        assert!(c.feed(b"curl: (7) Connection refused\r\n").is_empty());
+    }
+
+    #[test]
+    fn login_phase_ends_without_a_password() {
+        let mut c = Classifier::new();
+        assert!(
+            c.feed(b"Welcome\r\n".repeat(LOGIN_LINES).as_slice())
+                .is_empty()
+        );
+        assert!(!c.in_login_phase());
+        assert!(c.feed(b"curl: (7) Connection refused\r\n").is_empty());
+
+        let mut c = Classifier::new();
+        c.logged_in();
+        assert!(c.feed(b"curl: (7) Connection refused\r\n").is_empty());
+    }
+}
@@ -0,0 +1,488 @@
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::password::{FileFormat, PasswordSource};
+
+/// Environment variable that overrides the config file location.
+pub const CONFIG_ENV_VAR: &str = "SSHPASS_CONFIG";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read config file \"{path}\": {source}")]
+    Read { path: PathBuf, source: io::Error },
+    #[error("failed to parse config file \"{path}\": {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("section \"{pattern}\" sets more than one password source")]
+    ConflictingPasswordSource { pattern: String },
+    #[error("section \"{pattern}\" sets password-file-format without password-file")]
+    FormatWithoutFile { pattern: String },
+}
+
+/// Contents of the user configuration file.
+///
+/// ```toml
+/// [[host]]
+/// match = "switch-* !switch-lab*"
+/// prompt = "Password:"
+/// localized-prompts = true
+/// c-locale = true
+/// stall-timeout = 30
+/// host-key = "accept-new"
+/// password-file = "/home/me/.secrets/switches.env"
+/// password-file-format = "dotenv:SWITCH_PASSWORD"
+///
+/// [[key]]
+/// match = "*/id_ed25519_prod"
+/// passphrase-env = "PROD_KEY_PASSPHRASE"
+///
+/// [policy]
+/// password-argument = "refuse"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default, rename = "host")]
+    hosts: Vec<HostSection>,
+    #[serde(default, rename = "key")]
+    keys: Vec<KeySection>,
+    #[serde(default)]
+    pub policy: Policy,
+}
+
+/// Site rules for how secrets may be passed.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Policy {
+    /// What to do about a password given with `-p`.
+    #[serde(default)]
+    pub password_argument: Enforcement,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Enforcement {
+    #[default]
+    Allow,
+    Warn,
+    Refuse,
+}
+
+/// How the wrapped ssh treats unknown and changed host keys, passed on
+/// as `-o StrictHostKeyChecking=`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HostKeyPolicy {
+    Strict,
+    AcceptNew,
+    No,
+}
+
+impl HostKeyPolicy {
+    pub fn ssh_option(self) -> &'static str {
+        match self {
+            Self::Strict => "StrictHostKeyChecking=yes",
+            Self::AcceptNew => "StrictHostKeyChecking=accept-new",
+            Self::No => "StrictHostKeyChecking=no",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct HostSection {
+    #[serde(rename = "match")]
+    pattern: String,
+    prompt: Option<String>,
+    localized_prompts: Option<bool>,
+    c_locale: Option<bool>,
+    password_file: Option<PathBuf>,
+    password_file_format: Option<FileFormat>,
+    password_env: Option<String>,
+    #[cfg(unix)]
+    password_fd: Option<i32>,
+    password_credential: Option<String>,
+    script: Option<PathBuf>,
+    login_marker: Option<String>,
+    /// Seconds without progress in the login dialog before giving up.
+    stall_timeout: Option<u64>,
+    host_key: Option<HostKeyPolicy>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct KeySection {
+    #[serde(rename = "match")]
+    pattern: String,
+    passphrase_file: Option<PathBuf>,
+    passphrase_env: Option<String>,
+    #[cfg(unix)]
+    passphrase_fd: Option<i32>,
+}
+
+/// Settings that apply to one host, merged from every matching section.
+#[derive(Debug, Default)]
+pub struct Profile {
+    pub prompt: Option<String>,
+    pub localized_prompts: Option<bool>,
+    /// Run the command with `LC_ALL=C`.
+    pub c_locale: Option<bool>,
+    pub password: Option<PasswordSource>,
+    pub script: Option<PathBuf>,
+    pub login_marker: Option<String>,
+    pub stall_timeout: Option<Duration>,
+    pub host_key: Option<HostKeyPolicy>,
+}
+
+impl Config {
+    /// Loads the config file from `$SSHPASS_CONFIG` or the default location.
+    ///
+    /// A missing file at the default location is not an error.
+    pub fn load() -> Result<Self, ConfigError> {
+        if let Some(path) = std::env::var_os(CONFIG_ENV_VAR) {
+            return Self::load_from(PathBuf::from(path));
+        }
+        match default_path() {
+            Some(path) if path.exists() => Self::load_from(path),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    fn load_from(path: PathBuf) -> Result<Self, ConfigError> {
+        let content = fs::read_to_string(&path).map_err(|e| ConfigError::Read {
+            path: path.clone(),
+            source: e,
+        })?;
+        Self::parse(&content).map_err(|e| match e {
+            ConfigError::Parse { source, .. } => ConfigError::Parse { path, source },
+            other => other,
+        })
+    }
+
+    fn parse(content: &str) -> Result<Self, ConfigError> {
+        let config: Self = toml::from_str(content).map_err(|e| ConfigError::Parse {
+            path: PathBuf::new(),
+            source: e,
+        })?;
+        for section in &config.hosts {
+            section.password_source()?;
+        }
+        for section in &config.keys {
+            section.passphrase_source()?;
+        }
+        Ok(config)
+    }
+
+    /// Merges all sections matching `host`. Like ssh_config, the first
+    /// section that sets a value wins.
+    pub fn profile(&self, host: Option<&str>) -> Profile {
+        let mut profile = Profile::default();
+        let Some(host) = host else {
+            return profile;
+        };
+        for section in self
+            .hosts
+            .iter()
+            .filter(|s| matches_patterns(&s.pattern, host))
+        {
+            if profile.prompt.is_none() {
+                profile.prompt.clone_from(&section.prompt);
+            }
+            if profile.localized_prompts.is_none() {
+                profile.localized_prompts = section.localized_prompts;
+            }
+            if profile.c_locale.is_none() {
+                profile.c_locale = section.c_locale;
+            }
+            if profile.password.is_none() {
+                profile.password = section.password_source().ok().flatten();
+            }
+            if profile.script.is_none() {
+                profile.script.clone_from(&section.script);
+            }
+            if profile.login_marker.is_none() {
+                profile.login_marker.clone_from(&section.login_marker);
+            }
+            if profile.stall_timeout.is_none() {
+                profile.stall_timeout = section.stall_timeout.map(Duration::from_secs);
+            }
+            if profile.host_key.is_none() {
+                profile.host_key = section.host_key;
+            }
+        }
+        profile
+    }
+
+    /// Passphrase sources for private keys, as (key path pattern, source).
+    pub fn key_passphrases(&self) -> Vec<(String, PasswordSource)> {
+        self.keys
+            .iter()
+            .filter_map(|k| Some((k.pattern.clone(), k.passphrase_source().ok()??)))
+            .collect()
+    }
+}
+
+impl HostSection {
+    fn password_source(&self) -> Result<Option<PasswordSource>, ConfigError> {
+        let mut sources = Vec::new();
+        match (&self.password_file, &self.password_file_format) {
+            (Some(path), format) => sources.push(PasswordSource::File(
+                path.clone(),
+                format.clone().unwrap_or_default(),
+            )),
+            (None, Some(_)) => {
+                return Err(ConfigError::FormatWithoutFile {
+                    pattern: self.pattern.clone(),
+                });
+            }
+            (None, None) => {}
+        }
+        if let Some(ref var) = self.password_env {
+            sources.push(PasswordSource::Env(var.clone()));
+        }
+        #[cfg(unix)]
+        if let Some(fd) = self.password_fd {
+            sources.push(PasswordSource::Fd(fd));
+        }
+        if let Some(ref name) = self.password_credential {
+            sources.push(PasswordSource::Credential(name.clone()));
+        }
+        at_most_one(&self.pattern, sources)
+    }
+}
+
+impl KeySection {
+    fn passphrase_source(&self) -> Result<Option<PasswordSource>, ConfigError> {
+        let mut sources = Vec::new();
+        if let Some(ref path) = self.passphrase_file {
+            sources.push(PasswordSource::File(path.clone(), FileFormat::default()));
+        }
+        if let Some(ref var) = self.passphrase_env {
+            sources.push(PasswordSource::Env(var.clone()));
+        }
+        #[cfg(unix)]
+        if let Some(fd) = self.passphrase_fd {
+            sources.push(PasswordSource::Fd(fd));
+        }
+        at_most_one(&self.pattern, sources)
+    }
+}
+
+fn at_most_one(
+    pattern: &str,
+    mut sources: Vec<PasswordSource>,
+) -> Result<Option<PasswordSource>, ConfigError> {
+    if sources.len() > 1 {
+        return Err(ConfigError::ConflictingPasswordSource {
+            pattern: pattern.to_string(),
+        });
+    }
+    Ok(sources.pop())
+}
+
+fn default_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("sshpass-rs").join("config.toml"))
+}
+
+/// Matches `name` against a whitespace or comma separated list of glob
+/// patterns. A pattern prefixed with `!` excludes the name.
+pub fn matches_patterns(patterns: &str, name: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns.split([' ', '\t', ',']).filter(|p| !p.is_empty()) {
+        if let Some(negated) = pattern.strip_prefix('!') {
+            if glob_match(negated.as_bytes(), name.as_bytes()) {
+                return false;
+            }
+        } else if glob_match(pattern.as_bytes(), name.as_bytes()) {
+            matched = true;
+        }
+    }
+    matched
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c.eq_ignore_ascii_case(&text[t]) => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bt)) => {
+                    p = bp + 1;
+                    t = bt + 1;
+                    backtrack = Some((bp, bt + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = r#"
+[[host]]
+match = "switch-* !switch-lab*"
+prompt = "Password:"
+password-file = "/etc/switches.pw"
+stall-timeout = 30
+c-locale = true
+
+[[host]]
+match = "*"
+prompt = "assword:"
+localized-prompts = true
+c-locale = false
+host-key = "accept-new"
+password-env = "FALLBACK_PASS"
+"#;
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_match(b"switch-*", b"switch-12"));
+        assert!(glob_match(b"db?.example.com", b"DB1.example.com"));
+        assert!(glob_match(b"*", b""));
+        assert!(!glob_match(b"switch-*", b"router-1"));
+        assert!(!glob_match(b"db?", b"db12"));
+    }
+
+    #[test]
+    fn negated_pattern_excludes() {
+        assert!(matches_patterns("switch-* !switch-lab*", "switch-core"));
+        assert!(!matches_patterns("switch-* !switch-lab*", "switch-lab3"));
+        assert!(!matches_patterns("!foo", "bar"));
+    }
+
+    #[test]
+    fn first_matching_section_wins() {
+        let config = Config::parse(SAMPLE).unwrap();
+        let profile = config.profile(Some("switch-core"));
+        assert_eq!(profile.prompt.as_deref(), Some("Password:"));
+        assert!(matches!(profile.password, Some(PasswordSource::File(..))));
+        assert_eq!(profile.stall_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(profile.localized_prompts, Some(true));
+        assert_eq!(profile.c_locale, Some(true));
+        assert_eq!(profile.host_key, Some(HostKeyPolicy::AcceptNew));
+    }
+
+    #[test]
+    fn falls_through_to_later_sections() {
+        let config = Config::parse(SAMPLE).unwrap();
+        let profile = config.profile(Some("switch-lab1"));
+        assert_eq!(profile.prompt.as_deref(), Some("assword:"));
+        assert!(
+            matches!(profile.password, Some(PasswordSource::Env(ref v)) if v == "FALLBACK_PASS")
+        );
+    }
+
+    #[test]
+    fn no_host_gives_empty_profile() {
+        let config = Config::parse(SAMPLE).unwrap();
+        let profile = config.profile(None);
+        assert!(profile.prompt.is_none());
+        assert!(profile.password.is_none());
+    }
+
+    #[test]
+    fn conflicting_sources_rejected() {
+        let err =
+            Config::parse("[[host]]\nmatch = \"*\"\npassword-file = \"a\"\npassword-env = \"B\"\n")
+                .unwrap_err();
+        assert!(matches!(err, ConfigError::ConflictingPasswordSource { .. }));
+    }
+
+    #[test]
+    fn file_format() {
+        let config = Config::parse(
+            "[[host]]\nmatch = \"*\"\npassword-file = \"a\"\npassword-file-format = \"line:2\"\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            config.profile(Some("any")).password,
+            Some(PasswordSource::File(_, FileFormat::Line(2)))
+        ));
+        assert!(
+            Config::parse("[[host]]\nmatch = \"*\"\npassword-file = \"a\"\npassword-file-format = \"line:0\"\n")
+                .is_err()
+        );
+        assert!(matches!(
+            Config::parse("[[host]]\nmatch = \"*\"\npassword-file-format = \"whole\"\n"),
+            Err(ConfigError::FormatWithoutFile { .. })
+        ));
+    }
+
+    #[test]
+    fn policy() {
+        let config = Config::parse("[policy]\npassword-argument = \"refuse\"\n").unwrap();
+        assert_eq!(config.policy.password_argument, Enforcement::Refuse);
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.policy.password_argument, Enforcement::Allow);
+        assert!(Config::parse("[policy]\npassword-argument = \"maybe\"\n").is_err());
+    }
+
+    #[test]
+    fn credential_source() {
+        let config =
+            Config::parse("[[host]]\nmatch = \"*\"\npassword-credential = \"switch-pw\"\n")
+                .unwrap();
+        let profile = config.profile(Some("any"));
+        assert!(
+            matches!(profile.password, Some(PasswordSource::Credential(ref n)) if n == "switch-pw")
+        );
+    }
+
+    #[test]
+    fn key_sections() {
+        let config = Config::parse(
+            "[[key]]\nmatch = \"*/id_prod\"\npassphrase-env = \"PROD\"\n[[key]]\nmatch = \"*\"\n",
+        )
+        .unwrap();
+        let keys = config.key_passphrases();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].0, "*/id_prod");
+    }
+
+    #[test]
+    fn host_key_policy() {
+        let config = Config::parse(
+            "[[host]]\nmatch = \"lab-*\"\nhost-key = \"no\"\n[[host]]\nmatch = \"*\"\nhost-key = \"strict\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.profile(Some("lab-1")).host_key,
+            Some(HostKeyPolicy::No)
+        );
+        assert_eq!(
+            config
+                .profile(Some("db1"))
+                .host_key
+                .map(HostKeyPolicy::ssh_option),
+            Some("StrictHostKeyChecking=yes")
+        );
+        assert!(Config::parse("[[host]]\nmatch = \"*\"\nhost-key = \"ask\"\n").is_err());
+    }
+
+    #[test]
+    fn unknown_key_rejected() {
+        assert!(Config::parse("[[host]]\nmatch = \"*\"\npasword = \"x\"\n").is_err());
+    }
+}
@@ -0,0 +1,402 @@
+use std::collections::VecDeque;
+use std::io;
+use std::time::Duration;
+
+use crate::transport::{Decoder, Encoder, MSG_SERVICE_ACCEPT, MSG_SERVICE_REQUEST, Transport};
+
+pub const MSG_USERAUTH_REQUEST: u8 = 50;
+pub const MSG_USERAUTH_FAILURE: u8 = 51;
+pub const MSG_USERAUTH_SUCCESS: u8 = 52;
+const MSG_USERAUTH_BANNER: u8 = 53;
+/// Also SSH_MSG_USERAUTH_PASSWD_CHANGEREQ, after a password.
+pub const MSG_USERAUTH_INFO_REQUEST: u8 = 60;
+pub const MSG_USERAUTH_INFO_RESPONSE: u8 = 61;
+const MSG_GLOBAL_REQUEST: u8 = 80;
+const MSG_REQUEST_FAILURE: u8 = 82;
+pub const MSG_CHANNEL_OPEN: u8 = 90;
+pub const MSG_CHANNEL_OPEN_CONFIRMATION: u8 = 91;
+const MSG_CHANNEL_OPEN_FAILURE: u8 = 92;
+const MSG_CHANNEL_WINDOW_ADJUST: u8 = 93;
+pub const MSG_CHANNEL_DATA: u8 = 94;
+pub const MSG_CHANNEL_EXTENDED_DATA: u8 = 95;
+pub const MSG_CHANNEL_EOF: u8 = 96;
+pub const MSG_CHANNEL_CLOSE: u8 = 97;
+pub const MSG_CHANNEL_REQUEST: u8 = 98;
+pub const MSG_CHANNEL_SUCCESS: u8 = 99;
+pub const MSG_CHANNEL_FAILURE: u8 = 100;
+
+/// SSH_EXTENDED_DATA_STDERR
+pub const STDERR: u32 = 1;
+/// SSH_OPEN_ADMINISTRATIVELY_PROHIBITED
+const OPEN_PROHIBITED: u32 = 1;
+
+/// Keyboard-interactive rounds answered before giving up, so that a server
+/// asking again after a wrong password is not answered forever.
+const MAX_PROMPT_ROUNDS: usize = 4;
+/// Receive window offered to the server; topped up when half is used.
+const WINDOW: u32 = 2 * 1024 * 1024;
+const MAX_PACKET_SIZE: u32 = 32 * 1024;
+
+fn unexpected(packet: &[u8]) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected ssh message {}", packet[0]),
+    )
+}
+
+enum AuthReply {
+    Success,
+    /// The methods that can continue.
+    Failure(Vec<String>),
+    Other(Vec<u8>),
+}
+
+fn auth_reply(transport: &mut Transport) -> io::Result<AuthReply> {
+    loop {
+        let packet = transport.recv()?;
+        let mut d = Decoder::new(&packet[1..]);
+        match packet[0] {
+            MSG_USERAUTH_SUCCESS => return Ok(AuthReply::Success),
+            MSG_USERAUTH_FAILURE => return Ok(AuthReply::Failure(d.name_list()?)),
+            // ssh shows the banner too.
+            MSG_USERAUTH_BANNER => eprint!("{}", d.text()?),
+            _ => return Ok(AuthReply::Other(packet)),
+        }
+    }
+}
+
+/// Logs in as `user` by the password method, or by keyboard-interactive
+/// with `password` answering the prompts that hide the input. Returns false
+/// if the server did not take it.
+pub fn login(transport: &mut Transport, user: &str, password: &[u8]) -> io::Result<bool> {
+    let service = Encoder::new(MSG_SERVICE_REQUEST).string("ssh-userauth");
+    transport.send(&service.into_bytes())?;
+    let reply = transport.recv()?;
+    if reply[0] != MSG_SERVICE_ACCEPT {
+        return Err(unexpected(&reply));
+    }
+
+    let request = |method: &str| {
+        Encoder::new(MSG_USERAUTH_REQUEST)
+            .string(user)
+            .string("ssh-connection")
+            .string(method)
+    };
+    transport.send(&request("none").into_bytes())?;
+    let methods = match auth_reply(transport)? {
+        AuthReply::Success => return Ok(true),
+        AuthReply::Failure(methods) => methods,
+        AuthReply::Other(packet) => return Err(unexpected(&packet)),
+    };
+
+    if methods.iter().any(|m| m == "password") {
+        transport.send(
+            &request("password")
+                .bool(false)
+                .string(password)
+                .into_bytes(),
+        )?;
+        match auth_reply(transport)? {
+            AuthReply::Success => return Ok(true),
+            // A request to change the password counts as a refusal.
+            AuthReply::Failure(_) => {}
+            AuthReply::Other(packet) if packet[0] == MSG_USERAUTH_INFO_REQUEST => {}
+            AuthReply::Other(packet) => return Err(unexpected(&packet)),
+        }
+    }
+
+    if methods.iter().any(|m| m == "keyboard-interactive") {
+        transport.send(
+            &request("keyboard-interactive")
+                .string("")
+                .string("")
+                .into_bytes(),
+        )?;
+        for _ in 0..MAX_PROMPT_ROUNDS {
+            let packet = match auth_reply(transport)? {
+                AuthReply::Success => return Ok(true),
+                AuthReply::Failure(_) => return Ok(false),
+                AuthReply::Other(packet) if packet[0] == MSG_USERAUTH_INFO_REQUEST => packet,
+                AuthReply::Other(packet) => return Err(unexpected(&packet)),
+            };
+            let mut d = Decoder::new(&packet[1..]);
+            let (_name, _instruction, _language) = (d.string()?, d.string()?, d.string()?);
+            let count = d.u32()?;
+            let mut response = Encoder::new(MSG_USERAUTH_INFO_RESPONSE).u32(count);
+            for _ in 0..count {
+                let (_prompt, echo) = (d.string()?, d.bool()?);
+                response = response.string(if echo { &[][..] } else { password });
+            }
+            transport.send(&response.into_bytes())?;
+        }
+    }
+    Ok(false)
+}
+
+/// What arrived on a channel.
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    Data(Vec<u8>),
+    Stderr(Vec<u8>),
+    Eof,
+    ExitStatus(u32),
+    /// The name of the signal without `SIG`.
+    ExitSignal(String),
+    /// The server closed the channel; nothing follows.
+    Closed,
+}
+
+/// A session channel, the only one on its connection.
+pub struct Channel {
+    transport: Transport,
+    remote_id: u32,
+    /// What the server still accepts before adjusting the window.
+    remote_window: u32,
+    remote_max_packet: u32,
+    /// What the server may still send.
+    local_window: u32,
+    events: VecDeque<Event>,
+    /// Answers to requests that wanted one, true for success.
+    replies: VecDeque<bool>,
+    closed: bool,
+    close_sent: bool,
+}
+
+impl Channel {
+    /// Opens a session channel on a logged in `transport`.
+    pub fn open_session(mut transport: Transport) -> io::Result<Self> {
+        let open = Encoder::new(MSG_CHANNEL_OPEN)
+            .string("session")
+            .u32(0)
+            .u32(WINDOW)
+            .u32(MAX_PACKET_SIZE);
+        transport.send(&open.into_bytes())?;
+        loop {
+            let packet = transport.recv()?;
+            let mut d = Decoder::new(&packet[1..]);
+            match packet[0] {
+                MSG_CHANNEL_OPEN_CONFIRMATION => {
+                    let _local_id = d.u32()?;
+                    return Ok(Self {
+                        transport,
+                        remote_id: d.u32()?,
+                        remote_window: d.u32()?,
+                        remote_max_packet: d.u32()?.max(1),
+                        local_window: WINDOW,
+                        events: VecDeque::new(),
+                        replies: VecDeque::new(),
+                        closed: false,
+                        close_sent: false,
+                    });
+                }
+                MSG_CHANNEL_OPEN_FAILURE => {
+                    let (_local_id, _reason) = (d.u32()?, d.u32()?);
+                    return Err(io::Error::other(format!(
+                        "the server refused a session: {}",
+                        d.text()?
+                    )));
+                }
+                MSG_GLOBAL_REQUEST => refuse_global(&mut transport, &mut d)?,
+                _ => return Err(unexpected(&packet)),
+            }
+        }
+    }
+
+    /// Asks for a terminal of type `term` and `cols` by `rows` characters.
+    pub fn request_pty(&mut self, term: &str, cols: u32, rows: u32) -> io::Result<()> {
+        let modes = [0u8]; // TTY_OP_END
+        let body = Encoder::default()
+            .string(term)
+            .u32(cols)
+            .u32(rows)
+            .u32(0)
+            .u32(0)
+            .string(modes);
+        self.request("pty-req", body, true)
+    }
+
+    pub fn shell(&mut self) -> io::Result<()> {
+        self.request("shell", Encoder::default(), true)
+    }
+
+    pub fn exec(&mut self, command: &str) -> io::Result<()> {
+        self.request("exec", Encoder::default().string(command), true)
+    }
+
+    pub fn subsystem(&mut self, name: &str) -> io::Result<()> {
+        self.request("subsystem", Encoder::default().string(name), true)
+    }
+
+    pub fn window_change(&mut self, cols: u32, rows: u32) -> io::Result<()> {
+        let body = Encoder::default().u32(cols).u32(rows).u32(0).u32(0);
+        self.request("window-change", body, false)
+    }
+
+    fn request(&mut self, name: &str, body: Encoder, want_reply: bool) -> io::Result<()> {
+        let request = Encoder::new(MSG_CHANNEL_REQUEST)
+            .u32(self.remote_id)
+            .string(name)
+            .bool(want_reply)
+            .raw(&body.into_bytes());
+        self.transport.send(&request.into_bytes())?;
+        if !want_reply {
+            return Ok(());
+        }
+        loop {
+            if let Some(success) = self.replies.pop_front() {
+                if success {
+                    return Ok(());
+                }
+                return Err(io::Error::other(format!("the server refused {name}")));
+            }
+            if self.closed {
+                return Err(io::Error::other(format!(
+                    "the server closed the channel on {name}"
+                )));
+            }
+            let packet = self.transport.recv()?;
+            self.process(packet)?;
+        }
+    }
+
+    /// Sends all of `data`, waiting for the server to make room if needed.
+    pub fn write(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            if self.closed {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            if self.remote_window == 0 {
+                let packet = self.transport.recv()?;
+                self.process(packet)?;
+                continue;
+            }
+            let n = data
+                .len()
+                .min(self.remote_window as usize)
+                .min(self.remote_max_packet as usize);
+            let message = Encoder::new(MSG_CHANNEL_DATA)
+                .u32(self.remote_id)
+                .string(&data[..n]);
+            self.transport.send(&message.into_bytes())?;
+            self.remote_window -= n as u32;
+            data = &data[n..];
+        }
+        Ok(())
+    }
+
+    /// Tells the server that no more data follows.
+    pub fn send_eof(&mut self) -> io::Result<()> {
+        let message = Encoder::new(MSG_CHANNEL_EOF).u32(self.remote_id);
+        self.transport.send(&message.into_bytes())
+    }
+
+    pub fn close(&mut self) -> io::Result<()> {
+        if self.close_sent {
+            return Ok(());
+        }
+        self.close_sent = true;
+        let message = Encoder::new(MSG_CHANNEL_CLOSE).u32(self.remote_id);
+        self.transport.send(&message.into_bytes())
+    }
+
+    /// The next event, waiting for at most `timeout`, or as long as it
+    /// takes without one. After `Closed`, gives `Closed` again.
+    pub fn poll(&mut self, timeout: Option<Duration>) -> io::Result<Option<Event>> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Some(event));
+            }
+            if self.closed {
+                return Ok(Some(Event::Closed));
+            }
+            let packet = match timeout {
+                Some(timeout) => match self.transport.recv_timeout(timeout)? {
+                    Some(packet) => packet,
+                    None => return Ok(None),
+                },
+                None => self.transport.recv()?,
+            };
+            self.process(packet)?;
+        }
+    }
+
+    fn process(&mut self, packet: Vec<u8>) -> io::Result<()> {
+        let mut d = Decoder::new(&packet[1..]);
+        match packet[0] {
+            MSG_GLOBAL_REQUEST => return refuse_global(&mut self.transport, &mut d),
+            MSG_CHANNEL_OPEN => {
+                let (_kind, sender) = (d.string()?, d.u32()?);
+                let refusal = Encoder::new(MSG_CHANNEL_OPEN_FAILURE)
+                    .u32(sender)
+                    .u32(OPEN_PROHIBITED)
+                    .string("")
+                    .string("");
+                return self.transport.send(&refusal.into_bytes());
+            }
+            _ => {}
+        }
+        let _local_id = d.u32()?;
+        match packet[0] {
+            MSG_CHANNEL_WINDOW_ADJUST => {
+                self.remote_window = self.remote_window.saturating_add(d.u32()?);
+            }
+            MSG_CHANNEL_DATA => {
+                let data = d.string()?.to_vec();
+                self.consume(data.len())?;
+                self.events.push_back(Event::Data(data));
+            }
+            MSG_CHANNEL_EXTENDED_DATA => {
+                let code = d.u32()?;
+                let data = d.string()?.to_vec();
+                self.consume(data.len())?;
+                if code == STDERR {
+                    self.events.push_back(Event::Stderr(data));
+                }
+            }
+            MSG_CHANNEL_EOF => self.events.push_back(Event::Eof),
+            MSG_CHANNEL_CLOSE => {
+                self.close()?;
+                self.closed = true;
+                self.events.push_back(Event::Closed);
+            }
+            MSG_CHANNEL_REQUEST => {
+                let name = d.string()?;
+                let want_reply = d.bool()?;
+                match name {
+                    b"exit-status" => self.events.push_back(Event::ExitStatus(d.u32()?)),
+                    b"exit-signal" => self.events.push_back(Event::ExitSignal(d.text()?)),
+                    _ if want_reply => {
+                        let failure = Encoder::new(MSG_CHANNEL_FAILURE).u32(self.remote_id);
+                        self.transport.send(&failure.into_bytes())?;
+                    }
+                    _ => {}
+                }
+            }
+            MSG_CHANNEL_SUCCESS => self.replies.push_back(true),
+            MSG_CHANNEL_FAILURE => self.replies.push_back(false),
+            _ => return Err(unexpected(&packet)),
+        }
+        Ok(())
+    }
+
+    /// Accounts for `n` received bytes and tops up the window.
+    fn consume(&mut self, n: usize) -> io::Result<()> {
+        self.local_window = self.local_window.saturating_sub(n as u32);
+        if self.local_window < WINDOW / 2 {
+            let adjust = Encoder::new(MSG_CHANNEL_WINDOW_ADJUST)
+                .u32(self.remote_id)
+                .u32(WINDOW - self.local_window);
+            self.transport.send(&adjust.into_bytes())?;
+            self.local_window = WINDOW;
+        }
+        Ok(())
+    }
+}
+
+fn refuse_global(transport: &mut Transport, d: &mut Decoder) -> io::Result<()> {
+    let (_name, want_reply) = (d.string()?, d.bool()?);
+    if want_reply {
+        transport.send(&[MSG_REQUEST_FAILURE])?;
+    }
+    Ok(())
+}
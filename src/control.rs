@@ -0,0 +1,338 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::destination::{self, Endpoint};
+
+/// Programs that can share an OpenSSH control master.
+const CLIENTS: &[&str] = &["ssh", "scp", "sftp"];
+const DEFAULT_PORT: u16 = 22;
+
+/// Who a master is logged in as, and where; what OpenSSH's `%r@%h:%p`
+/// stands for. Connections may only share a master if all three match.
+#[derive(Debug, PartialEq)]
+pub struct Remote {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+}
+
+impl fmt::Display for Remote {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "{}@[{}]:{}", self.user, self.host, self.port)
+        } else {
+            write!(f, "{}@{}:{}", self.user, self.host, self.port)
+        }
+    }
+}
+
+impl Remote {
+    /// Fills in what the command line leaves open the way ssh would,
+    /// from its configuration.
+    fn resolve(endpoint: Endpoint) -> Self {
+        let config = Command::new("ssh")
+            .arg("-G")
+            .args(&endpoint.options)
+            .args(endpoint.user.iter().flat_map(|user| ["-l", user]))
+            .args(endpoint.port.map(|port| format!("-p{port}")))
+            .arg(&endpoint.host)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+            .unwrap_or_default();
+        Self::from_config(endpoint, &config)
+    }
+
+    /// Takes user, host name and port from `ssh -G` output, or falls back
+    /// to the defaults.
+    fn from_config(endpoint: Endpoint, config: &str) -> Self {
+        let value = |key: &str| {
+            config.lines().find_map(|line| {
+                let (k, v) = line.split_once(' ')?;
+                (k == key).then(|| v.to_string())
+            })
+        };
+        Self {
+            user: value("user")
+                .or(endpoint.user)
+                .or_else(|| std::env::var("USER").ok())
+                .or_else(|| std::env::var("LOGNAME").ok())
+                .unwrap_or_default(),
+            host: value("hostname").unwrap_or(endpoint.host),
+            port: value("port")
+                .and_then(|p| p.parse().ok())
+                .or(endpoint.port)
+                .unwrap_or(DEFAULT_PORT),
+        }
+    }
+
+    /// The socket name, or `None` for a name this module did not write.
+    fn from_socket_name(name: &str) -> Option<Self> {
+        let (rest, port) = name.rsplit_once(':')?;
+        let (user, host) = rest.rsplit_once('@')?;
+        Some(Self {
+            user: user.to_string(),
+            host: host.to_string(),
+            port: port.parse().ok()?,
+        })
+    }
+
+    /// `user@host:port` with anything unusual replaced; never starts with
+    /// a dot.
+    fn socket_name(&self) -> String {
+        let clean = |part: &str, extra: char| -> String {
+            part.chars()
+                .map(|c| match c {
+                    'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+                    c if c == extra => c,
+                    _ => '_',
+                })
+                .collect()
+        };
+        let user = clean(&self.user, '@');
+        let user = match user.strip_prefix('.') {
+            Some(rest) => format!("_{rest}"),
+            None => user,
+        };
+        format!("{user}@{}:{}", clean(&self.host, ':'), self.port)
+    }
+}
+
+/// A control master socket and the remote end it connects to.
+pub struct Master {
+    pub remote: Remote,
+    pub socket: PathBuf,
+}
+
+impl Master {
+    /// Whether a master still listens on the socket.
+    pub fn is_alive(&self) -> bool {
+        UnixStream::connect(&self.socket).is_ok()
+    }
+
+    /// Asks the master to exit, or removes the socket if it is gone.
+    pub fn stop(&self) -> io::Result<()> {
+        if !self.socket.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no master was started",
+            ));
+        }
+        if !self.is_alive() {
+            return fs::remove_file(&self.socket);
+        }
+        let status = Command::new("ssh")
+            .arg("-S")
+            .arg(&self.socket)
+            .args(["-O", "exit", &self.remote.host])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!(
+                "ssh -O exit failed with {status}"
+            )))
+        }
+    }
+}
+
+/// Directory holding the sockets, private to the user.
+fn dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".ssh"),
+    };
+    Some(base.join("sshpass-rs"))
+}
+
+/// The master for the user, host and port of `command`, whether it runs
+/// or not.
+pub fn master_for(command: &[String]) -> Option<Master> {
+    let program = Path::new(command.first()?).file_name()?.to_str()?;
+    if !CLIENTS.contains(&program) {
+        return None;
+    }
+    let remote = Remote::resolve(destination::endpoint(command)?);
+    Some(Master {
+        socket: dir()?.join(remote.socket_name()),
+        remote,
+    })
+}
+
+/// All sockets left by `--master`.
+pub fn masters() -> io::Result<Vec<Master>> {
+    let Some(dir) = dir() else {
+        return Ok(Vec::new());
+    };
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut masters = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_socket()
+            && let Some(remote) = Remote::from_socket_name(&entry.file_name().to_string_lossy())
+        {
+            masters.push(Master {
+                remote,
+                socket: entry.path(),
+            });
+        }
+    }
+    masters.sort_by(|a, b| a.socket.cmp(&b.socket));
+    Ok(masters)
+}
+
+/// Turns an ssh command into one that logs in, then leaves a master
+/// listening on `master`'s socket in the background.
+pub fn start_command(command: &[OsString], master: &Master) -> io::Result<Vec<OsString>> {
+    if let Some(dir) = master.socket.parent() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+    }
+    Ok(with_options(
+        command,
+        &["-f", "-N", "-M", "-o", &control_path(&master.socket)],
+    ))
+}
+
+/// Turns `command` into one that goes through the running `master`.
+pub fn reuse_command(command: &[OsString], master: &Master) -> Vec<OsString> {
+    with_options(
+        command,
+        &[
+            "-o",
+            "ControlMaster=no",
+            "-o",
+            &control_path(&master.socket),
+        ],
+    )
+}
+
+fn control_path(socket: &Path) -> String {
+    // ssh expands %-tokens in the path.
+    format!(
+        "ControlPath={}",
+        socket.to_string_lossy().replace('%', "%%")
+    )
+}
+
+/// Inserts options right after the program name.
+fn with_options(command: &[OsString], options: &[&str]) -> Vec<OsString> {
+    let mut result = vec![command[0].clone()];
+    result.extend(options.iter().map(OsString::from));
+    result.extend_from_slice(&command[1..]);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmd(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn remote(user: &str, host: &str, port: u16) -> Remote {
+        Remote {
+            user: user.into(),
+            host: host.into(),
+            port,
+        }
+    }
+
+    fn at(socket: &str) -> Master {
+        Master {
+            remote: remote("me", "db1", 22),
+            socket: socket.into(),
+        }
+    }
+
+    #[test]
+    fn socket_names() {
+        let names = [
+            (
+                remote("alice", "db1.example.com", 22),
+                "alice@db1.example.com:22",
+            ),
+            (remote("me@corp", "fd00::1", 2222), "me@corp@fd00::1:2222"),
+            (remote("../x", "h/y", 22), "_._x@h_y:22"),
+        ];
+        for (remote, name) in names {
+            assert_eq!(remote.socket_name(), name);
+        }
+        assert_eq!(
+            Remote::from_socket_name("me@corp@fd00::1:2222"),
+            Some(remote("me@corp", "fd00::1", 2222))
+        );
+        assert_eq!(Remote::from_socket_name("db1"), None);
+    }
+
+    #[test]
+    fn users_and_ports_get_their_own_master() {
+        let endpoint = |user: Option<&str>, port| Endpoint {
+            user: user.map(String::from),
+            host: "db1".into(),
+            port,
+            options: Vec::new(),
+        };
+        let config = "user alice\nhostname db1.example.com\nport 22\n";
+        assert_eq!(
+            Remote::from_config(endpoint(None, None), config),
+            remote("alice", "db1.example.com", 22)
+        );
+        assert_eq!(
+            Remote::from_config(endpoint(Some("root"), Some(2222)), ""),
+            remote("root", "db1", 2222)
+        );
+        assert_eq!(
+            remote("root", "fd00::1", 2222).to_string(),
+            "root@[fd00::1]:2222"
+        );
+    }
+
+    #[test]
+    fn only_ssh_clients_share_masters() {
+        assert!(master_for(&cmd(&["rsync", "a", "db1:b"])).is_none());
+        assert!(master_for(&cmd(&["ssh"])).is_none());
+    }
+
+    #[test]
+    fn reuse_adds_control_path() {
+        assert_eq!(
+            reuse_command(
+                &["scp".into(), "f".into(), "db1:".into()],
+                &at("/run/s/100%/db1")
+            ),
+            [
+                "scp",
+                "-o",
+                "ControlMaster=no",
+                "-o",
+                "ControlPath=/run/s/100%%/db1",
+                "f",
+                "db1:"
+            ]
+        );
+    }
+
+    #[test]
+    fn dead_socket_is_not_alive() {
+        assert!(!at("/nonexistent/sshpass-rs/db1").is_alive());
+    }
+}
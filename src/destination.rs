@@ -0,0 +1,375 @@
+use std::path::Path;
+
+/// ssh options that consume the following argument.
+const SSH_OPTS_WITH_ARG: &[u8] = b"BbcDEeFIiJLlmOoPpQRSWw";
+/// sftp options that consume the following argument.
+const SFTP_OPTS_WITH_ARG: &[u8] = b"BbcDFiJloPRsS";
+/// scp options that consume the following argument.
+const SCP_OPTS_WITH_ARG: &[u8] = b"cDFiJloPSX";
+
+/// Extracts the remote host name from a wrapped ssh, sftp or scp command line.
+///
+/// Returns `None` if the program is not recognised or no destination was found.
+pub fn host(command: &[String]) -> Option<String> {
+    let (program, args) = command.split_first()?;
+    let name = Path::new(program).file_name()?.to_str()?;
+    match name {
+        "ssh" => first_operand(args, SSH_OPTS_WITH_ARG).map(|d| strip_destination(d, false)),
+        "sftp" => first_operand(args, SFTP_OPTS_WITH_ARG).map(|d| strip_destination(d, true)),
+        "scp" => scp_host(args),
+        _ => None,
+    }
+}
+
+/// Where a wrapped ssh, sftp or scp command connects to, as far as its
+/// command line tells.
+#[derive(Debug, Default, PartialEq)]
+pub struct Endpoint {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+    /// `-F` and `-o` options, which may set the rest.
+    pub options: Vec<String>,
+}
+
+/// Extracts the user, host and port from a wrapped ssh, sftp or scp
+/// command line. Like ssh, the first `-l` or `-o User` wins over a user in
+/// the destination.
+///
+/// Returns `None` if the program is not recognised or no destination was found.
+pub fn endpoint(command: &[String]) -> Option<Endpoint> {
+    let (program, args) = command.split_first()?;
+    let name = Path::new(program).file_name()?.to_str()?;
+    let (opts_with_arg, user_flag, port_flag, destination) = match name {
+        "ssh" => (
+            SSH_OPTS_WITH_ARG,
+            Some(b'l'),
+            b'p',
+            first_operand(args, SSH_OPTS_WITH_ARG)?,
+        ),
+        "sftp" => (
+            SFTP_OPTS_WITH_ARG,
+            None,
+            b'P',
+            first_operand(args, SFTP_OPTS_WITH_ARG)?,
+        ),
+        "scp" => (SCP_OPTS_WITH_ARG, None, b'P', scp_destination(args)?),
+        _ => return None,
+    };
+
+    let mut endpoint = Endpoint::default();
+    for (flag, value) in option_values(args, opts_with_arg) {
+        if Some(flag) == user_flag {
+            endpoint.user.get_or_insert_with(|| value.to_string());
+        } else if flag == port_flag {
+            endpoint.port = value.parse().ok().or(endpoint.port);
+        } else if flag == b'F' {
+            endpoint
+                .options
+                .extend(["-F".to_string(), value.to_string()]);
+        } else if flag == b'o' {
+            if let Some((key, value)) = value.split_once(['=', ' ']) {
+                let value = value.trim_start_matches(['=', ' ']);
+                match key.trim().to_ascii_lowercase().as_str() {
+                    "user" => {
+                        endpoint.user.get_or_insert_with(|| value.to_string());
+                    }
+                    "port" => endpoint.port = endpoint.port.or(value.parse().ok()),
+                    _ => {}
+                }
+            }
+            endpoint
+                .options
+                .extend(["-o".to_string(), value.to_string()]);
+        }
+    }
+
+    let (user, host, port) = split_destination(destination, name != "ssh");
+    endpoint.user = endpoint.user.or(user);
+    endpoint.host = host;
+    endpoint.port = endpoint.port.or(port);
+    Some(endpoint)
+}
+
+/// Extracts the jump hosts of `-J` and `-o ProxyJump=` options from a
+/// wrapped ssh, sftp or scp command line, in the order they are given.
+pub fn jump_hosts(command: &[String]) -> Vec<String> {
+    let Some((program, args)) = command.split_first() else {
+        return Vec::new();
+    };
+    let opts_with_arg = match Path::new(program).file_name().and_then(|n| n.to_str()) {
+        Some("ssh") => SSH_OPTS_WITH_ARG,
+        Some("sftp") => SFTP_OPTS_WITH_ARG,
+        Some("scp") => SCP_OPTS_WITH_ARG,
+        _ => return Vec::new(),
+    };
+    let mut hosts = Vec::new();
+    for (flag, value) in option_values(args, opts_with_arg) {
+        let jumps = match flag {
+            b'J' => value,
+            b'o' => match value.split_once(['=', ' ']) {
+                Some((key, jumps)) if key.trim().eq_ignore_ascii_case("ProxyJump") => {
+                    jumps.trim_start_matches(['=', ' '])
+                }
+                _ => continue,
+            },
+            _ => continue,
+        };
+        if jumps.eq_ignore_ascii_case("none") {
+            continue;
+        }
+        hosts.extend(jumps.split(',').map(|j| strip_destination(j.trim(), true)));
+    }
+    hosts
+}
+
+/// Whether a wrapped ssh, sftp or scp command line sets the ssh option
+/// `key` with `-o`.
+pub fn sets_option(command: &[String], key: &str) -> bool {
+    let Some((program, args)) = command.split_first() else {
+        return false;
+    };
+    let opts_with_arg = match Path::new(program).file_name().and_then(|n| n.to_str()) {
+        Some("ssh") => SSH_OPTS_WITH_ARG,
+        Some("sftp") => SFTP_OPTS_WITH_ARG,
+        Some("scp") => SCP_OPTS_WITH_ARG,
+        _ => return false,
+    };
+    option_values(args, opts_with_arg)
+        .into_iter()
+        .any(|(flag, value)| {
+            flag == b'o'
+                && value
+                    .split_once(['=', ' '])
+                    .is_some_and(|(k, _)| k.trim().eq_ignore_ascii_case(key))
+        })
+}
+
+/// Option letters and their values, up to the first operand.
+fn option_values<'a>(args: &'a [String], opts_with_arg: &[u8]) -> Vec<(u8, &'a str)> {
+    let mut values = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let Some(flags) = arg.strip_prefix('-').filter(|f| !f.is_empty() && *f != "-") else {
+            break;
+        };
+        for (i, flag) in flags.bytes().enumerate() {
+            if opts_with_arg.contains(&flag) {
+                let value = if i + 1 == flags.len() {
+                    iter.next().map(String::as_str)
+                } else {
+                    Some(&flags[i + 1..])
+                };
+                values.extend(value.map(|v| (flag, v)));
+                break;
+            }
+        }
+    }
+    values
+}
+
+fn first_operand<'a>(args: &'a [String], opts_with_arg: &[u8]) -> Option<&'a str> {
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            return iter.next().map(String::as_str);
+        }
+        let Some(flags) = arg.strip_prefix('-').filter(|f| !f.is_empty()) else {
+            return Some(arg);
+        };
+        for (i, flag) in flags.bytes().enumerate() {
+            if opts_with_arg.contains(&flag) {
+                if i + 1 == flags.len() {
+                    iter.next();
+                }
+                break;
+            }
+        }
+    }
+    None
+}
+
+fn scp_host(args: &[String]) -> Option<String> {
+    scp_destination(args).map(|d| strip_destination(d, true))
+}
+
+fn scp_destination(args: &[String]) -> Option<&str> {
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if let Some(flags) = arg.strip_prefix('-') {
+            if let Some(last) = flags.bytes().last()
+                && SCP_OPTS_WITH_ARG.contains(&last)
+            {
+                iter.next();
+            }
+            continue;
+        }
+        if arg.starts_with("scp://") || is_remote_path(arg) {
+            return Some(arg);
+        }
+    }
+    None
+}
+
+fn is_remote_path(arg: &str) -> bool {
+    match arg.find(':') {
+        Some(colon) => !arg[..colon].contains('/'),
+        None => false,
+    }
+}
+
+/// Reduces `[scheme://][user@]host[:port|:path]` to the bare host name.
+fn strip_destination(dest: &str, colon_ends_host: bool) -> String {
+    split_destination(dest, colon_ends_host).1
+}
+
+/// Splits `[scheme://][user@]host[:port|:path]` into user, host and, for
+/// URIs, port.
+fn split_destination(dest: &str, colon_ends_host: bool) -> (Option<String>, String, Option<u16>) {
+    let (rest, is_uri) = match dest.split_once("://") {
+        Some((_, rest)) => (rest, true),
+        None => (dest, false),
+    };
+    let (user, rest) = match rest.rsplit_once('@') {
+        Some((user, host)) => (Some(user.to_string()), host),
+        None => (None, rest),
+    };
+    let (host, after) = if let Some(bracketed) = rest.strip_prefix('[') {
+        bracketed.split_once(']').unwrap_or((bracketed, ""))
+    } else if is_uri || colon_ends_host {
+        let end = rest
+            .find(if is_uri { &[':', '/'][..] } else { &[':'][..] })
+            .unwrap_or(rest.len());
+        rest.split_at(end)
+    } else {
+        (rest, "")
+    };
+    let port = after
+        .strip_prefix(':')
+        .filter(|_| is_uri)
+        .and_then(|p| p.split('/').next())
+        .and_then(|p| p.parse().ok());
+    (user, host.to_string(), port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmd(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn ssh_plain_host() {
+        assert_eq!(
+            host(&cmd(&["ssh", "example.com"])).as_deref(),
+            Some("example.com")
+        );
+    }
+
+    #[test]
+    fn ssh_user_and_options() {
+        let c = cmd(&[
+            "ssh",
+            "-o",
+            "StrictHostKeyChecking=no",
+            "-p",
+            "2222",
+            "-t",
+            "me@db1",
+            "ls",
+        ]);
+        assert_eq!(host(&c).as_deref(), Some("db1"));
+    }
+
+    #[test]
+    fn options_set_on_the_command_line() {
+        let c = cmd(&["ssh", "-oStrictHostKeyChecking=no", "db1", "-o", "Port=1"]);
+        assert!(sets_option(&c, "stricthostkeychecking"));
+        assert!(!sets_option(&c, "Port"));
+        assert!(!sets_option(
+            &cmd(&["rsync", "-o", "StrictHostKeyChecking=no"]),
+            "StrictHostKeyChecking"
+        ));
+    }
+
+    #[test]
+    fn ssh_joined_option_value() {
+        let c = cmd(&["/usr/bin/ssh", "-p2222", "-vl", "root", "router"]);
+        assert_eq!(host(&c).as_deref(), Some("router"));
+    }
+
+    #[test]
+    fn ssh_uri() {
+        let c = cmd(&["ssh", "ssh://admin@switch-3:2200"]);
+        assert_eq!(host(&c).as_deref(), Some("switch-3"));
+    }
+
+    #[test]
+    fn scp_remote_source() {
+        let c = cmd(&["scp", "-P", "22", "./local", "user@backup:/srv/"]);
+        assert_eq!(host(&c).as_deref(), Some("backup"));
+    }
+
+    #[test]
+    fn sftp_with_path() {
+        let c = cmd(&["sftp", "-b", "batch", "user@files:/upload"]);
+        assert_eq!(host(&c).as_deref(), Some("files"));
+    }
+
+    #[test]
+    fn jump_hosts_from_options() {
+        let c = cmd(&["ssh", "-J", "me@bastion:2222,[fd00::1]", "target"]);
+        assert_eq!(jump_hosts(&c), ["bastion", "fd00::1"]);
+        let c = cmd(&[
+            "ssh",
+            "-vJbastion",
+            "-o",
+            "proxyjump=ssh://me@edge",
+            "target",
+        ]);
+        assert_eq!(jump_hosts(&c), ["bastion", "edge"]);
+        let c = cmd(&["scp", "-oProxyJump none", "f", "target:/tmp"]);
+        assert_eq!(jump_hosts(&c), Vec::<String>::new());
+    }
+
+    #[test]
+    fn jump_hosts_stop_at_the_destination() {
+        let c = cmd(&["ssh", "target", "ssh", "-J", "inner", "other"]);
+        assert_eq!(jump_hosts(&c), Vec::<String>::new());
+    }
+
+    #[test]
+    fn endpoints() {
+        let e = endpoint(&cmd(&["ssh", "-p", "2222", "alice@db1", "uptime"])).unwrap();
+        assert_eq!(
+            (e.user.as_deref(), e.host.as_str(), e.port),
+            (Some("alice"), "db1", Some(2222))
+        );
+
+        let e = endpoint(&cmd(&["ssh", "-l", "root", "-o", "User=bob", "alice@db1"])).unwrap();
+        assert_eq!(e.user.as_deref(), Some("root"));
+        assert_eq!(e.options, ["-o", "User=bob"]);
+
+        let e = endpoint(&cmd(&["ssh", "ssh://admin@[fd00::1]:2200"])).unwrap();
+        assert_eq!(
+            (e.user.as_deref(), e.host.as_str(), e.port),
+            (Some("admin"), "fd00::1", Some(2200))
+        );
+
+        let e = endpoint(&cmd(&["scp", "-P", "22", "-F", "cfg", "f", "db1:/tmp"])).unwrap();
+        assert_eq!(
+            (e.user, e.host.as_str(), e.port, e.options),
+            (None, "db1", Some(22), cmd(&["-F", "cfg"]))
+        );
+
+        let e = endpoint(&cmd(&["sftp", "-oPort=2022", "me@files:/upload"])).unwrap();
+        assert_eq!((e.user.as_deref(), e.port), (Some("me"), Some(2022)));
+    }
+
+    #[test]
+    fn unknown_program() {
+        assert_eq!(host(&cmd(&["rsync", "a", "b:c"])), None);
+    }
+}
@@ -0,0 +1,251 @@
+use portable_pty::CommandBuilder;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, thiserror::Error)]
+pub enum EnvironmentError {
+    #[error("working directory \"{0}\" is not a directory")]
+    NotADirectory(PathBuf),
+    #[error("{0}: command not found")]
+    NotFound(String),
+}
+
+/// Changes to the environment and working directory of the child.
+#[derive(Debug, Default, Clone)]
+pub struct ChildEnv {
+    /// Start from an empty environment instead of ours.
+    pub clear: bool,
+    pub unset: Vec<String>,
+    pub set: Vec<(String, String)>,
+    pub cwd: Option<PathBuf>,
+    pub term: Option<String>,
+    /// Force `LC_ALL=C`, so that messages come in English.
+    pub c_locale: bool,
+}
+
+impl ChildEnv {
+    /// Builds the command line with this environment applied.
+    pub fn command(&self, command: &[OsString]) -> Result<CommandBuilder, EnvironmentError> {
+        let mut cmd = CommandBuilder::new(self.program(&command[0])?);
+        cmd.args(&command[1..]);
+        self.apply(&mut cmd)?;
+        Ok(cmd)
+    }
+
+    /// Like [`ChildEnv::command`], for a child that is spawned directly
+    /// instead of into the PTY.
+    pub fn std_command(
+        &self,
+        command: &[OsString],
+    ) -> Result<std::process::Command, EnvironmentError> {
+        let mut cmd = std::process::Command::new(self.program(&command[0])?);
+        cmd.args(&command[1..]);
+        self.apply(&mut cmd)?;
+        Ok(cmd)
+    }
+
+    /// Sets up the environment and working directory of either builder.
+    fn apply(&self, cmd: &mut impl Builder) -> Result<(), EnvironmentError> {
+        if self.clear {
+            cmd.env_clear();
+        }
+        for key in &self.unset {
+            cmd.env_remove(key);
+        }
+        for (key, value) in &self.set {
+            cmd.env(key, value);
+        }
+        if let Some(ref term) = self.term {
+            cmd.env("TERM", term);
+        }
+        if self.c_locale {
+            cmd.env("LC_ALL", "C");
+        }
+        // portable-pty would fall back to the home directory, so the
+        // current one is always passed on.
+        let dir = match self.cwd {
+            Some(ref dir) => dir.clone(),
+            None => match std::env::current_dir() {
+                Ok(dir) => dir,
+                Err(_) => return Ok(()),
+            },
+        };
+        if !dir.is_dir() {
+            return Err(EnvironmentError::NotADirectory(dir));
+        }
+        cmd.cwd(&dir);
+        Ok(())
+    }
+
+    /// Looks the program up in our own PATH when the child gets none.
+    fn program(&self, program: &OsStr) -> Result<PathBuf, EnvironmentError> {
+        let child_has_path = !self.clear || self.set.iter().any(|(key, _)| key == "PATH");
+        if child_has_path || program.as_encoded_bytes().contains(&b'/') {
+            return Ok(PathBuf::from(program));
+        }
+        std::env::var_os("PATH")
+            .iter()
+            .flat_map(std::env::split_paths)
+            .map(|dir| dir.join(program))
+            .find(|candidate| is_executable(candidate))
+            .ok_or_else(|| EnvironmentError::NotFound(program.to_string_lossy().into_owned()))
+    }
+}
+
+/// The parts of a command builder that [`ChildEnv`] changes.
+trait Builder {
+    fn env_clear(&mut self);
+    fn env_remove(&mut self, key: &str);
+    fn env(&mut self, key: &str, value: &str);
+    fn cwd(&mut self, dir: &Path);
+}
+
+impl Builder for CommandBuilder {
+    fn env_clear(&mut self) {
+        CommandBuilder::env_clear(self);
+    }
+
+    fn env_remove(&mut self, key: &str) {
+        CommandBuilder::env_remove(self, key);
+    }
+
+    fn env(&mut self, key: &str, value: &str) {
+        CommandBuilder::env(self, key, value);
+    }
+
+    fn cwd(&mut self, dir: &Path) {
+        CommandBuilder::cwd(self, dir);
+    }
+}
+
+impl Builder for std::process::Command {
+    fn env_clear(&mut self) {
+        std::process::Command::env_clear(self);
+    }
+
+    fn env_remove(&mut self, key: &str) {
+        std::process::Command::env_remove(self, key);
+    }
+
+    fn env(&mut self, key: &str, value: &str) {
+        std::process::Command::env(self, key, value);
+    }
+
+    fn cwd(&mut self, dir: &Path) {
+        self.current_dir(dir);
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Parses a `KEY=VALUE` assignment.
+pub fn parse_assignment(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got \"{s}\"")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(env: &ChildEnv) -> CommandBuilder {
+        env.command(&["/bin/true".into()]).unwrap()
+    }
+
+    #[test]
+    fn assignments() {
+        assert_eq!(
+            parse_assignment("LC_ALL=C").unwrap(),
+            ("LC_ALL".into(), "C".into())
+        );
+        assert_eq!(
+            parse_assignment("EMPTY=").unwrap(),
+            ("EMPTY".into(), "".into())
+        );
+        assert_eq!(
+            parse_assignment("A=b=c").unwrap(),
+            ("A".into(), "b=c".into())
+        );
+        assert!(parse_assignment("NOVALUE").is_err());
+        assert!(parse_assignment("=x").is_err());
+    }
+
+    #[test]
+    fn set_unset_and_term() {
+        let env = ChildEnv {
+            unset: vec!["PATH".into()],
+            set: vec![("LC_ALL".into(), "C".into())],
+            term: Some("vt100".into()),
+            ..ChildEnv::default()
+        };
+        let cmd = command(&env);
+        assert_eq!(cmd.get_env("PATH"), None);
+        assert_eq!(cmd.get_env("LC_ALL"), Some(OsStr::new("C")));
+        assert_eq!(cmd.get_env("TERM"), Some(OsStr::new("vt100")));
+    }
+
+    #[test]
+    fn c_locale_wins() {
+        let env = ChildEnv {
+            set: vec![("LC_ALL".into(), "de_DE.UTF-8".into())],
+            c_locale: true,
+            ..ChildEnv::default()
+        };
+        assert_eq!(command(&env).get_env("LC_ALL"), Some(OsStr::new("C")));
+    }
+
+    #[test]
+    fn clean_environment_keeps_only_what_is_set() {
+        let env = ChildEnv {
+            clear: true,
+            set: vec![("A".into(), "1".into())],
+            ..ChildEnv::default()
+        };
+        let cmd = command(&env);
+        assert_eq!(cmd.iter_full_env_as_str().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn clean_environment_still_finds_the_program() {
+        let env = ChildEnv {
+            clear: true,
+            ..ChildEnv::default()
+        };
+        let cmd = env.command(&["sh".into()]).unwrap();
+        assert!(Path::new(&cmd.get_argv()[0]).is_absolute());
+    }
+
+    #[test]
+    fn both_builders_default_to_the_current_directory() {
+        let env = ChildEnv::default();
+        let here = std::env::current_dir().unwrap();
+        assert_eq!(command(&env).get_cwd().map(Path::new), Some(here.as_path()));
+        let cmd = env.std_command(&["/bin/true".into()]).unwrap();
+        assert_eq!(cmd.get_current_dir(), Some(here.as_path()));
+    }
+
+    #[test]
+    fn missing_working_directory() {
+        let env = ChildEnv {
+            cwd: Some("/nonexistent/sshpass".into()),
+            ..ChildEnv::default()
+        };
+        assert!(matches!(
+            env.command(&["/bin/true".into()]),
+            Err(EnvironmentError::NotADirectory(_))
+        ));
+    }
+}
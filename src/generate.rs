@@ -0,0 +1,141 @@
+const LOWER: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
+const UPPER: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DIGITS: &[u8] = b"0123456789";
+/// Symbols that are safe to type into shells, PAM prompts and config files.
+const SYMBOLS: &[u8] = b"!#%+,-.:=@^_~";
+
+#[derive(Debug, thiserror::Error)]
+pub enum GenerateError {
+    #[error("password policy allows no characters")]
+    EmptyAlphabet,
+    #[error("password length {length} is shorter than the {required} required character classes")]
+    TooShort { length: usize, required: usize },
+    #[error("failed to get random bytes: {0}")]
+    Random(getrandom::Error),
+}
+
+/// Rules a generated password has to satisfy.
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    pub length: usize,
+    pub lower: bool,
+    pub upper: bool,
+    pub digits: bool,
+    pub symbols: bool,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            length: 24,
+            lower: true,
+            upper: true,
+            digits: true,
+            symbols: true,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    fn classes(&self) -> Vec<&'static [u8]> {
+        [
+            (self.lower, LOWER),
+            (self.upper, UPPER),
+            (self.digits, DIGITS),
+            (self.symbols, SYMBOLS),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, class)| enabled.then_some(class))
+        .collect()
+    }
+}
+
+/// Generates a random password with at least one character of every class
+/// the policy enables.
+pub fn generate(policy: &PasswordPolicy) -> Result<String, GenerateError> {
+    let classes = policy.classes();
+    if classes.is_empty() {
+        return Err(GenerateError::EmptyAlphabet);
+    }
+    if policy.length < classes.len() {
+        return Err(GenerateError::TooShort {
+            length: policy.length,
+            required: classes.len(),
+        });
+    }
+    let alphabet: Vec<u8> = classes.concat();
+
+    loop {
+        let mut password = Vec::with_capacity(policy.length);
+        while password.len() < policy.length {
+            password.push(alphabet[random_below(alphabet.len())?]);
+        }
+        if classes
+            .iter()
+            .all(|class| password.iter().any(|c| class.contains(c)))
+        {
+            return Ok(String::from_utf8(password).expect("ASCII alphabet"));
+        }
+    }
+}
+
+/// Returns a uniformly distributed index below `n` (which must be <= 256).
+fn random_below(n: usize) -> Result<usize, GenerateError> {
+    let limit = 256 - 256 % n;
+    loop {
+        let mut byte = [0u8; 1];
+        getrandom::fill(&mut byte).map_err(GenerateError::Random)?;
+        if (byte[0] as usize) < limit {
+            return Ok(byte[0] as usize % n);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_policy_uses_all_classes() {
+        let pw = generate(&PasswordPolicy::default()).unwrap();
+        assert_eq!(pw.len(), 24);
+        assert!(pw.bytes().any(|c| c.is_ascii_lowercase()));
+        assert!(pw.bytes().any(|c| c.is_ascii_uppercase()));
+        assert!(pw.bytes().any(|c| c.is_ascii_digit()));
+        assert!(pw.bytes().any(|c| SYMBOLS.contains(&c)));
+    }
+
+    #[test]
+    fn restricted_alphabet() {
+        let policy = PasswordPolicy {
+            length: 12,
+            lower: false,
+            upper: false,
+            digits: true,
+            symbols: false,
+        };
+        let pw = generate(&policy).unwrap();
+        assert_eq!(pw.len(), 12);
+        assert!(pw.bytes().all(|c| c.is_ascii_digit()));
+    }
+
+    #[test]
+    fn impossible_policies() {
+        let mut policy = PasswordPolicy {
+            length: 3,
+            ..PasswordPolicy::default()
+        };
+        assert!(matches!(
+            generate(&policy),
+            Err(GenerateError::TooShort { .. })
+        ));
+        policy.lower = false;
+        policy.upper = false;
+        policy.digits = false;
+        policy.symbols = false;
+        assert!(matches!(
+            generate(&policy),
+            Err(GenerateError::EmptyAlphabet)
+        ));
+    }
+}
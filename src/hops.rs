@@ -0,0 +1,56 @@
+use regex::bytes::Regex;
+use std::sync::LazyLock;
+
+static PROMPT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"[^\s@']+@([^\s@']+)'s password:").expect("valid password prompt regex")
+});
+
+/// Returns the host if `window` contains an OpenSSH password prompt,
+/// `user@host's password:`.
+pub fn prompt_host(window: &[u8]) -> Option<String> {
+    let captures = PROMPT.captures_iter(window).last()?;
+    Some(String::from_utf8_lossy(&captures[1]).into_owned())
+}
+
+/// Passwords for the jump hosts of a ProxyJump chain. Hosts without one
+/// get the password of the destination.
+#[derive(Default, Clone)]
+pub struct HopPasswords {
+    entries: Vec<(String, Vec<u8>)>,
+}
+
+impl HopPasswords {
+    /// Adds a password. Earlier entries take precedence.
+    pub fn add(&mut self, host: String, password: Vec<u8>) {
+        self.entries.push((host, password));
+    }
+
+    pub fn for_host(&self, host: &str) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|(h, _)| h.eq_ignore_ascii_case(host))
+            .map(|(_, password)| password.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_host() {
+        assert_eq!(
+            prompt_host(b"\r\nme@bastion.example.com's password: ").as_deref(),
+            Some("bastion.example.com")
+        );
+        assert_eq!(prompt_host(b"Password: "), None);
+    }
+
+    #[test]
+    fn hosts_compare_case_insensitively() {
+        let mut hops = HopPasswords::default();
+        hops.add("Bastion".into(), b"jump".to_vec());
+        assert_eq!(hops.for_host("bastion"), Some(&b"jump"[..]));
+        assert_eq!(hops.for_host("target"), None);
+    }
+}
@@ -1,29 +1,323 @@
+mod argv;
+mod classify;
+mod config;
+#[cfg(feature = "native-ssh")]
+mod connection;
+#[cfg(unix)]
+mod control;
+mod destination;
+mod environment;
+mod generate;
+mod hops;
 mod matcher;
+mod multicall;
+#[cfg(feature = "native-ssh")]
+mod native;
+mod passphrase;
+mod passwd;
 mod password;
+mod prompts;
 mod pty;
+mod rotate;
+mod script;
+mod session;
+#[cfg(feature = "native-ssh")]
+mod sftp;
+mod simulate;
+#[cfg(all(test, unix, feature = "native-ssh"))]
+mod sshd;
+#[cfg(feature = "native-ssh")]
+mod transfer;
+#[cfg(feature = "native-ssh")]
+mod transport;
 
-use clap::Parser;
-use password::{PasswordSource, resolve_password};
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use config::{Config, Enforcement, HostKeyPolicy, Policy, Profile};
+use environment::ChildEnv;
+use generate::PasswordPolicy;
+use hops::HopPasswords;
+use passphrase::Passphrases;
+use passwd::{PasswordChange, PendingPassword};
+use password::{FileFormat, PasswordError, PasswordSource, resolve_password};
+use regex::bytes::Regex;
+use script::{PASSWORD_SECRET, Script, ScriptRunner};
+use session::{DEFAULT_LOGIN_MARKER, Session, SessionConfig};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::Write;
 use std::path::PathBuf;
 use std::process;
+use std::time::Duration;
 
 const DEFAULT_PROMPT: &str = "assword:";
 const DEFAULT_ENV_VAR: &str = "SSHPASS";
 
 const EXIT_CONFLICTING_ARGUMENTS: i32 = 2;
 const EXIT_RUNTIME_ERROR: i32 = 3;
+const EXIT_PARSE_ERROR: i32 = 4;
 
 #[derive(Parser)]
 #[command(
     name = "sshpass",
     about = "Non-interactive ssh password authentication",
-    version
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    disable_help_subcommand = true
 )]
 struct Cli {
+    #[command(subcommand)]
+    action: Option<Action>,
+
     /// Provide password as argument (security unwise)
     #[arg(short = 'p', value_name = "password")]
-    password: Option<String>,
+    password: Option<OsString>,
+
+    #[command(flatten)]
+    password_source: PasswordArgs,
+
+    /// Which string sshpass searches for to detect a password prompt (default: "assword:")
+    #[arg(short = 'P', value_name = "prompt")]
+    prompt: Option<String>,
+
+    /// Also recognise the password prompts of localised PAM modules
+    #[arg(long)]
+    localized_prompts: bool,
+
+    /// Drive the login dialog with an expect-style script instead of the prompt
+    #[arg(long, value_name = "file")]
+    script: Option<PathBuf>,
+
+    /// Exit with 0 as soon as the login succeeded instead of running a session
+    #[arg(long)]
+    check_login: bool,
+
+    /// Regex that marks a successful login (default: a shell prompt)
+    #[arg(long, value_name = "regex", requires = "check_login")]
+    login_marker: Option<String>,
+
+    /// Give up with exit code 21 if an unanswered prompt gets no further for
+    /// this many seconds, showing where it got stuck
+    #[arg(long, value_name = "seconds", value_parser = clap::value_parser!(u64).range(1..))]
+    stall_timeout: Option<u64>,
+
+    /// Take the private key passphrase from file
+    #[arg(long, value_name = "filename")]
+    passphrase_file: Option<PathBuf>,
+
+    /// Take the private key passphrase from an env-var
+    #[arg(long, value_name = "env_var")]
+    passphrase_env: Option<String>,
+
+    /// Take the private key passphrase from a file descriptor
+    #[cfg(unix)]
+    #[arg(long, value_name = "number")]
+    passphrase_fd: Option<i32>,
+
+    /// Take the new password for a forced password change from file
+    #[arg(long, value_name = "filename")]
+    new_password_file: Option<PathBuf>,
+
+    /// Take the new password for a forced password change from an env-var
+    #[arg(long, value_name = "env_var")]
+    new_password_env: Option<String>,
+
+    /// Take the new password for a forced password change from a file descriptor
+    #[cfg(unix)]
+    #[arg(long, value_name = "number")]
+    new_password_fd: Option<i32>,
+
+    /// Generate the new password for a forced password change and save it to file
+    #[arg(long, value_name = "filename")]
+    generate_password: Option<PathBuf>,
+
+    #[command(flatten)]
+    policy: PolicyArgs,
+
+    #[command(flatten)]
+    child_env: EnvArgs,
+
+    /// Width of the terminal the command sees (default: ours, $COLUMNS or 80)
+    #[arg(long, value_name = "number", value_parser = clap::value_parser!(u16).range(1..))]
+    cols: Option<u16>,
+
+    /// Height of the terminal the command sees (default: ours, $LINES or 24)
+    #[arg(long, value_name = "number", value_parser = clap::value_parser!(u16).range(1..))]
+    rows: Option<u16>,
+
+    /// Log in with the built-in SSH client instead of running ssh; the
+    /// command must be `ssh [options] destination [command]`
+    #[cfg(feature = "native-ssh")]
+    #[arg(long, conflicts_with_all = [
+        "prompt", "localized_prompts", "script", "check_login", "stall_timeout",
+        "passphrase_file", "passphrase_env", "new_password_file", "new_password_env",
+        "generate_password", "set_env", "unset", "clean_env", "chdir", "term", "c_locale",
+        "cols", "rows",
+    ])]
+    native: bool,
+
+    /// Log in once and leave an OpenSSH control master running in the
+    /// background; later runs for the same host go through it
+    #[cfg(unix)]
+    #[arg(long, conflicts_with = "check_login")]
+    master: bool,
+
+    /// Act as ssh, scp, sftp or rsync-ssh, taking all further arguments as
+    /// theirs (must come first)
+    #[arg(long = "as", value_name = "program")]
+    as_program: Option<String>,
+
+    /// Command and arguments to run
+    #[arg(trailing_var_arg = true, required = true)]
+    command: Vec<OsString>,
+}
+
+#[derive(Subcommand)]
+enum Action {
+    /// Change the password on the remote host and update the password file
+    Rotate(RotateArgs),
+    /// Replay recorded output and show how sshpass would answer it. Exits
+    /// with the code sshpass would exit with, or 0 if the command decides
+    Simulate(SimulateArgs),
+    /// List or stop the control masters started with --master
+    #[cfg(unix)]
+    #[command(subcommand)]
+    Master(MasterAction),
+    /// Upload files over SFTP: put [options] <local>... <[user@]host:path>
+    #[cfg(feature = "native-ssh")]
+    Put(TransferArgs),
+    /// Download files over SFTP: get [options] <[user@]host:path>... <local>
+    #[cfg(feature = "native-ssh")]
+    Get(TransferArgs),
+}
+
+#[cfg(unix)]
+#[derive(Subcommand)]
+enum MasterAction {
+    /// Show the masters and whether they still run
+    List,
+    /// Stop the masters for the given destinations
+    Stop(StopArgs),
+}
+
+#[cfg(unix)]
+#[derive(Args)]
+struct StopArgs {
+    /// Stop all masters
+    #[arg(long, conflicts_with = "destinations")]
+    all: bool,
+
+    /// Destinations as given to ssh, [user@]host or ssh://[user@]host[:port]
+    #[arg(required_unless_present = "all", value_name = "destination")]
+    destinations: Vec<String>,
+}
+
+#[derive(Args)]
+struct RotateArgs {
+    /// File holding the current password; receives the new one
+    #[arg(short = 'f', value_name = "filename")]
+    file: Option<PathBuf>,
+
+    /// Which string sshpass searches for to detect a password prompt (default: "assword:")
+    #[arg(short = 'P', value_name = "prompt")]
+    prompt: Option<String>,
+
+    /// Also recognise the password prompts of localised PAM modules
+    #[arg(long)]
+    localized_prompts: bool,
+
+    /// Command that changes the password on the remote host
+    #[arg(long, value_name = "command", default_value = "passwd")]
+    change_command: String,
+
+    #[command(flatten)]
+    policy: PolicyArgs,
+
+    #[command(flatten)]
+    child_env: EnvArgs,
 
+    /// ssh command and arguments used to log in
+    #[arg(trailing_var_arg = true, required = true)]
+    command: Vec<String>,
+}
+
+#[derive(Args)]
+struct SimulateArgs {
+    /// Which string sshpass searches for to detect a password prompt (default: "assword:")
+    #[arg(short = 'P', value_name = "prompt")]
+    prompt: Option<String>,
+
+    /// Also recognise the password prompts of localised PAM modules
+    #[arg(long)]
+    localized_prompts: bool,
+
+    /// Drive the login dialog with an expect-style script instead of the prompt
+    #[arg(long, value_name = "file")]
+    script: Option<PathBuf>,
+
+    /// Exit with 0 as soon as the login succeeded
+    #[arg(long)]
+    check_login: bool,
+
+    /// Regex that marks a successful login (default: a shell prompt)
+    #[arg(long, value_name = "regex", requires = "check_login")]
+    login_marker: Option<String>,
+
+    /// Answer passphrase prompts, as if a passphrase was given
+    #[arg(long)]
+    passphrase: bool,
+
+    /// Answer forced password change prompts, as if a new password was given
+    #[arg(long)]
+    new_password: bool,
+
+    /// Use the prompt, script and login marker configured for this host
+    #[arg(long, value_name = "name")]
+    host: Option<String>,
+
+    /// Recorded output, as raw bytes or an asciicast recording
+    #[arg(value_name = "transcript")]
+    transcript: PathBuf,
+}
+
+#[cfg(feature = "native-ssh")]
+#[derive(Args)]
+struct TransferArgs {
+    #[command(flatten)]
+    password_source: PasswordArgs,
+
+    /// Copy directories recursively
+    #[arg(short = 'r')]
+    recursive: bool,
+
+    /// Keep the permissions and times of the files
+    #[arg(long)]
+    preserve: bool,
+
+    /// Continue files a previous transfer left incomplete
+    #[arg(long)]
+    resume: bool,
+
+    /// Report progress on stderr, a line at a time
+    #[arg(long)]
+    progress: bool,
+
+    /// Port to connect to on the remote host
+    #[arg(short = 'P', value_name = "port")]
+    port: Option<u16>,
+
+    /// ssh option for the connection, as for ssh -o
+    #[arg(short = 'o', value_name = "option")]
+    ssh_options: Vec<String>,
+
+    /// Files to copy, followed by where to put them
+    #[arg(required = true, num_args = 2.., value_name = "path")]
+    paths: Vec<String>,
+}
+
+/// Where to read the password from, besides `-p`.
+#[derive(Args)]
+struct PasswordArgs {
     /// Password is passed as env-var (default: SSHPASS)
     #[arg(short = 'e', value_name = "env_var", num_args = 0..=1, default_missing_value = DEFAULT_ENV_VAR, require_equals = true)]
     env: Option<String>,
@@ -32,18 +326,96 @@ struct Cli {
     #[arg(short = 'f', value_name = "filename")]
     file: Option<PathBuf>,
 
+    /// How to read the password file: first-line, whole, strip-newline,
+    /// line:N or dotenv:KEY (default: first-line)
+    #[arg(long, value_name = "format", requires = "file")]
+    file_format: Option<FileFormat>,
+
     /// Use number as file descriptor for getting password
     #[cfg(unix)]
     #[arg(short = 'd', value_name = "number")]
     fd: Option<i32>,
 
-    /// Which string sshpass searches for to detect a password prompt
-    #[arg(short = 'P', value_name = "prompt", default_value = DEFAULT_PROMPT)]
-    prompt: String,
+    /// Take password from a systemd credential ($CREDENTIALS_DIRECTORY)
+    #[arg(long, value_name = "name")]
+    credential: Option<String>,
+}
 
-    /// Command and arguments to run
-    #[arg(trailing_var_arg = true, required = true)]
-    command: Vec<String>,
+/// Complexity rules for generated passwords.
+#[derive(Args)]
+struct PolicyArgs {
+    /// Length of generated passwords
+    #[arg(long, value_name = "number", default_value_t = PasswordPolicy::default().length)]
+    password_length: usize,
+
+    /// Leave lowercase letters out of generated passwords
+    #[arg(long)]
+    no_lower: bool,
+
+    /// Leave uppercase letters out of generated passwords
+    #[arg(long)]
+    no_upper: bool,
+
+    /// Leave digits out of generated passwords
+    #[arg(long)]
+    no_digits: bool,
+
+    /// Leave symbols out of generated passwords
+    #[arg(long)]
+    no_symbols: bool,
+}
+
+impl PolicyArgs {
+    fn policy(&self) -> PasswordPolicy {
+        PasswordPolicy {
+            length: self.password_length,
+            lower: !self.no_lower,
+            upper: !self.no_upper,
+            digits: !self.no_digits,
+            symbols: !self.no_symbols,
+        }
+    }
+}
+
+/// Environment and working directory of the command.
+#[derive(Args)]
+struct EnvArgs {
+    /// Set an environment variable for the command
+    #[arg(long = "env", value_name = "KEY=VALUE", value_parser = environment::parse_assignment)]
+    set_env: Vec<(String, String)>,
+
+    /// Remove an environment variable for the command
+    #[arg(long, value_name = "KEY")]
+    unset: Vec<String>,
+
+    /// Start the command with an empty environment
+    #[arg(long)]
+    clean_env: bool,
+
+    /// Run the command in this directory (default: the current directory)
+    #[arg(long, value_name = "dir")]
+    chdir: Option<PathBuf>,
+
+    /// Set TERM for the command
+    #[arg(long, value_name = "name")]
+    term: Option<String>,
+
+    /// Run the command with LC_ALL=C, so that prompts come in English
+    #[arg(long)]
+    c_locale: bool,
+}
+
+impl EnvArgs {
+    fn into_child_env(self) -> ChildEnv {
+        ChildEnv {
+            clear: self.clean_env,
+            unset: self.unset,
+            set: self.set_env,
+            cwd: self.chdir,
+            term: self.term,
+            c_locale: self.c_locale,
+        }
+    }
 }
 
 fn main() {
@@ -52,13 +424,539 @@ fn main() {
 }
 
 fn run() -> i32 {
-    let cli = Cli::parse();
+    let args: Vec<OsString> = std::env::args_os().collect();
+    match multicall::invocation(&args) {
+        Some(Ok((program, rest))) => return run_as(program, rest),
+        Some(Err(e)) => {
+            eprintln!("SSHPASS: {e}");
+            return EXIT_CONFLICTING_ARGUMENTS;
+        }
+        None => {}
+    }
+
+    let mut cli = Cli::parse_from(&args);
+    hide_secrets(&args);
+    if cli.as_program.is_some() {
+        eprintln!("SSHPASS: {} must be the first argument", multicall::AS_FLAG);
+        return EXIT_CONFLICTING_ARGUMENTS;
+    }
 
-    let source = match determine_password_source(&cli) {
+    let config = match Config::load() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("SSHPASS: {e}");
+            return EXIT_RUNTIME_ERROR;
+        }
+    };
+
+    match cli.action {
+        Some(Action::Rotate(args)) => return run_rotate(args, &config),
+        Some(Action::Simulate(args)) => return run_simulate(args, &config),
+        #[cfg(unix)]
+        Some(Action::Master(action)) => return run_master(action),
+        #[cfg(feature = "native-ssh")]
+        Some(Action::Put(args)) => return run_transfer(args, &config, true),
+        #[cfg(feature = "native-ssh")]
+        Some(Action::Get(args)) => return run_transfer(args, &config, false),
+        None => {}
+    }
+    let mut profile = config.profile(destination::host(&lossy(&cli.command)).as_deref());
+    with_host_key(&mut cli.command, profile.host_key);
+    let words = lossy(&cli.command);
+
+    let source = match determine_password_source(
+        cli.password.as_ref(),
+        &cli.password_source,
+        &mut profile,
+        &config.policy,
+    ) {
         Ok(s) => s,
         Err(code) => return code,
     };
 
+    #[cfg(feature = "native-ssh")]
+    if cli.native {
+        #[cfg(unix)]
+        if cli.master {
+            eprintln!("SSHPASS: --master needs ssh itself, not --native");
+            return EXIT_CONFLICTING_ARGUMENTS;
+        }
+        return run_native(&words, &source);
+    }
+
+    let script = match cli.script.as_ref().or(profile.script.as_ref()) {
+        Some(path) => match Script::load(path) {
+            Ok(s) => Some(s),
+            Err(e) => {
+                eprintln!("SSHPASS: {e}");
+                return EXIT_PARSE_ERROR;
+            }
+        },
+        None => None,
+    };
+
+    // Scripts expect the login dialog a master would skip.
+    #[cfg(unix)]
+    let reusing = match use_master(&mut cli.command, cli.master, script.is_none()) {
+        Ok(MasterUse::AlreadyRunning) => return 0,
+        Ok(MasterUse::Reused) => true,
+        Ok(MasterUse::Started | MasterUse::Direct) => false,
+        Err(code) => return code,
+    };
+    #[cfg(not(unix))]
+    let reusing = false;
+
+    let password = if !reusing && script.as_ref().is_none_or(Script::uses_password) {
+        match resolve_password(&source) {
+            Ok(pw) => pw,
+            Err(e) => {
+                eprintln!("SSHPASS: {e}");
+                return EXIT_RUNTIME_ERROR;
+            }
+        }
+    } else {
+        Vec::new()
+    };
+
+    let script = match script.map(|s| script_runner(s, &password)).transpose() {
+        Ok(runner) => runner,
+        Err(e) => {
+            eprintln!("SSHPASS: {e}");
+            return EXIT_RUNTIME_ERROR;
+        }
+    };
+
+    let passphrases = match determine_passphrases(&cli, &config) {
+        Ok(p) => p,
+        Err(code) => return code,
+    };
+
+    let hop_passwords = match determine_hop_passwords(&words, &config, &source) {
+        Ok(h) => h,
+        Err(code) => return code,
+    };
+
+    let password_change = match determine_password_change(&cli, &password) {
+        Ok(c) => c,
+        Err(code) => return code,
+    };
+
+    let login_marker =
+        match determine_login_marker(cli.check_login, cli.login_marker.or(profile.login_marker)) {
+            Ok(m) => m,
+            Err(code) => return code,
+        };
+
+    let config = pty::RunConfig {
+        command: cli.command,
+        password,
+        prompt: cli
+            .prompt
+            .or(profile.prompt)
+            .unwrap_or_else(|| DEFAULT_PROMPT.to_string()),
+        localized_prompts: cli.localized_prompts || profile.localized_prompts == Some(true),
+        script,
+        login_marker,
+        passphrases,
+        hop_passwords,
+        password_change,
+        stall_timeout: cli
+            .stall_timeout
+            .map(Duration::from_secs)
+            .or(profile.stall_timeout),
+        env: ChildEnv {
+            c_locale: cli.child_env.c_locale || profile.c_locale == Some(true),
+            ..cli.child_env.into_child_env()
+        },
+        cols: cli.cols,
+        rows: cli.rows,
+        inherit_stdio: false,
+    };
+
+    match pty::run(config) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("PTY error: {e}");
+            EXIT_RUNTIME_ERROR
+        }
+    }
+}
+
+/// What [`use_master`] did to the command.
+#[cfg(unix)]
+enum MasterUse {
+    /// It now starts a master.
+    Started,
+    /// It now goes through a running master.
+    Reused,
+    /// A master should be started, but one runs already.
+    AlreadyRunning,
+    /// It connects on its own.
+    Direct,
+}
+
+/// With `start`, turns `command` into one that leaves a control master
+/// behind. Otherwise, if `reuse` is set and a master for the same user,
+/// host and port runs, routes `command` through it.
+#[cfg(unix)]
+fn use_master(command: &mut Vec<OsString>, start: bool, reuse: bool) -> Result<MasterUse, i32> {
+    let master = control::master_for(&lossy(command));
+    if start {
+        let is_ssh = std::path::Path::new(&command[0])
+            .file_name()
+            .is_some_and(|name| name == "ssh");
+        let Some(master) = master.filter(|_| is_ssh) else {
+            eprintln!("SSHPASS: --master needs an ssh command with a destination");
+            return Err(EXIT_CONFLICTING_ARGUMENTS);
+        };
+        if master.is_alive() {
+            eprintln!("SSHPASS: a master for {} is already running", master.remote);
+            return Ok(MasterUse::AlreadyRunning);
+        }
+        // A socket left behind by a master that died would block ssh.
+        let _ = std::fs::remove_file(&master.socket);
+        return match control::start_command(command, &master) {
+            Ok(started) => {
+                *command = started;
+                Ok(MasterUse::Started)
+            }
+            Err(e) => {
+                eprintln!("SSHPASS: {}: {e}", master.socket.display());
+                Err(EXIT_RUNTIME_ERROR)
+            }
+        };
+    }
+    match master {
+        Some(master) if reuse && master.is_alive() => {
+            *command = control::reuse_command(command, &master);
+            Ok(MasterUse::Reused)
+        }
+        _ => Ok(MasterUse::Direct),
+    }
+}
+
+#[cfg(unix)]
+fn run_master(action: MasterAction) -> i32 {
+    let masters = match action {
+        MasterAction::List => {
+            return match control::masters() {
+                Ok(masters) => {
+                    for master in masters {
+                        let state = if master.is_alive() {
+                            "running"
+                        } else {
+                            "stale"
+                        };
+                        println!("{}\t{state}\t{}", master.remote, master.socket.display());
+                    }
+                    0
+                }
+                Err(e) => {
+                    eprintln!("SSHPASS: {e}");
+                    EXIT_RUNTIME_ERROR
+                }
+            };
+        }
+        MasterAction::Stop(args) if args.all => match control::masters() {
+            Ok(masters) => masters,
+            Err(e) => {
+                eprintln!("SSHPASS: {e}");
+                return EXIT_RUNTIME_ERROR;
+            }
+        },
+        MasterAction::Stop(args) => args
+            .destinations
+            .into_iter()
+            .filter_map(|d| control::master_for(&["ssh".to_string(), d]))
+            .collect(),
+    };
+
+    let mut code = 0;
+    for master in masters {
+        if let Err(e) = master.stop() {
+            eprintln!("SSHPASS: {}: {e}", master.remote);
+            code = EXIT_RUNTIME_ERROR;
+        }
+    }
+    code
+}
+
+#[cfg(feature = "native-ssh")]
+fn run_native(command: &[String], source: &PasswordSource) -> i32 {
+    let target = match native::target(command) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("SSHPASS: {e}");
+            return EXIT_CONFLICTING_ARGUMENTS;
+        }
+    };
+    let password = match resolve_password(source) {
+        Ok(pw) => pw,
+        Err(e) => {
+            eprintln!("SSHPASS: {e}");
+            return EXIT_RUNTIME_ERROR;
+        }
+    };
+    match native::run(target, &password) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("SSHPASS: {e}");
+            EXIT_RUNTIME_ERROR
+        }
+    }
+}
+
+/// Runs `put` if `upload` is set, else `get`.
+#[cfg(feature = "native-ssh")]
+fn run_transfer(args: TransferArgs, config: &Config, upload: bool) -> i32 {
+    let (sources, target) = args.paths.split_at(args.paths.len() - 1);
+    let remote = if upload { target } else { sources };
+    let remote = match remote
+        .iter()
+        .map(|p| transfer::remote_path(p))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("SSHPASS: {e}");
+            return EXIT_CONFLICTING_ARGUMENTS;
+        }
+    };
+    let destination = &remote[0].destination;
+    if remote.iter().any(|r| r.destination != *destination) {
+        eprintln!("SSHPASS: all remote paths must be on the same host");
+        return EXIT_CONFLICTING_ARGUMENTS;
+    }
+
+    let mut ssh = vec!["ssh".to_string()];
+    for option in &args.ssh_options {
+        ssh.extend(["-o".to_string(), option.clone()]);
+    }
+    if let Some(port) = args.port {
+        ssh.extend(["-p".to_string(), port.to_string()]);
+    }
+    ssh.extend(["--".to_string(), destination.clone()]);
+    let mut login = match native::target(&ssh) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("SSHPASS: {e}");
+            return EXIT_CONFLICTING_ARGUMENTS;
+        }
+    };
+
+    let mut profile = config.profile(Some(&login.host));
+    if let Some(policy) = profile.host_key
+        && !destination::sets_option(&ssh, "StrictHostKeyChecking")
+    {
+        login.host_key_check = policy.into();
+    }
+    let source = match determine_password_source(
+        None,
+        &args.password_source,
+        &mut profile,
+        &config.policy,
+    ) {
+        Ok(s) => s,
+        Err(code) => return code,
+    };
+    let password = match resolve_password(&source) {
+        Ok(pw) => pw,
+        Err(e) => {
+            eprintln!("SSHPASS: {e}");
+            return EXIT_RUNTIME_ERROR;
+        }
+    };
+
+    let transport = match native::connect(&login, &password) {
+        Ok(Ok(transport)) => transport,
+        Ok(Err(code)) => return code,
+        Err(e) => {
+            eprintln!("SSHPASS: {e}");
+            return EXIT_RUNTIME_ERROR;
+        }
+    };
+    let sftp = match connection::Channel::open_session(transport).and_then(sftp::Sftp::new) {
+        Ok(sftp) => sftp,
+        Err(e) => {
+            eprintln!("SSHPASS: sftp: {e}");
+            return EXIT_RUNTIME_ERROR;
+        }
+    };
+
+    let options = transfer::Options {
+        recursive: args.recursive,
+        preserve: args.preserve,
+        resume: args.resume,
+        progress: args.progress,
+    };
+    let result = if upload {
+        let sources: Vec<PathBuf> = sources.iter().map(PathBuf::from).collect();
+        transfer::upload(&sftp, &sources, &remote[0].path, options)
+    } else {
+        let sources: Vec<PathBuf> = remote.into_iter().map(|r| r.path).collect();
+        transfer::download(&sftp, &sources, std::path::Path::new(&target[0]), options)
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("SSHPASS: {e}");
+            EXIT_RUNTIME_ERROR
+        }
+    }
+}
+
+/// The login marker to watch for with `--check-login`: `pattern`, or the
+/// default shell prompt.
+fn determine_login_marker(check: bool, pattern: Option<String>) -> Result<Option<Regex>, i32> {
+    if !check {
+        return Ok(None);
+    }
+    let pattern = pattern.unwrap_or_else(|| DEFAULT_LOGIN_MARKER.to_string());
+    match Regex::new(&pattern) {
+        Ok(re) => Ok(Some(re)),
+        Err(e) => {
+            eprintln!("SSHPASS: invalid login marker: {e}");
+            Err(EXIT_PARSE_ERROR)
+        }
+    }
+}
+
+fn run_simulate(args: SimulateArgs, config: &Config) -> i32 {
+    let profile = config.profile(args.host.as_deref());
+
+    // Nothing is sent anywhere, so secrets stay empty.
+    let script = match args.script.as_ref().or(profile.script.as_ref()) {
+        Some(path) => match Script::load(path) {
+            Ok(script) => {
+                let mut secrets = HashMap::new();
+                secrets.insert(PASSWORD_SECRET.to_string(), Vec::new());
+                for (name, _) in script.secrets() {
+                    secrets.insert(name.clone(), Vec::new());
+                }
+                Some(ScriptRunner::new(script, secrets))
+            }
+            Err(e) => {
+                eprintln!("SSHPASS: {e}");
+                return EXIT_PARSE_ERROR;
+            }
+        },
+        None => None,
+    };
+
+    let login_marker = match determine_login_marker(
+        args.check_login,
+        args.login_marker.or(profile.login_marker),
+    ) {
+        Ok(m) => m,
+        Err(code) => return code,
+    };
+
+    let mut passphrases = Passphrases::default();
+    if args.passphrase {
+        passphrases.add(None, Vec::new());
+    }
+
+    let chunks = match simulate::load(&args.transcript) {
+        Ok(chunks) => chunks,
+        Err(e) => {
+            eprintln!("SSHPASS: {e}");
+            return EXIT_RUNTIME_ERROR;
+        }
+    };
+    let session = Session::new(SessionConfig {
+        password: Vec::new(),
+        prompt: args
+            .prompt
+            .or(profile.prompt)
+            .unwrap_or_else(|| DEFAULT_PROMPT.to_string()),
+        localized_prompts: args.localized_prompts || profile.localized_prompts == Some(true),
+        script,
+        login_marker,
+        passphrases,
+        hop_passwords: HopPasswords::default(),
+        password_change: args
+            .new_password
+            .then(|| PasswordChange::new(Vec::new(), Vec::new())),
+        // A recording has no notion of time.
+        stall_timeout: None,
+    });
+
+    let mut out = std::io::stdout().lock();
+    let result = simulate::replay(session, &chunks, &mut out).and_then(|code| {
+        match code {
+            Some(code) => writeln!(out, "exit code {code}")?,
+            None => writeln!(out, "exit code of the command")?,
+        }
+        Ok(code.unwrap_or(0))
+    });
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("SSHPASS: {e}");
+            EXIT_RUNTIME_ERROR
+        }
+    }
+}
+
+/// The command line as text, for picking out hosts and options. Bytes
+/// that are not UTF-8 only matter to the command itself.
+fn lossy(command: &[OsString]) -> Vec<String> {
+    command
+        .iter()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Passes the profile's host key policy to an ssh, scp or sftp command,
+/// unless its command line sets one.
+fn with_host_key(command: &mut Vec<OsString>, policy: Option<HostKeyPolicy>) {
+    let words = lossy(command);
+    if let Some(policy) = policy
+        && destination::host(&words).is_some()
+        && !destination::sets_option(&words, "StrictHostKeyChecking")
+    {
+        command.splice(1..1, ["-o".into(), policy.ssh_option().into()]);
+    }
+}
+
+/// Runs the real `program` with password handling configured through
+/// `SSHPASS` or the config file, or as is if neither has a password.
+fn run_as(program: &str, args: &[OsString]) -> i32 {
+    let real = match multicall::find_program(program) {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("SSHPASS: {e}");
+            return EXIT_RUNTIME_ERROR;
+        }
+    };
+    let mut command = vec![real.into_os_string()];
+    command.extend_from_slice(args);
+    let words = lossy(&command);
+
+    #[cfg(unix)]
+    if let Some(master) = control::master_for(&words)
+        && master.is_alive()
+    {
+        return exec(&control::reuse_command(&command, &master));
+    }
+
+    let config = match Config::load() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("SSHPASS: {e}");
+            return EXIT_RUNTIME_ERROR;
+        }
+    };
+    let mut profile = config.profile(destination::host(&words).as_deref());
+    with_host_key(&mut command, profile.host_key);
+
+    let source = if std::env::var_os(DEFAULT_ENV_VAR).is_some() {
+        PasswordSource::Env(DEFAULT_ENV_VAR.to_string())
+    } else if let Some(source) = profile.password.take() {
+        source
+    } else {
+        return exec(&command);
+    };
     let password = match resolve_password(&source) {
         Ok(pw) => pw,
         Err(e) => {
@@ -67,10 +965,42 @@ fn run() -> i32 {
         }
     };
 
+    let mut passphrases = Passphrases::default();
+    for (pattern, source) in config.key_passphrases() {
+        match resolve_password(&source) {
+            Ok(passphrase) => passphrases.add(Some(pattern), passphrase),
+            Err(e) => {
+                eprintln!("SSHPASS: {e}");
+                return EXIT_RUNTIME_ERROR;
+            }
+        }
+    }
+
+    let hop_passwords = match determine_hop_passwords(&words, &config, &source) {
+        Ok(h) => h,
+        Err(code) => return code,
+    };
+
     let config = pty::RunConfig {
-        command: cli.command,
+        command,
         password,
-        prompt: cli.prompt,
+        prompt: profile.prompt.unwrap_or_else(|| DEFAULT_PROMPT.to_string()),
+        localized_prompts: profile.localized_prompts == Some(true),
+        script: None,
+        login_marker: None,
+        passphrases,
+        hop_passwords,
+        password_change: None,
+        // Once the data flows over our stdio the PTY goes quiet, which
+        // would look like a stall.
+        stall_timeout: None,
+        env: ChildEnv {
+            c_locale: profile.c_locale == Some(true),
+            ..ChildEnv::default()
+        },
+        cols: None,
+        rows: None,
+        inherit_stdio: true,
     };
 
     match pty::run(config) {
@@ -82,25 +1012,152 @@ fn run() -> i32 {
     }
 }
 
-fn determine_password_source(cli: &Cli) -> Result<PasswordSource, i32> {
+/// Replaces sshpass with `command`.
+fn exec(command: &[OsString]) -> i32 {
+    let mut cmd = process::Command::new(&command[0]);
+    cmd.args(&command[1..]);
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        let e = cmd.exec();
+        eprintln!("SSHPASS: {}: {e}", command[0].to_string_lossy());
+        EXIT_RUNTIME_ERROR
+    }
+    #[cfg(not(unix))]
+    match cmd.status() {
+        Ok(status) => status.code().unwrap_or(255),
+        Err(e) => {
+            eprintln!("SSHPASS: {}: {e}", command[0].to_string_lossy());
+            EXIT_RUNTIME_ERROR
+        }
+    }
+}
+
+fn run_rotate(args: RotateArgs, config: &Config) -> i32 {
+    let mut profile = config.profile(destination::host(&args.command).as_deref());
+    let file = match (args.file, profile.password.take()) {
+        (Some(path), _) | (None, Some(PasswordSource::File(path, FileFormat::FirstLine))) => path,
+        (None, Some(PasswordSource::File(path, _))) => {
+            eprintln!(
+                "SSHPASS: rotate can only update password files holding just the password, not \"{}\"",
+                path.display()
+            );
+            return EXIT_CONFLICTING_ARGUMENTS;
+        }
+        _ => {
+            eprintln!("SSHPASS: rotate needs a password file to update (-f or password-file)");
+            return EXIT_CONFLICTING_ARGUMENTS;
+        }
+    };
+
+    let mut passphrases = Passphrases::default();
+    for (pattern, source) in config.key_passphrases() {
+        match resolve_password(&source) {
+            Ok(passphrase) => passphrases.add(Some(pattern), passphrase),
+            Err(e) => {
+                eprintln!("SSHPASS: {e}");
+                return EXIT_RUNTIME_ERROR;
+            }
+        }
+    }
+
+    let hop_passwords = match determine_hop_passwords(
+        &args.command,
+        config,
+        &PasswordSource::File(file.clone(), FileFormat::FirstLine),
+    ) {
+        Ok(h) => h,
+        Err(code) => return code,
+    };
+
+    let rotation = rotate::Rotation {
+        login: args.command,
+        file,
+        prompt: args
+            .prompt
+            .or(profile.prompt)
+            .unwrap_or_else(|| DEFAULT_PROMPT.to_string()),
+        localized_prompts: args.localized_prompts || profile.localized_prompts == Some(true),
+        change_command: args
+            .change_command
+            .split_whitespace()
+            .map(str::to_string)
+            .collect(),
+        policy: args.policy.policy(),
+        passphrases,
+        hop_passwords,
+        env: ChildEnv {
+            c_locale: args.child_env.c_locale || profile.c_locale == Some(true),
+            ..args.child_env.into_child_env()
+        },
+    };
+
+    match rotate::run(rotation) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("SSHPASS: {e}");
+            EXIT_RUNTIME_ERROR
+        }
+    }
+}
+
+/// Overwrites passwords given on the command line, now that they have been
+/// copied, so that other users cannot read them from the process list.
+fn hide_secrets(args: &[OsString]) {
+    let mut command = Cli::command();
+    command.build();
+    let spans = argv::secret_spans(&command, args);
+    if !spans.is_empty() && !argv::conceal(args, &spans) {
+        eprintln!("SSHPASS: warning: could not hide the password from the process list");
+    }
+}
+
+/// Picks the one password source given by `-p` (`direct`) or `args`, or
+/// the profile's, or stdin.
+fn determine_password_source(
+    direct: Option<&OsString>,
+    args: &PasswordArgs,
+    profile: &mut Profile,
+    policy: &Policy,
+) -> Result<PasswordSource, i32> {
     let mut sources: Vec<PasswordSource> = Vec::new();
 
-    if let Some(ref pw) = cli.password {
+    if let Some(pw) = direct {
+        match policy.password_argument {
+            Enforcement::Allow => {}
+            Enforcement::Warn => eprintln!(
+                "SSHPASS: warning: {}",
+                PasswordError::VisibleArgument { refused: false }
+            ),
+            Enforcement::Refuse => {
+                eprintln!(
+                    "SSHPASS: {}",
+                    PasswordError::VisibleArgument { refused: true }
+                );
+                return Err(EXIT_RUNTIME_ERROR);
+            }
+        }
         sources.push(PasswordSource::Direct(pw.clone()));
     }
-    if let Some(ref var) = cli.env {
+    if let Some(ref var) = args.env {
         sources.push(PasswordSource::Env(var.clone()));
     }
-    if let Some(ref path) = cli.file {
-        sources.push(PasswordSource::File(path.clone()));
+    if let Some(ref path) = args.file {
+        sources.push(PasswordSource::File(
+            path.clone(),
+            args.file_format.clone().unwrap_or_default(),
+        ));
     }
     #[cfg(unix)]
-    if let Some(fd) = cli.fd {
+    if let Some(fd) = args.fd {
         sources.push(PasswordSource::Fd(fd));
     }
+    if let Some(ref name) = args.credential {
+        sources.push(PasswordSource::Credential(name.clone()));
+    }
 
     match sources.len() {
-        0 => Ok(PasswordSource::Stdin),
+        0 => Ok(profile.password.take().unwrap_or(PasswordSource::Stdin)),
         1 => Ok(sources.into_iter().next().unwrap()),
         _ => {
             eprintln!("SSHPASS: conflicting password source");
@@ -108,3 +1165,142 @@ fn determine_password_source(cli: &Cli) -> Result<PasswordSource, i32> {
         }
     }
 }
+
+fn determine_passphrases(cli: &Cli, config: &Config) -> Result<Passphrases, i32> {
+    let mut sources: Vec<(Option<String>, PasswordSource)> = Vec::new();
+
+    if let Some(ref path) = cli.passphrase_file {
+        sources.push((
+            None,
+            PasswordSource::File(path.clone(), FileFormat::default()),
+        ));
+    }
+    if let Some(ref var) = cli.passphrase_env {
+        sources.push((None, PasswordSource::Env(var.clone())));
+    }
+    #[cfg(unix)]
+    if let Some(fd) = cli.passphrase_fd {
+        sources.push((None, PasswordSource::Fd(fd)));
+    }
+
+    match sources.len() {
+        0 => sources.extend(
+            config
+                .key_passphrases()
+                .into_iter()
+                .map(|(pattern, source)| (Some(pattern), source)),
+        ),
+        1 => {}
+        _ => {
+            eprintln!("SSHPASS: conflicting passphrase source");
+            return Err(EXIT_CONFLICTING_ARGUMENTS);
+        }
+    }
+
+    let mut passphrases = Passphrases::default();
+    for (pattern, source) in sources {
+        match resolve_password(&source) {
+            Ok(passphrase) => passphrases.add(pattern, passphrase),
+            Err(e) => {
+                eprintln!("SSHPASS: {e}");
+                return Err(EXIT_RUNTIME_ERROR);
+            }
+        }
+    }
+    Ok(passphrases)
+}
+
+/// Resolves the passwords configured for the jump hosts of `command`.
+/// Hops sharing the destination's password source fall back on its
+/// password, and every source is read once, as env-vars are cleared on use.
+fn determine_hop_passwords(
+    command: &[String],
+    config: &Config,
+    destination: &PasswordSource,
+) -> Result<HopPasswords, i32> {
+    let mut resolved: Vec<(PasswordSource, Vec<u8>)> = Vec::new();
+    let mut hop_passwords = HopPasswords::default();
+    for host in destination::jump_hosts(command) {
+        let Some(source) = config.profile(Some(&host)).password else {
+            continue;
+        };
+        if source == *destination {
+            continue;
+        }
+        if let Some((_, password)) = resolved.iter().find(|(s, _)| *s == source) {
+            hop_passwords.add(host, password.clone());
+            continue;
+        }
+        match resolve_password(&source) {
+            Ok(password) => {
+                hop_passwords.add(host, password.clone());
+                resolved.push((source, password));
+            }
+            Err(e) => {
+                eprintln!("SSHPASS: {e}");
+                return Err(EXIT_RUNTIME_ERROR);
+            }
+        }
+    }
+    Ok(hop_passwords)
+}
+
+fn determine_password_change(cli: &Cli, current: &[u8]) -> Result<Option<PasswordChange>, i32> {
+    let mut sources: Vec<PasswordSource> = Vec::new();
+
+    if let Some(ref path) = cli.new_password_file {
+        sources.push(PasswordSource::File(path.clone(), FileFormat::default()));
+    }
+    if let Some(ref var) = cli.new_password_env {
+        sources.push(PasswordSource::Env(var.clone()));
+    }
+    #[cfg(unix)]
+    if let Some(fd) = cli.new_password_fd {
+        sources.push(PasswordSource::Fd(fd));
+    }
+
+    let conflicting = match cli.generate_password {
+        Some(_) => !sources.is_empty(),
+        None => sources.len() > 1,
+    };
+    if conflicting {
+        eprintln!("SSHPASS: conflicting new password source");
+        return Err(EXIT_CONFLICTING_ARGUMENTS);
+    }
+
+    if let Some(ref path) = cli.generate_password {
+        let new = generate::generate(&cli.policy.policy())
+            .map_err(|e| {
+                eprintln!("SSHPASS: {e}");
+                EXIT_RUNTIME_ERROR
+            })?
+            .into_bytes();
+        let pending = PendingPassword::stage(path, &new).map_err(|e| {
+            eprintln!("SSHPASS: failed to write \"{}\": {e}", path.display());
+            EXIT_RUNTIME_ERROR
+        })?;
+        return Ok(Some(
+            PasswordChange::new(current.to_vec(), new).with_pending(pending),
+        ));
+    }
+
+    match sources.pop() {
+        Some(source) => match resolve_password(&source) {
+            Ok(new) => Ok(Some(PasswordChange::new(current.to_vec(), new))),
+            Err(e) => {
+                eprintln!("SSHPASS: {e}");
+                Err(EXIT_RUNTIME_ERROR)
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+fn script_runner(script: Script, password: &[u8]) -> Result<ScriptRunner, PasswordError> {
+    let mut secrets = HashMap::new();
+    secrets.insert(PASSWORD_SECRET.to_string(), password.to_vec());
+    for (name, source) in script.secrets() {
+        secrets.insert(name.clone(), resolve_password(source)?);
+    }
+    Ok(ScriptRunner::new(script, secrets))
+}
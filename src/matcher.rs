@@ -36,6 +36,48 @@ impl Matcher {
     }
 }
 
+/// Keeps the most recent, possibly incomplete, line of output.
+pub struct LastLine {
+    line: Vec<u8>,
+}
+
+impl LastLine {
+    const MAX_LEN: usize = 1024;
+
+    pub fn new() -> Self {
+        Self { line: Vec::new() }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        match data.iter().rposition(|&b| b == b'\n') {
+            Some(pos) => {
+                self.line.clear();
+                self.line.extend_from_slice(&data[pos + 1..]);
+            }
+            None => self.line.extend_from_slice(data),
+        }
+        if self.line.len() > Self::MAX_LEN {
+            self.line.drain(..self.line.len() - Self::MAX_LEN);
+        }
+    }
+
+    /// Returns `data` prefixed with the current line, so that patterns
+    /// split across buffers can be matched in one piece.
+    pub fn prepend_to(&self, data: &[u8]) -> Vec<u8> {
+        let mut window = self.line.clone();
+        window.extend_from_slice(data);
+        window
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.line
+    }
+
+    pub fn clear(&mut self) {
+        self.line.clear();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -121,4 +163,29 @@ mod tests {
         let mut m = Matcher::new("");
         assert!(!m.feed(b"anything"));
     }
+
+    #[test]
+    fn last_line_spans_buffers() {
+        let mut l = LastLine::new();
+        l.feed(b"Last login: today\r\nuser@");
+        l.feed(b"host:~$ ");
+        assert_eq!(l.prepend_to(b""), b"user@host:~$ ");
+    }
+
+    #[test]
+    fn last_line_resets_on_newline() {
+        let mut l = LastLine::new();
+        l.feed(b"partial");
+        l.feed(b"\n");
+        assert_eq!(l.prepend_to(b""), b"");
+    }
+
+    #[test]
+    fn prepend_joins_partial_line() {
+        let mut l = LastLine::new();
+        l.feed(b"old\nEnter pass");
+        assert_eq!(l.prepend_to(b"phrase:"), b"Enter passphrase:");
+        l.clear();
+        assert_eq!(l.prepend_to(b"x"), b"x");
+    }
 }
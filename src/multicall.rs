@@ -0,0 +1,141 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Names sshpass answers to when invoked through a symlink or `--as`, and
+/// the program each of them stands for.
+const PERSONALITIES: &[(&str, &str)] = &[
+    ("ssh", "ssh"),
+    ("scp", "scp"),
+    ("sftp", "sftp"),
+    // For `rsync -e rsync-ssh`.
+    ("rsync-ssh", "ssh"),
+];
+
+/// Flag that makes sshpass act as a program without a symlink. It has to
+/// come first, as everything after its value belongs to that program.
+pub const AS_FLAG: &str = "--as";
+
+#[derive(Debug, thiserror::Error)]
+pub enum MulticallError {
+    #[error("--as needs one of: {}", names())]
+    UnknownPersonality,
+    #[error("{0}: not found in PATH")]
+    NotFound(String),
+}
+
+/// Decides from the raw command line whether sshpass stands in for another
+/// program. Returns that program's name and its arguments.
+pub fn invocation(
+    args: &[OsString],
+) -> Option<Result<(&'static str, &[OsString]), MulticallError>> {
+    let (argv0, rest) = args.split_first()?;
+    if let Some(program) = Path::new(argv0)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(personality)
+    {
+        return Some(Ok((program, rest)));
+    }
+
+    let first = rest.first()?.to_str()?;
+    let (name, rest) = if let Some(name) = first.strip_prefix("--as=") {
+        (name, &rest[1..])
+    } else if first == AS_FLAG {
+        match rest.get(1) {
+            Some(name) => (name.to_str().unwrap_or_default(), &rest[2..]),
+            None => return Some(Err(MulticallError::UnknownPersonality)),
+        }
+    } else {
+        return None;
+    };
+    Some(
+        personality(name)
+            .map(|program| (program, rest))
+            .ok_or(MulticallError::UnknownPersonality),
+    )
+}
+
+fn personality(name: &str) -> Option<&'static str> {
+    PERSONALITIES
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map(|&(_, program)| program)
+}
+
+fn names() -> String {
+    PERSONALITIES
+        .iter()
+        .map(|(alias, _)| *alias)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Finds the real `program` in PATH, skipping sshpass itself so that a
+/// symlink named `ssh` early in PATH does not run in circles.
+pub fn find_program(program: &str) -> Result<PathBuf, MulticallError> {
+    let own = std::env::current_exe().and_then(std::fs::canonicalize).ok();
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| {
+            candidate.is_file()
+                && std::fs::canonicalize(candidate).ok() != own
+                && is_executable(candidate)
+        })
+        .ok_or_else(|| MulticallError::NotFound(program.to_string()))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|m| m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(_path: &Path) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn invoked_through_symlink() {
+        let args = strings(&["/usr/local/bin/scp", "-P", "2222", "f", "host:"]);
+        let (program, rest) = invocation(&args).unwrap().unwrap();
+        assert_eq!(program, "scp");
+        assert_eq!(rest, &args[1..]);
+    }
+
+    #[test]
+    fn rsync_alias_is_ssh() {
+        let args = strings(&["rsync-ssh", "-l", "me", "host", "rsync", "--server"]);
+        assert_eq!(invocation(&args).unwrap().unwrap().0, "ssh");
+    }
+
+    #[test]
+    fn as_flag() {
+        let args = strings(&["sshpass", "--as", "ssh", "-o", "SendEnv=X", "git@host"]);
+        let (program, rest) = invocation(&args).unwrap().unwrap();
+        assert_eq!(program, "ssh");
+        assert_eq!(rest, &args[3..]);
+
+        let args = strings(&["sshpass", "--as=sftp", "host"]);
+        assert_eq!(invocation(&args).unwrap().unwrap().0, "sftp");
+
+        let args = strings(&["sshpass", "--as", "telnet", "host"]);
+        assert!(invocation(&args).unwrap().is_err());
+    }
+
+    #[test]
+    fn regular_invocation() {
+        assert!(invocation(&strings(&["sshpass", "-p", "x", "ssh", "host"])).is_none());
+        assert!(invocation(&strings(&["sshpass-rs", "ssh", "host"])).is_none());
+    }
+}
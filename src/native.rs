@@ -0,0 +1,715 @@
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use ssh_key::known_hosts::{HostPatterns, KnownHosts, Marker};
+use ssh_key::{Algorithm, PublicKey};
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::{self, HostKeyPolicy};
+use crate::connection::{self, Channel, Event};
+use crate::pty::{
+    RETURN_CONNECTION_FAILED, RETURN_HOST_KEY_CHANGED, RETURN_HOST_KEY_UNKNOWN,
+    RETURN_INCORRECT_PASSWORD, RawModeGuard, get_terminal_size,
+};
+use crate::transport::{HOST_KEY_ALGORITHMS, Transport};
+
+const DEFAULT_PORT: u16 = 22;
+const DEFAULT_TERM: &str = "xterm";
+/// Exit code of ssh when the connection breaks.
+const EXIT_SSH_ERROR: i32 = 255;
+/// How long the forwarding loop waits for the server before it looks at
+/// the local side again.
+const IDLE_POLL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, thiserror::Error)]
+pub enum NativeError {
+    #[error("--native only runs ssh, not \"{0}\"")]
+    NotSsh(String),
+    #[error("--native does not support the ssh option {0}")]
+    Unsupported(String),
+    #[error("invalid ssh option {option}: {message}")]
+    Invalid { option: String, message: String },
+    #[error("no destination in the ssh command")]
+    NoDestination,
+    #[error("no user name given and $USER is not set")]
+    NoUser,
+    #[error("ssh: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// How to treat a host key missing from known_hosts, after
+/// `StrictHostKeyChecking`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HostKeyCheck {
+    /// Refuse it, as sshpass refuses to answer ssh's question.
+    Strict,
+    /// Record it and go on.
+    AcceptNew,
+    /// Accept any key, even a changed one.
+    Off,
+}
+
+impl From<HostKeyPolicy> for HostKeyCheck {
+    fn from(policy: HostKeyPolicy) -> Self {
+        match policy {
+            HostKeyPolicy::Strict => Self::Strict,
+            HostKeyPolicy::AcceptNew => Self::AcceptNew,
+            HostKeyPolicy::No => Self::Off,
+        }
+    }
+}
+
+/// The part of an ssh command line the native backend understands.
+#[derive(Debug, PartialEq)]
+pub struct Target {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    /// Remote command; a login shell if empty.
+    pub command: Vec<String>,
+    /// `-t` or `-T`; otherwise a terminal is requested for a terminal.
+    pub tty: Option<bool>,
+    pub host_key_check: HostKeyCheck,
+    pub known_hosts: Option<PathBuf>,
+}
+
+/// Reads the destination and the supported options of an ssh command line.
+pub fn target(command: &[String]) -> Result<Target, NativeError> {
+    let (program, args) = command.split_first().ok_or(NativeError::NoDestination)?;
+    if Path::new(program).file_name().and_then(|n| n.to_str()) != Some("ssh") {
+        return Err(NativeError::NotSsh(program.clone()));
+    }
+
+    let mut user = None;
+    let mut port = None;
+    let mut tty = None;
+    let mut host_key_check = HostKeyCheck::Strict;
+    let mut known_hosts = None;
+    let mut iter = args.iter();
+    let destination = loop {
+        let arg = iter.next().ok_or(NativeError::NoDestination)?;
+        if arg == "--" {
+            break iter.next().ok_or(NativeError::NoDestination)?;
+        }
+        let Some(flags) = arg.strip_prefix('-').filter(|f| !f.is_empty()) else {
+            break arg;
+        };
+        for (i, flag) in flags.char_indices() {
+            match flag {
+                't' => tty = Some(true),
+                'T' => tty = Some(false),
+                'q' => {}
+                'p' | 'l' | 'o' => {
+                    let value = if i + 1 < flags.len() {
+                        flags[i + 1..].to_string()
+                    } else {
+                        iter.next()
+                            .ok_or_else(|| NativeError::Invalid {
+                                option: format!("-{flag}"),
+                                message: "missing value".into(),
+                            })?
+                            .clone()
+                    };
+                    match flag {
+                        'p' => port = Some(parse_port(&value)?),
+                        'l' => user = Some(value),
+                        _ => {
+                            let (key, value) = value
+                                .split_once(['=', ' '])
+                                .map(|(k, v)| (k.trim(), v.trim_start_matches(['=', ' '])))
+                                .ok_or_else(|| NativeError::Invalid {
+                                    option: value.clone(),
+                                    message: "expected key=value".into(),
+                                })?;
+                            match key.to_ascii_lowercase().as_str() {
+                                "user" => user = Some(value.to_string()),
+                                "port" => port = Some(parse_port(value)?),
+                                "stricthostkeychecking" => {
+                                    host_key_check = match value.to_ascii_lowercase().as_str() {
+                                        "yes" | "ask" => HostKeyCheck::Strict,
+                                        "accept-new" => HostKeyCheck::AcceptNew,
+                                        "no" | "off" => HostKeyCheck::Off,
+                                        _ => {
+                                            return Err(NativeError::Invalid {
+                                                option: format!("{key}={value}"),
+                                                message: "unknown value".into(),
+                                            });
+                                        }
+                                    }
+                                }
+                                "userknownhostsfile" => {
+                                    known_hosts = value.split_whitespace().next().map(PathBuf::from)
+                                }
+                                _ => return Err(NativeError::Unsupported(format!("-o {key}"))),
+                            }
+                        }
+                    }
+                    break;
+                }
+                other => return Err(NativeError::Unsupported(format!("-{other}"))),
+            }
+        }
+    };
+
+    let (dest_user, host, dest_port) = split_destination(destination)?;
+    let user = match user.or(dest_user) {
+        Some(user) => user,
+        None => std::env::var("USER")
+            .or_else(|_| std::env::var("LOGNAME"))
+            .map_err(|_| NativeError::NoUser)?,
+    };
+    Ok(Target {
+        user,
+        host,
+        port: port.or(dest_port).unwrap_or(DEFAULT_PORT),
+        command: iter.cloned().collect(),
+        tty,
+        host_key_check,
+        known_hosts,
+    })
+}
+
+fn parse_port(value: &str) -> Result<u16, NativeError> {
+    value.parse().map_err(|_| NativeError::Invalid {
+        option: value.to_string(),
+        message: "not a port number".into(),
+    })
+}
+
+/// Splits `[ssh://][user@]host[:port]`; the port only in the URI form.
+fn split_destination(
+    destination: &str,
+) -> Result<(Option<String>, String, Option<u16>), NativeError> {
+    let (rest, is_uri) = match destination.strip_prefix("ssh://") {
+        Some(rest) => (rest.trim_end_matches('/'), true),
+        None => (destination, false),
+    };
+    let (user, rest) = match rest.rsplit_once('@') {
+        Some((user, host)) => (Some(user.to_string()), host),
+        None => (None, rest),
+    };
+    let (host, port) = if let Some(bracketed) = rest.strip_prefix('[') {
+        let (host, after) = bracketed.split_once(']').unwrap_or((bracketed, ""));
+        (host, after.strip_prefix(':'))
+    } else if is_uri {
+        match rest.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (rest, None),
+        }
+    } else {
+        (rest, None)
+    };
+    if host.is_empty() {
+        return Err(NativeError::NoDestination);
+    }
+    Ok((user, host.to_string(), port.map(parse_port).transpose()?))
+}
+
+/// Connects and logs in with `password`. Gives the exit code for sshpass
+/// instead of a connection if the connection, host key or password failed.
+pub fn connect(target: &Target, password: &[u8]) -> Result<Result<Transport, i32>, NativeError> {
+    let tcp = match TcpStream::connect((target.host.as_str(), target.port)) {
+        Ok(tcp) => tcp,
+        Err(e) => {
+            eprintln!("SSHPASS: connecting to {}: {e}", target.host);
+            return Ok(Err(RETURN_CONNECTION_FAILED));
+        }
+    };
+    tcp.set_nodelay(true)?;
+
+    let known_hosts = known_hosts_path(target);
+    let recorded = recorded_keys(&known_hosts, &host_name(target))?;
+    let mut transport = match Transport::connect(tcp, host_key_algorithms(&recorded)) {
+        Ok(transport) => transport,
+        Err(e) => {
+            eprintln!("SSHPASS: handshake with {}: {e}", target.host);
+            return Ok(Err(RETURN_CONNECTION_FAILED));
+        }
+    };
+
+    if let Some(code) = check_host_key(&transport, target, &known_hosts, &recorded)? {
+        return Ok(Err(code));
+    }
+    if !connection::login(&mut transport, &target.user, password)? {
+        return Ok(Err(RETURN_INCORRECT_PASSWORD));
+    }
+    Ok(Ok(transport))
+}
+
+/// Connects, logs in with `password` and runs the command with the local
+/// stdin and stdout attached. Returns the exit code for sshpass.
+pub fn run(target: Target, password: &[u8]) -> Result<i32, NativeError> {
+    let transport = match connect(&target, password)? {
+        Ok(transport) => transport,
+        Err(code) => return Ok(code),
+    };
+
+    let mut channel = Channel::open_session(transport)?;
+    let stdin_is_tty = io::stdin().is_terminal();
+    let want_tty = target
+        .tty
+        .unwrap_or(stdin_is_tty && target.command.is_empty());
+    if want_tty {
+        let term = std::env::var("TERM").unwrap_or_else(|_| DEFAULT_TERM.to_string());
+        let (cols, rows) = get_terminal_size().map_or((80, 24), |s| (s.cols, s.rows));
+        channel.request_pty(&term, cols.into(), rows.into())?;
+    }
+    if target.command.is_empty() {
+        channel.shell()?;
+    } else {
+        channel.exec(&target.command.join(" "))?;
+    }
+
+    let _raw_guard = want_tty.then(RawModeGuard::enter_passthrough);
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        let mut buf = [0u8; 4096];
+        loop {
+            match stdin.read(&mut buf) {
+                Ok(0) | Err(_) => {
+                    let _ = tx.send(Vec::new());
+                    break;
+                }
+                Ok(n) => {
+                    if tx.send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(forward(
+        &mut channel,
+        &rx,
+        &mut io::stdout(),
+        &mut io::stderr(),
+        want_tty,
+    )?)
+}
+
+/// Passes `input` to the channel and its output to `stdout` and `stderr`
+/// until the server closes it; an empty chunk of input is the end of it.
+/// Returns the exit code for sshpass.
+fn forward(
+    channel: &mut Channel,
+    input: &Receiver<Vec<u8>>,
+    stdout: &mut impl Write,
+    stderr: &mut impl Write,
+    tty: bool,
+) -> io::Result<i32> {
+    let mut last_size = get_terminal_size();
+    let mut input_open = true;
+    let mut status = None;
+    let mut signal = None;
+    loop {
+        while input_open {
+            match input.try_recv() {
+                Ok(data) if data.is_empty() => {
+                    channel.send_eof()?;
+                    input_open = false;
+                }
+                Ok(data) => channel.write(&data)?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => input_open = false,
+            }
+        }
+        if tty {
+            let size = get_terminal_size();
+            if let Some(s) = size
+                && size != last_size
+            {
+                channel.window_change(s.cols.into(), s.rows.into())?;
+                last_size = size;
+            }
+        }
+        match channel.poll(Some(IDLE_POLL))? {
+            Some(Event::Data(data)) => {
+                stdout.write_all(&data)?;
+                stdout.flush()?;
+            }
+            Some(Event::Stderr(data)) => {
+                stderr.write_all(&data)?;
+                stderr.flush()?;
+            }
+            Some(Event::ExitStatus(code)) => status = Some(code as i32),
+            Some(Event::ExitSignal(name)) => signal = Some(name),
+            Some(Event::Closed) => break,
+            Some(Event::Eof) | None => {}
+        }
+    }
+    match signal {
+        Some(signal) => {
+            eprintln!("SSHPASS: remote command killed by SIG{signal}");
+            Ok(EXIT_SSH_ERROR)
+        }
+        None => Ok(status.unwrap_or(EXIT_SSH_ERROR)),
+    }
+}
+
+fn known_hosts_path(target: &Target) -> PathBuf {
+    match target.known_hosts.clone() {
+        Some(path) => path,
+        None => match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".ssh").join("known_hosts"),
+            None => PathBuf::from("/dev/null"),
+        },
+    }
+}
+
+/// The name of the host in known_hosts: `[host]:port` off port 22.
+fn host_name(target: &Target) -> String {
+    if target.port == DEFAULT_PORT {
+        target.host.clone()
+    } else {
+        format!("[{}]:{}", target.host, target.port)
+    }
+}
+
+/// The keys known_hosts at `path` has for `name`, each with whether it is
+/// revoked. Lines that do not parse are skipped, as ssh does.
+fn recorded_keys(path: &Path, name: &str) -> io::Result<Vec<(PublicKey, bool)>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(KnownHosts::new(&contents)
+        .filter_map(Result::ok)
+        .filter(|entry| entry.marker() != Some(&Marker::CertAuthority))
+        .filter(|entry| match entry.host_patterns() {
+            HostPatterns::Patterns(patterns) => config::matches_patterns(&patterns.join(","), name),
+            HostPatterns::HashedName { salt, hash } => {
+                let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(salt).unwrap();
+                mac.update(name.as_bytes());
+                mac.verify_slice(hash).is_ok()
+            }
+        })
+        .map(|entry| {
+            let revoked = entry.marker() == Some(&Marker::Revoked);
+            (entry.public_key().clone(), revoked)
+        })
+        .collect())
+}
+
+/// The host key algorithms to offer, those of the recorded keys first so
+/// that the server does not present a key of another type.
+fn host_key_algorithms(recorded: &[(PublicKey, bool)]) -> Vec<&'static str> {
+    let (known, other): (Vec<_>, Vec<_>) = HOST_KEY_ALGORITHMS.iter().partition(|name| {
+        let algorithm = Algorithm::new(name).expect("valid algorithm name");
+        let rsa = matches!(algorithm, Algorithm::Rsa { .. });
+        recorded.iter().any(|(key, revoked)| {
+            !revoked && (key.algorithm() == algorithm || rsa && key.algorithm().is_rsa())
+        })
+    });
+    known.into_iter().chain(other).collect()
+}
+
+/// Compares the server's host key with the `recorded` ones, returning an
+/// exit code if the connection must not go on.
+fn check_host_key(
+    transport: &Transport,
+    target: &Target,
+    known_hosts: &Path,
+    recorded: &[(PublicKey, bool)],
+) -> Result<Option<i32>, NativeError> {
+    let key = PublicKey::from_bytes(transport.host_key()).map_err(io::Error::other)?;
+    let same = |k: &PublicKey| k.key_data() == key.key_data();
+    if recorded.iter().any(|(k, revoked)| *revoked && same(k)) {
+        eprintln!("SSHPASS: host key of {} is revoked", target.host);
+        return Ok(Some(RETURN_HOST_KEY_CHANGED));
+    }
+    if recorded.iter().any(|(k, _)| same(k)) || target.host_key_check == HostKeyCheck::Off {
+        return Ok(None);
+    }
+    if recorded
+        .iter()
+        .any(|(k, revoked)| !revoked && k.algorithm() == key.algorithm())
+    {
+        eprintln!("SSHPASS: host key of {} has changed", target.host);
+        return Ok(Some(RETURN_HOST_KEY_CHANGED));
+    }
+    if target.host_key_check == HostKeyCheck::AcceptNew {
+        let line = format!(
+            "{} {}\n",
+            host_name(target),
+            key.to_openssh().map_err(io::Error::other)?
+        );
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(known_hosts)
+            .and_then(|mut f| f.write_all(line.as_bytes()))?;
+        return Ok(None);
+    }
+    eprintln!("SSHPASS: host key of {} is not known", target.host);
+    Ok(Some(RETURN_HOST_KEY_UNKNOWN))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use crate::sshd::{Config, TestServer};
+    #[cfg(unix)]
+    use crate::transport::{CIPHERS, MACS};
+
+    fn cmd(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn plain_destination() {
+        let t = target(&cmd(&["ssh", "-p", "2222", "me@host", "echo", "hi"])).unwrap();
+        assert_eq!(
+            (t.user.as_str(), t.host.as_str(), t.port),
+            ("me", "host", 2222)
+        );
+        assert_eq!(t.command, ["echo", "hi"]);
+        assert_eq!(t.host_key_check, HostKeyCheck::Strict);
+    }
+
+    #[test]
+    fn options_and_uri() {
+        let t = target(&cmd(&[
+            "/usr/bin/ssh",
+            "-tq",
+            "-oStrictHostKeyChecking=no",
+            "-o",
+            "UserKnownHostsFile /dev/null",
+            "ssh://admin@[fd00::1]:2200",
+        ]))
+        .unwrap();
+        assert_eq!(
+            (t.user.as_str(), t.host.as_str(), t.port),
+            ("admin", "fd00::1", 2200)
+        );
+        assert_eq!(t.tty, Some(true));
+        assert_eq!(t.host_key_check, HostKeyCheck::Off);
+        assert_eq!(t.known_hosts.as_deref(), Some(Path::new("/dev/null")));
+        assert!(t.command.is_empty());
+    }
+
+    #[test]
+    fn unsupported_options_are_refused() {
+        assert!(matches!(
+            target(&cmd(&["ssh", "-L", "80:x:80", "host"])),
+            Err(NativeError::Unsupported(_))
+        ));
+        assert!(matches!(
+            target(&cmd(&["ssh", "-o", "ProxyJump=bastion", "host"])),
+            Err(NativeError::Unsupported(_))
+        ));
+        assert!(matches!(
+            target(&cmd(&["scp", "f", "host:"])),
+            Err(NativeError::NotSsh(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    fn local(server: &TestServer, name: &str, known: &str, check: HostKeyCheck) -> Target {
+        let known_hosts = std::env::temp_dir().join(name);
+        fs::write(&known_hosts, known).unwrap();
+        Target {
+            user: "me".into(),
+            host: "127.0.0.1".into(),
+            port: server.port,
+            command: Vec::new(),
+            tty: Some(false),
+            host_key_check: check,
+            known_hosts: Some(known_hosts),
+        }
+    }
+
+    /// Runs `command` with `input`, giving the exit code, stdout and
+    /// stderr, or the exit code of a failed login.
+    #[cfg(unix)]
+    fn exec(
+        target: &Target,
+        password: &[u8],
+        command: &str,
+        input: &[u8],
+    ) -> Result<(i32, String, String), i32> {
+        let transport = connect(target, password).unwrap()?;
+        let mut channel = Channel::open_session(transport).unwrap();
+        channel.exec(command).unwrap();
+        let (tx, rx) = mpsc::channel();
+        tx.send(input.to_vec()).unwrap();
+        tx.send(Vec::new()).unwrap();
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        let code = forward(&mut channel, &rx, &mut stdout, &mut stderr, false).unwrap();
+        Ok((
+            code,
+            String::from_utf8(stdout).unwrap(),
+            String::from_utf8(stderr).unwrap(),
+        ))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runs_commands_with_every_cipher_and_mac() {
+        for &cipher in CIPHERS {
+            for mac in MACS {
+                let mut config = Config::new(b"secret");
+                config.ciphers =
+                    std::slice::from_ref(CIPHERS.iter().find(|&&c| c == cipher).unwrap());
+                config.macs = std::slice::from_ref(MACS.iter().find(|&m| m == mac).unwrap());
+                let server = TestServer::start(config);
+                let target = local(
+                    &server,
+                    "sshpass_test_native_ciphers",
+                    &server.known_hosts_line(),
+                    HostKeyCheck::Strict,
+                );
+                let result = exec(
+                    &target,
+                    b"secret",
+                    "printf out; cat; printf err >&2; exit 3",
+                    b" in",
+                );
+                assert_eq!(
+                    result,
+                    Ok((3, "out in".into(), "err".into())),
+                    "{cipher} with {mac}"
+                );
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wrong_password_is_refused() {
+        for keyboard_interactive in [false, true] {
+            let mut config = Config::new(b"secret");
+            config.keyboard_interactive = keyboard_interactive;
+            let server = TestServer::start(config);
+            let target = local(
+                &server,
+                "sshpass_test_native_password",
+                &server.known_hosts_line(),
+                HostKeyCheck::Strict,
+            );
+            assert_eq!(
+                exec(&target, b"wrong", "true", b""),
+                Err(RETURN_INCORRECT_PASSWORD)
+            );
+            assert_eq!(
+                exec(&target, b"secret", "true", b""),
+                Ok((0, String::new(), String::new()))
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn password_bytes_are_sent_as_they_are() {
+        let server = TestServer::start(Config::new(b"p\xe4ss\xff"));
+        let target = local(&server, "sshpass_test_native_bytes", "", HostKeyCheck::Off);
+        assert_eq!(
+            exec(&target, b"p\xe4ss\xff", "exit 4", b"").map(|r| r.0),
+            Ok(4)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn killed_command_is_exit_255() {
+        let server = TestServer::start(Config::new(b"pw"));
+        let target = local(&server, "sshpass_test_native_signal", "", HostKeyCheck::Off);
+        assert_eq!(
+            exec(&target, b"pw", "kill -TERM $$", b"").map(|r| r.0),
+            Ok(EXIT_SSH_ERROR)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unknown_host_key() {
+        let server = TestServer::start(Config::new(b"pw"));
+        let target = local(
+            &server,
+            "sshpass_test_native_unknown",
+            "",
+            HostKeyCheck::Strict,
+        );
+        assert_eq!(
+            exec(&target, b"pw", "true", b""),
+            Err(RETURN_HOST_KEY_UNKNOWN)
+        );
+
+        let target = local(
+            &server,
+            "sshpass_test_native_accept",
+            "",
+            HostKeyCheck::AcceptNew,
+        );
+        assert!(exec(&target, b"pw", "true", b"").is_ok());
+        let known_hosts = target.known_hosts.clone().unwrap();
+        assert_eq!(
+            fs::read_to_string(&known_hosts).unwrap(),
+            server.known_hosts_line()
+        );
+        let target = Target {
+            host_key_check: HostKeyCheck::Strict,
+            ..target
+        };
+        assert!(exec(&target, b"pw", "true", b"").is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn changed_host_key() {
+        let server = TestServer::start(Config::new(b"pw"));
+        let other = TestServer::start(Config::new(b"pw"));
+        let known = other
+            .known_hosts_line()
+            .replace(&other.port.to_string(), &server.port.to_string());
+        for check in [HostKeyCheck::Strict, HostKeyCheck::AcceptNew] {
+            let target = local(&server, "sshpass_test_native_changed", &known, check);
+            assert_eq!(
+                exec(&target, b"pw", "true", b""),
+                Err(RETURN_HOST_KEY_CHANGED)
+            );
+        }
+        let target = local(
+            &server,
+            "sshpass_test_native_changed",
+            &known,
+            HostKeyCheck::Off,
+        );
+        assert!(exec(&target, b"pw", "true", b"").is_ok());
+
+        let revoked = format!("@revoked {}", server.known_hosts_line());
+        let target = local(
+            &server,
+            "sshpass_test_native_revoked",
+            &revoked,
+            HostKeyCheck::Off,
+        );
+        assert_eq!(
+            exec(&target, b"pw", "true", b""),
+            Err(RETURN_HOST_KEY_CHANGED)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn known_hosts_patterns() {
+        let server = TestServer::start(Config::new(b"pw"));
+        let key = server.host_key.to_openssh().unwrap();
+        let known = format!("other {key}\n[127.0.0.*]:{},!10.0.0.1 {key}\n", server.port);
+        let target = local(
+            &server,
+            "sshpass_test_native_patterns",
+            &known,
+            HostKeyCheck::Strict,
+        );
+        assert!(exec(&target, b"pw", "true", b"").is_ok());
+    }
+}
@@ -0,0 +1,69 @@
+use regex::bytes::Regex;
+use std::sync::LazyLock;
+
+use crate::config::matches_patterns;
+
+static PROMPT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"Enter passphrase for key '([^'\r\n]*)':").expect("valid passphrase regex")
+});
+
+/// Returns the key path if `window` contains an ssh passphrase prompt.
+pub fn prompt_key(window: &[u8]) -> Option<String> {
+    let captures = PROMPT.captures(window)?;
+    Some(String::from_utf8_lossy(&captures[1]).into_owned())
+}
+
+/// Private key passphrases, either for any key or for keys whose path
+/// matches a glob pattern.
+#[derive(Default, Clone)]
+pub struct Passphrases {
+    entries: Vec<(Option<String>, Vec<u8>)>,
+}
+
+impl Passphrases {
+    /// Adds a passphrase. Earlier entries take precedence.
+    pub fn add(&mut self, pattern: Option<String>, passphrase: Vec<u8>) {
+        self.entries.push((pattern, passphrase));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn for_key(&self, key: &str) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|(pattern, _)| pattern.as_deref().is_none_or(|p| matches_patterns(p, key)))
+            .map(|(_, passphrase)| passphrase.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_key_path() {
+        assert_eq!(
+            prompt_key(b"\r\nEnter passphrase for key '/home/me/.ssh/id_ed25519': ").as_deref(),
+            Some("/home/me/.ssh/id_ed25519")
+        );
+        assert_eq!(prompt_key(b"user@host's password: "), None);
+    }
+
+    #[test]
+    fn keyed_entries_before_fallback() {
+        let mut p = Passphrases::default();
+        p.add(Some("*/id_prod".into()), b"prod".to_vec());
+        p.add(None, b"any".to_vec());
+        assert_eq!(p.for_key("/home/me/.ssh/id_prod"), Some(&b"prod"[..]));
+        assert_eq!(p.for_key("/home/me/.ssh/id_rsa"), Some(&b"any"[..]));
+    }
+
+    #[test]
+    fn no_match_without_fallback() {
+        let mut p = Passphrases::default();
+        p.add(Some("*/id_prod".into()), b"prod".to_vec());
+        assert_eq!(p.for_key("/home/me/.ssh/id_rsa"), None);
+    }
+}
@@ -0,0 +1,343 @@
+use regex::bytes::Regex;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+// Besides English, the German, French and Spanish wording of Linux-PAM.
+static CURRENT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)(?:\(current\) (?:UNIX )?password|current password|old password|\(aktuelles\) UNIX-Passwort|aktuelles Passwort|mot de passe (?:UNIX )?actuel|contraseña (?:UNIX )?actual)\s*:\s*$",
+    )
+    .expect("valid regex")
+});
+static RETYPE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)(?:(?:retype|re-enter|repeat|confirm) new (?:UNIX )?password|geben Sie das neue (?:UNIX-)?Passwort erneut ein|retapez le nouveau mot de passe(?: UNIX)?|vuelva a escribir la nueva contraseña(?: UNIX)?)\s*:\s*$",
+    )
+    .expect("valid regex")
+});
+static NEW: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)(?:new (?:UNIX )?password|neues (?:UNIX-)?Passwort|geben Sie ein neues (?:UNIX-)?Passwort ein|nouveau mot de passe(?: UNIX)?|nueva contraseña(?: UNIX)?)\s*:\s*$",
+    )
+    .expect("valid regex")
+});
+static CHANGED: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)password updated successfully|all authentication tokens updated successfully|password changed",
+    )
+    .expect("valid regex")
+});
+static REJECTED: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)BAD PASSWORD|password unchanged|authentication token manipulation error|exhausted maximum number of retries|passwords do not match|sorry, passwords do not match|password has been already used",
+    )
+    .expect("valid regex")
+});
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Prompt {
+    Current,
+    New,
+    Retype,
+}
+
+/// Recognises a password change prompt at the end of the last line of
+/// `window`.
+pub fn detect_prompt(window: &[u8]) -> Option<Prompt> {
+    let line = match window.iter().rposition(|&b| b == b'\n') {
+        Some(pos) => &window[pos + 1..],
+        None => window,
+    };
+    if CURRENT.is_match(line) {
+        Some(Prompt::Current)
+    } else if RETYPE.is_match(line) {
+        Some(Prompt::Retype)
+    } else if NEW.is_match(line) {
+        Some(Prompt::New)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ChangeEvent {
+    /// Type this (including the trailing newline) into the prompt.
+    Answer(Vec<u8>),
+    /// The new password was accepted.
+    Changed,
+    /// The new password was refused, with the message that said so.
+    Rejected(String),
+}
+
+/// Drives the current / new / retype password dialog of `passwd` and PAM.
+pub struct PasswordChange {
+    current: Vec<u8>,
+    new: Vec<u8>,
+    new_sent: bool,
+    /// `Some(true)` once changed, `Some(false)` once rejected.
+    verdict: Option<bool>,
+    pending: Option<PendingPassword>,
+    in_session: bool,
+}
+
+impl PasswordChange {
+    pub fn new(current: Vec<u8>, new: Vec<u8>) -> Self {
+        Self {
+            current,
+            new,
+            new_sent: false,
+            verdict: None,
+            pending: None,
+            in_session: false,
+        }
+    }
+
+    /// Also answers the dialog after login, for a `passwd` run as the
+    /// session command.
+    pub fn during_session(mut self) -> Self {
+        self.in_session = true;
+        self
+    }
+
+    pub fn in_session(&self) -> bool {
+        self.in_session
+    }
+
+    /// Attaches a staged copy of the new password that is committed or
+    /// discarded by [`PasswordChange::settle`].
+    pub fn with_pending(mut self, pending: PendingPassword) -> Self {
+        self.pending = Some(pending);
+        self
+    }
+
+    /// Inspects output (prefixed with the current line) and decides what to
+    /// do. The caller must forget the window after an `Answer`.
+    pub fn feed(&mut self, window: &[u8]) -> Option<ChangeEvent> {
+        if self.verdict.is_some() {
+            return None;
+        }
+        if let Some(found) = REJECTED.find(window) {
+            self.verdict = Some(false);
+            return Some(ChangeEvent::Rejected(
+                String::from_utf8_lossy(found.as_bytes()).into_owned(),
+            ));
+        }
+        if CHANGED.is_match(window) {
+            self.verdict = Some(true);
+            return Some(ChangeEvent::Changed);
+        }
+        let answer = match detect_prompt(window)? {
+            Prompt::Current => &self.current,
+            Prompt::New if self.new_sent => {
+                self.verdict = Some(false);
+                return Some(ChangeEvent::Rejected("new password asked again".into()));
+            }
+            Prompt::New => {
+                self.new_sent = true;
+                &self.new
+            }
+            Prompt::Retype => &self.new,
+        };
+        let mut payload = answer.clone();
+        payload.push(b'\n');
+        Some(ChangeEvent::Answer(payload))
+    }
+
+    /// Commits the staged password after a successful change, discards it
+    /// after a rejection and keeps it if the outcome is unknown. Returns a
+    /// message for the user if something needs their attention.
+    pub fn settle(&mut self) -> Option<String> {
+        let pending = self.pending.take()?;
+        let staged = pending.staged.display().to_string();
+        match self.verdict {
+            Some(true) => pending.commit().err().map(|e| {
+                format!("failed to save the new password: {e}; it is kept in \"{staged}\"")
+            }),
+            None if self.new_sent => Some(format!(
+                "the new password may have been set; it is kept in \"{staged}\""
+            )),
+            _ => {
+                pending.discard();
+                None
+            }
+        }
+    }
+}
+
+/// A new password written next to its final location until the server
+/// has accepted it, so that it cannot get lost halfway through a change.
+pub struct PendingPassword {
+    path: PathBuf,
+    staged: PathBuf,
+}
+
+impl PendingPassword {
+    /// Writes `password` to `<path>.unconfirmed`.
+    pub fn stage(path: &Path, password: &[u8]) -> io::Result<Self> {
+        let mut staged = path.as_os_str().to_owned();
+        staged.push(".unconfirmed");
+        let staged = PathBuf::from(staged);
+        write_private(&staged, password)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            staged,
+        })
+    }
+
+    pub fn staged(&self) -> &Path {
+        &self.staged
+    }
+
+    /// Moves the staged password to its final location.
+    pub fn commit(self) -> io::Result<()> {
+        fs::rename(&self.staged, &self.path)
+    }
+
+    pub fn discard(self) {
+        let _ = fs::remove_file(&self.staged);
+    }
+}
+
+/// Writes a password to a new file only the owner can read.
+fn write_private(path: &Path, password: &[u8]) -> io::Result<()> {
+    let _ = fs::remove_file(path);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    io::Write::write_all(&mut file, &[password, b"\n"].concat())?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(s: &str) -> Option<ChangeEvent> {
+        Some(ChangeEvent::Answer(format!("{s}\n").into_bytes()))
+    }
+
+    #[test]
+    fn detects_prompts() {
+        assert_eq!(
+            detect_prompt(b"(current) UNIX password: "),
+            Some(Prompt::Current)
+        );
+        assert_eq!(detect_prompt(b"Current password: "), Some(Prompt::Current));
+        assert_eq!(detect_prompt(b"New password: "), Some(Prompt::New));
+        assert_eq!(
+            detect_prompt(b"Retype new password: "),
+            Some(Prompt::Retype)
+        );
+        assert_eq!(detect_prompt(b"user@host's password: "), None);
+        assert_eq!(
+            detect_prompt(b"Retype new password: \r\npasswd: all done\r\n"),
+            None
+        );
+        assert_eq!(detect_prompt(b"New password: was set yesterday"), None);
+    }
+
+    #[test]
+    fn detects_localized_prompts() {
+        let prompts = [
+            ("(aktuelles) UNIX-Passwort: ", Prompt::Current),
+            ("Neues Passwort: ", Prompt::New),
+            ("Geben Sie das neue Passwort erneut ein: ", Prompt::Retype),
+            ("Mot de passe actuel : ", Prompt::Current),
+            ("Nouveau mot de passe : ", Prompt::New),
+            ("Retapez le nouveau mot de passe : ", Prompt::Retype),
+            ("Contraseña actual: ", Prompt::Current),
+            ("Nueva contraseña: ", Prompt::New),
+            ("Vuelva a escribir la nueva contraseña: ", Prompt::Retype),
+        ];
+        for (prompt, kind) in prompts {
+            assert_eq!(detect_prompt(prompt.as_bytes()), Some(kind), "{prompt}");
+        }
+        assert_eq!(detect_prompt(b"Passwort: "), None);
+    }
+
+    #[test]
+    fn full_dialog() {
+        let mut c = PasswordChange::new(b"old".to_vec(), b"new".to_vec());
+        assert_eq!(
+            c.feed(b"You are required to change your password immediately\r\nCurrent password: "),
+            answer("old")
+        );
+        assert_eq!(c.feed(b"\r\nNew password: "), answer("new"));
+        assert_eq!(c.feed(b"\r\nRetype new password: "), answer("new"));
+        assert_eq!(
+            c.feed(b"\r\npasswd: password updated successfully\r\n"),
+            Some(ChangeEvent::Changed)
+        );
+        assert_eq!(c.feed(b"Connection closed.\r\n"), None);
+    }
+
+    #[test]
+    fn policy_rejection() {
+        let mut c = PasswordChange::new(b"old".to_vec(), b"short".to_vec());
+        c.feed(b"New password: ");
+        assert!(matches!(
+            c.feed(b"\r\nBAD PASSWORD: The password is shorter than 8 characters\r\n"),
+            Some(ChangeEvent::Rejected(_))
+        ));
+    }
+
+    #[test]
+    fn second_new_prompt_is_rejection() {
+        let mut c = PasswordChange::new(b"old".to_vec(), b"weak".to_vec());
+        c.feed(b"New password: ");
+        assert!(matches!(
+            c.feed(b"\r\nNew password: "),
+            Some(ChangeEvent::Rejected(_))
+        ));
+    }
+
+    #[test]
+    fn pending_password_committed_on_success() {
+        let path = std::env::temp_dir().join("sshpass_test_pending_ok");
+        let pending = PendingPassword::stage(&path, b"s3cret").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&pending.staged).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let mut c = PasswordChange::new(b"old".to_vec(), b"s3cret".to_vec()).with_pending(pending);
+        c.feed(b"New password: ");
+        c.feed(b"\r\npassword updated successfully\r\n");
+        assert_eq!(c.settle(), None);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "s3cret\n");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn pending_password_kept_when_outcome_unknown() {
+        let path = std::env::temp_dir().join("sshpass_test_pending_unknown");
+        let pending = PendingPassword::stage(&path, b"s3cret").unwrap();
+        let staged = pending.staged.clone();
+        let mut c = PasswordChange::new(b"old".to_vec(), b"s3cret".to_vec()).with_pending(pending);
+        c.feed(b"New password: ");
+        assert!(c.settle().is_some());
+        assert!(staged.exists());
+        assert!(!path.exists());
+        fs::remove_file(staged).unwrap();
+    }
+
+    #[test]
+    fn pending_password_discarded_on_rejection() {
+        let path = std::env::temp_dir().join("sshpass_test_pending_rejected");
+        let pending = PendingPassword::stage(&path, b"weak").unwrap();
+        let staged = pending.staged.clone();
+        let mut c = PasswordChange::new(b"old".to_vec(), b"weak".to_vec()).with_pending(pending);
+        c.feed(b"New password: ");
+        c.feed(b"\r\nBAD PASSWORD: it is too simplistic\r\n");
+        assert_eq!(c.settle(), None);
+        assert!(!staged.exists());
+    }
+}
@@ -1,6 +1,10 @@
+use std::ffi::OsString;
 use std::fs;
 use std::io::{self, BufRead};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+/// Set by systemd to the directory holding a service's credentials.
+pub const CREDENTIALS_DIR_VAR: &str = "CREDENTIALS_DIRECTORY";
 
 #[derive(Debug, thiserror::Error)]
 pub enum PasswordError {
@@ -13,61 +17,272 @@ pub enum PasswordError {
     #[cfg(unix)]
     #[error("failed to read password from fd {fd}: {source}")]
     FdRead { fd: i32, source: io::Error },
+    #[error(
+        "credential \"{name}\" requested but ${CREDENTIALS_DIR_VAR} is not set; \
+         load it with LoadCredential= or SetCredentialEncrypted="
+    )]
+    CredentialsDirNotSet { name: String },
+    #[error("credential \"{name}\" not found in \"{dir}\"")]
+    CredentialMissing { name: String, dir: PathBuf },
+    #[error("invalid credential name \"{name}\"")]
+    CredentialName { name: String },
+    #[error("failed to read credential \"{name}\": {source}")]
+    CredentialRead { name: String, source: io::Error },
+    #[cfg(unix)]
+    #[error("password file \"{path}\" is owned by uid {uid}, not by you")]
+    FileOwner { path: PathBuf, uid: u32 },
+    #[cfg(unix)]
+    #[error("password file \"{path}\" is accessible by others (mode {mode:o}); use chmod 600")]
+    FilePermissions { path: PathBuf, mode: u32 },
+    #[cfg(unix)]
+    #[error("password file \"{path}\" is a symlink into world-writable \"{dir}\"")]
+    FileSymlink { path: PathBuf, dir: PathBuf },
+    #[error(
+        "-p makes the password visible to other users{}; use -f, -e, -d or --credential",
+        if *.refused { " and is refused by policy" } else { "" }
+    )]
+    VisibleArgument { refused: bool },
+    #[error("password file \"{path}\" has no line {line}")]
+    LineMissing { path: PathBuf, line: usize },
+    #[error("password file \"{path}\" has no entry \"{key}\"")]
+    KeyMissing { path: PathBuf, key: String },
+}
+
+/// How the password is taken from a password file. Line endings may be
+/// `\n` or `\r\n`.
+#[derive(Debug, Default, Clone, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum FileFormat {
+    /// The first line.
+    #[default]
+    FirstLine,
+    /// The whole file, verbatim.
+    Whole,
+    /// The whole file without one trailing line ending.
+    StripNewline,
+    /// Line N, counting from 1.
+    Line(usize),
+    /// The value of `KEY=value` in a dotenv file.
+    Dotenv(String),
+}
+
+impl std::str::FromStr for FileFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "first-line" => Ok(Self::FirstLine),
+            None if s == "whole" => Ok(Self::Whole),
+            None if s == "strip-newline" => Ok(Self::StripNewline),
+            Some(("line", n)) => match n.parse() {
+                Ok(n) if n > 0 => Ok(Self::Line(n)),
+                _ => Err(format!("invalid line number \"{n}\"")),
+            },
+            Some(("dotenv", key)) if !key.is_empty() => Ok(Self::Dotenv(key.to_string())),
+            _ => Err(format!(
+                "unknown file format \"{s}\"; expected first-line, whole, strip-newline, \
+                 line:N or dotenv:KEY"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for FileFormat {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
 }
 
-#[derive(Debug)]
+impl FileFormat {
+    /// Extracts the password from the contents of the file at `path`.
+    fn extract(&self, path: &Path, content: &[u8]) -> Result<Vec<u8>, PasswordError> {
+        match self {
+            Self::FirstLine => Ok(first_line(content)),
+            Self::Whole => Ok(content.to_vec()),
+            Self::StripNewline => {
+                let content = content.strip_suffix(b"\n").unwrap_or(content);
+                Ok(content.strip_suffix(b"\r").unwrap_or(content).to_vec())
+            }
+            Self::Line(n) => lines(content)
+                .nth(n - 1)
+                .map(<[u8]>::to_vec)
+                .ok_or_else(|| PasswordError::LineMissing {
+                    path: path.to_path_buf(),
+                    line: *n,
+                }),
+            Self::Dotenv(key) => lines(content)
+                .find_map(|line| dotenv_value(line, key))
+                .ok_or_else(|| PasswordError::KeyMissing {
+                    path: path.to_path_buf(),
+                    key: key.clone(),
+                }),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub enum PasswordSource {
     Stdin,
-    File(PathBuf),
+    File(PathBuf, FileFormat),
     #[cfg(unix)]
     Fd(i32),
-    Direct(String),
+    Direct(OsString),
     Env(String),
+    /// A systemd credential, by name.
+    Credential(String),
 }
 
-pub fn resolve_password(source: &PasswordSource) -> Result<String, PasswordError> {
+/// Reads the password as raw bytes, so that passwords in legacy encodings
+/// reach the device unchanged.
+pub fn resolve_password(source: &PasswordSource) -> Result<Vec<u8>, PasswordError> {
     match source {
-        PasswordSource::Direct(pw) => Ok(pw.clone()),
+        PasswordSource::Direct(pw) => Ok(pw.clone().into_encoded_bytes()),
         PasswordSource::Env(var) => {
-            let pw =
-                std::env::var(var).map_err(|_| PasswordError::EnvNotSet { var: var.clone() })?;
+            let pw = std::env::var_os(var)
+                .ok_or_else(|| PasswordError::EnvNotSet { var: var.clone() })?;
             // SAFETY: We are single-threaded at this point
             unsafe { std::env::remove_var(var) };
-            Ok(pw)
+            Ok(pw.into_encoded_bytes())
         }
-        PasswordSource::File(path) => {
-            let content = fs::read_to_string(path).map_err(|e| PasswordError::FileOpen {
+        PasswordSource::File(path, format) => {
+            check_file(path)?;
+            let content = fs::read(path).map_err(|e| PasswordError::FileOpen {
                 path: path.clone(),
                 source: e,
             })?;
-            Ok(first_line(&content))
+            format.extract(path, &content)
         }
         PasswordSource::Stdin => {
-            let mut line = String::new();
+            let mut line = Vec::new();
             io::stdin()
                 .lock()
-                .read_line(&mut line)
+                .read_until(b'\n', &mut line)
                 .map_err(PasswordError::StdinRead)?;
             Ok(first_line(&line))
         }
         #[cfg(unix)]
         PasswordSource::Fd(fd) => read_from_fd(*fd),
+        PasswordSource::Credential(name) => read_credential(name),
+    }
+}
+
+/// Refuses password files that other users could read or swap out.
+#[cfg(unix)]
+fn check_file(path: &Path) -> Result<(), PasswordError> {
+    use std::os::unix::fs::MetadataExt;
+
+    let open_error = |source| PasswordError::FileOpen {
+        path: path.to_path_buf(),
+        source,
+    };
+    if fs::symlink_metadata(path).map_err(open_error)?.is_symlink() {
+        let target = fs::canonicalize(path).map_err(open_error)?;
+        if let Some(dir) = target.parent()
+            && fs::metadata(dir).is_ok_and(|m| m.mode() & 0o002 != 0)
+        {
+            return Err(PasswordError::FileSymlink {
+                path: path.to_path_buf(),
+                dir: dir.to_path_buf(),
+            });
+        }
+    }
+
+    let meta = fs::metadata(path).map_err(open_error)?;
+    let euid = unsafe { libc::geteuid() };
+    if meta.uid() != euid && meta.uid() != 0 {
+        return Err(PasswordError::FileOwner {
+            path: path.to_path_buf(),
+            uid: meta.uid(),
+        });
+    }
+    if meta.mode() & 0o077 != 0 {
+        return Err(PasswordError::FilePermissions {
+            path: path.to_path_buf(),
+            mode: meta.mode() & 0o777,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_file(_path: &Path) -> Result<(), PasswordError> {
+    Ok(())
+}
+
+fn read_credential(name: &str) -> Result<Vec<u8>, PasswordError> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        return Err(PasswordError::CredentialName { name: name.into() });
     }
+    let dir = std::env::var_os(CREDENTIALS_DIR_VAR)
+        .map(PathBuf::from)
+        .ok_or_else(|| PasswordError::CredentialsDirNotSet { name: name.into() })?;
+    match fs::read(dir.join(name)) {
+        Ok(content) => Ok(first_line(&content)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(PasswordError::CredentialMissing {
+            name: name.into(),
+            dir,
+        }),
+        Err(e) => Err(PasswordError::CredentialRead {
+            name: name.into(),
+            source: e,
+        }),
+    }
+}
+
+/// The bytes up to the first line ending, `\n` or `\r\n`.
+fn first_line(bytes: &[u8]) -> Vec<u8> {
+    lines(bytes).next().unwrap_or_default().to_vec()
+}
+
+fn lines(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let bytes = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+    bytes
+        .split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
 }
 
-fn first_line(s: &str) -> String {
-    s.lines().next().unwrap_or("").to_string()
+/// The value of `line` if it assigns `key`, as in `KEY=value`,
+/// `export KEY="value"` or `KEY='value'`.
+fn dotenv_value(line: &[u8], key: &str) -> Option<Vec<u8>> {
+    let line = line.trim_ascii();
+    let line = line.strip_prefix(b"export ").unwrap_or(line);
+    let eq = line.iter().position(|&b| b == b'=')?;
+    if line[..eq].trim_ascii() != key.as_bytes() {
+        return None;
+    }
+    let value = line[eq + 1..].trim_ascii();
+    match value {
+        [b'\'', inner @ .., b'\''] => Some(inner.to_vec()),
+        [b'"', inner @ .., b'"'] => {
+            let mut out = Vec::with_capacity(inner.len());
+            let mut bytes = inner.iter();
+            while let Some(&b) = bytes.next() {
+                out.push(match b {
+                    b'\\' => match bytes.next() {
+                        Some(b'n') => b'\n',
+                        Some(&escaped) => escaped,
+                        None => b'\\',
+                    },
+                    b => b,
+                });
+            }
+            Some(out)
+        }
+        _ => Some(value.to_vec()),
+    }
 }
 
 #[cfg(unix)]
-fn read_from_fd(fd: i32) -> Result<String, PasswordError> {
+fn read_from_fd(fd: i32) -> Result<Vec<u8>, PasswordError> {
     use std::os::unix::io::FromRawFd;
 
     let file = unsafe { std::fs::File::from_raw_fd(fd) };
     let mut reader = io::BufReader::new(&file);
-    let mut line = String::new();
+    let mut line = Vec::new();
     reader
-        .read_line(&mut line)
+        .read_until(b'\n', &mut line)
         .map_err(|e| PasswordError::FdRead { fd, source: e })?;
     std::mem::forget(file);
     Ok(first_line(&line))
@@ -81,14 +296,15 @@ mod tests {
     #[test]
     fn direct_password() {
         let source = PasswordSource::Direct("secret".into());
-        assert_eq!(resolve_password(&source).unwrap(), "secret");
+        assert_eq!(resolve_password(&source).unwrap(), b"secret");
     }
 
     #[test]
     fn env_password() {
-        std::env::set_var("SSHPASS_TEST_VAR", "envpass");
+        // SAFETY: No other test touches this variable
+        unsafe { std::env::set_var("SSHPASS_TEST_VAR", "envpass") };
         let source = PasswordSource::Env("SSHPASS_TEST_VAR".into());
-        assert_eq!(resolve_password(&source).unwrap(), "envpass");
+        assert_eq!(resolve_password(&source).unwrap(), b"envpass");
         assert!(std::env::var("SSHPASS_TEST_VAR").is_err());
     }
 
@@ -98,32 +314,185 @@ mod tests {
         assert!(resolve_password(&source).is_err());
     }
 
+    fn private_file(name: &str, content: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_file(&path);
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(&path).unwrap().write_all(content).unwrap();
+        path
+    }
+
     #[test]
     fn file_password() {
-        let dir = std::env::temp_dir().join("sshpass_test_pw");
-        let mut f = std::fs::File::create(&dir).unwrap();
-        writeln!(f, "filepass").unwrap();
-        writeln!(f, "second line").unwrap();
-        drop(f);
-
-        let source = PasswordSource::File(dir.clone());
-        assert_eq!(resolve_password(&source).unwrap(), "filepass");
+        let dir = private_file("sshpass_test_pw", b"filepass\nsecond line\n");
+
+        let source = PasswordSource::File(dir.clone(), FileFormat::default());
+        assert_eq!(resolve_password(&source).unwrap(), b"filepass");
         std::fs::remove_file(dir).unwrap();
     }
 
+    #[test]
+    fn file_password_is_byte_exact() {
+        let path = private_file("sshpass_test_latin1", b"caf\xe9 \r\n");
+
+        let source = PasswordSource::File(path.clone(), FileFormat::default());
+        assert_eq!(resolve_password(&source).unwrap(), b"caf\xe9 ");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn env_password_is_byte_exact() {
+        use std::os::unix::ffi::OsStrExt;
+        // SAFETY: No other test touches this variable
+        unsafe {
+            std::env::set_var(
+                "SSHPASS_TEST_LATIN1",
+                std::ffi::OsStr::from_bytes(b"\xe9t\xe9"),
+            )
+        };
+        let source = PasswordSource::Env("SSHPASS_TEST_LATIN1".into());
+        assert_eq!(resolve_password(&source).unwrap(), b"\xe9t\xe9");
+    }
+
+    fn extract(format: &str, content: &[u8]) -> Result<Vec<u8>, PasswordError> {
+        format
+            .parse::<FileFormat>()
+            .unwrap()
+            .extract(Path::new("pw"), content)
+    }
+
+    #[test]
+    fn file_formats() {
+        let content = b"first\r\nsecond \n";
+        assert_eq!(extract("first-line", content).unwrap(), b"first");
+        assert_eq!(extract("whole", content).unwrap(), content);
+        assert_eq!(
+            extract("strip-newline", content).unwrap(),
+            b"first\r\nsecond "
+        );
+        assert_eq!(extract("strip-newline", b"pw\r\n").unwrap(), b"pw");
+        assert_eq!(extract("line:2", content).unwrap(), b"second ");
+        assert!(matches!(
+            extract("line:3", content),
+            Err(PasswordError::LineMissing { line: 3, .. })
+        ));
+        assert!("line:0".parse::<FileFormat>().is_err());
+        assert!("dotenv:".parse::<FileFormat>().is_err());
+        assert!("last-line".parse::<FileFormat>().is_err());
+    }
+
+    #[test]
+    fn dotenv_file() {
+        let content =
+            b"# switches\r\nUSER=admin\r\nexport SWITCH_PW = \"a \\\"b\\\"\"\r\nRAW='x\\y'\r\n";
+        assert_eq!(extract("dotenv:USER", content).unwrap(), b"admin");
+        assert_eq!(extract("dotenv:SWITCH_PW", content).unwrap(), b"a \"b\"");
+        assert_eq!(extract("dotenv:RAW", content).unwrap(), b"x\\y");
+        assert!(matches!(
+            extract("dotenv:MISSING", content),
+            Err(PasswordError::KeyMissing { .. })
+        ));
+    }
+
     #[test]
     fn file_not_found() {
-        let source = PasswordSource::File("/nonexistent/path/pw.txt".into());
+        let source = PasswordSource::File("/nonexistent/path/pw.txt".into(), FileFormat::default());
         assert!(resolve_password(&source).is_err());
     }
 
+    #[test]
+    fn credential() {
+        let dir = std::env::temp_dir().join("sshpass_test_credentials");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("switch-pw"), "credpass").unwrap();
+        // SAFETY: No other test touches this variable
+        unsafe { std::env::set_var(CREDENTIALS_DIR_VAR, &dir) };
+
+        let source = PasswordSource::Credential("switch-pw".into());
+        assert_eq!(resolve_password(&source).unwrap(), b"credpass");
+        let source = PasswordSource::Credential("missing".into());
+        assert!(matches!(
+            resolve_password(&source),
+            Err(PasswordError::CredentialMissing { .. })
+        ));
+        let source = PasswordSource::Credential("../switch-pw".into());
+        assert!(matches!(
+            resolve_password(&source),
+            Err(PasswordError::CredentialName { .. })
+        ));
+
+        unsafe { std::env::remove_var(CREDENTIALS_DIR_VAR) };
+        let source = PasswordSource::Credential("switch-pw".into());
+        assert!(matches!(
+            resolve_password(&source),
+            Err(PasswordError::CredentialsDirNotSet { .. })
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
     #[test]
     fn file_empty() {
-        let dir = std::env::temp_dir().join("sshpass_test_empty");
-        std::fs::write(&dir, "").unwrap();
+        let dir = private_file("sshpass_test_empty", b"");
 
-        let source = PasswordSource::File(dir.clone());
-        assert_eq!(resolve_password(&source).unwrap(), "");
+        let source = PasswordSource::File(dir.clone(), FileFormat::default());
+        assert_eq!(resolve_password(&source).unwrap(), b"");
         std::fs::remove_file(dir).unwrap();
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn readable_file_refused() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = private_file("sshpass_test_loose", b"pw\n");
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        let source = PasswordSource::File(path.clone(), FileFormat::default());
+        assert!(matches!(
+            resolve_password(&source),
+            Err(PasswordError::FilePermissions { mode: 0o640, .. })
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_into_world_writable_dir_refused() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join("sshpass_test_ww");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o777)).unwrap();
+        let target = dir.join("pw");
+        let _ = std::fs::remove_file(&target);
+        std::fs::write(&target, "pw\n").unwrap();
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let link = std::env::temp_dir().join("sshpass_test_ww_link");
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        let source = PasswordSource::File(link.clone(), FileFormat::default());
+        assert!(matches!(
+            resolve_password(&source),
+            Err(PasswordError::FileSymlink { .. })
+        ));
+        std::fs::remove_file(link).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn warning_and_refusal_share_the_advice() {
+        let warning = PasswordError::VisibleArgument { refused: false }.to_string();
+        let refusal = PasswordError::VisibleArgument { refused: true }.to_string();
+        assert!(!warning.contains("policy"));
+        assert!(refusal.contains("refused by policy"));
+        for message in [warning, refusal] {
+            assert!(message.starts_with("-p makes the password visible to other users"));
+            assert!(message.ends_with("use -f, -e, -d or --credential"));
+        }
+    }
 }
@@ -0,0 +1,94 @@
+use regex::bytes::Regex;
+use std::sync::LazyLock;
+
+/// Password prompts of localised PAM modules and login programs. The ssh
+/// client itself always asks in English, which `assword:` covers.
+///
+/// A prompt has to make up the whole last line, apart from the
+/// `(user@host)` that ssh puts before keyboard-interactive prompts, so that
+/// `Neues Passwort:` of a password change does not pass for a login prompt.
+const LOCALIZED_PROMPTS: &[&str] = &[
+    // German
+    "Passwort:",
+    "Kennwort:",
+    // French, with and without the space before the colon
+    "Mot de passe :",
+    "Mot de passe:",
+    // Spanish
+    "Contraseña:",
+    // Portuguese
+    "Senha:",
+    // Dutch
+    "Wachtwoord:",
+    // Swedish
+    "Lösenord:",
+    // Danish
+    "Adgangskode:",
+    // Norwegian
+    "Passord:",
+    // Finnish
+    "Salasana:",
+    // Polish
+    "Hasło:",
+    // Czech and Slovak
+    "Heslo:",
+    // Hungarian
+    "Jelszó:",
+    // Turkish
+    "Parola:",
+    // Russian and Ukrainian
+    "Пароль:",
+    // Greek
+    "Κωδικός πρόσβασης:",
+    // Japanese
+    "パスワード:",
+    "パスワード：",
+    // Chinese, simplified and traditional
+    "密码：",
+    "密码:",
+    "密碼：",
+    "密碼:",
+    // Korean
+    "암호:",
+    "비밀번호:",
+];
+
+static LOCALIZED: LazyLock<Regex> = LazyLock::new(|| {
+    let prompts: Vec<String> = LOCALIZED_PROMPTS.iter().map(|p| regex::escape(p)).collect();
+    Regex::new(&format!(
+        r"^\s*(?:\([^)]*\)\s*)?(?:{})\s*$",
+        prompts.join("|")
+    ))
+    .expect("valid prompt regex")
+});
+
+/// Whether the last line of `window` is a localised password prompt.
+pub fn is_localized_prompt(window: &[u8]) -> bool {
+    let line = match window.iter().rposition(|&b| b == b'\n') {
+        Some(pos) => &window[pos + 1..],
+        None => window,
+    };
+    LOCALIZED.is_match(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whole_line_prompts() {
+        assert!(is_localized_prompt(b"Passwort: "));
+        assert!(is_localized_prompt(
+            b"Last login: today\r\n(me@host) Mot de passe : "
+        ));
+        assert!(is_localized_prompt("\r\nパスワード：".as_bytes()));
+    }
+
+    #[test]
+    fn change_prompts_are_not_login_prompts() {
+        assert!(!is_localized_prompt(b"Neues Passwort: "));
+        assert!(!is_localized_prompt(b"Nouveau mot de passe : "));
+        assert!(!is_localized_prompt("Nueva contraseña: ".as_bytes()));
+        assert!(!is_localized_prompt(b"Passwort: abgelaufen\r\n"));
+    }
+}
@@ -1,17 +1,58 @@
-use portable_pty::{CommandBuilder, MasterPty, PtySize, native_pty_system};
-use std::io::{Read, Write};
-use std::sync::atomic::{AtomicI32, Ordering};
-use std::sync::{Arc, Mutex};
+use portable_pty::{Child, ChildKiller, ExitStatus, MasterPty, PtySize, native_pty_system};
+use regex::bytes::Regex;
+use std::ffi::OsString;
+use std::io::{IsTerminal, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
-
-use crate::matcher::Matcher;
-
-const RETURN_INCORRECT_PASSWORD: i32 = 5;
-const RETURN_HOST_KEY_UNKNOWN: i32 = 6;
-const RETURN_HOST_KEY_CHANGED: i32 = 7;
+use std::time::{Duration, Instant};
+
+use crate::environment::ChildEnv;
+use crate::hops::HopPasswords;
+use crate::passphrase::Passphrases;
+use crate::passwd::PasswordChange;
+use crate::script::ScriptRunner;
+use crate::session::{Sent, Session, SessionConfig, Step};
+
+pub const RETURN_INCORRECT_PASSWORD: i32 = 5;
+pub const RETURN_HOST_KEY_UNKNOWN: i32 = 6;
+pub const RETURN_HOST_KEY_CHANGED: i32 = 7;
+pub const RETURN_SCRIPT_FAILED: i32 = 8;
+pub const RETURN_CONNECTION_FAILED: i32 = 9;
+pub const RETURN_INCORRECT_PASSPHRASE: i32 = 16;
+const RETURN_PASSWORD_CHANGED: i32 = 17;
+pub const RETURN_PASSWORD_REJECTED: i32 = 18;
+pub const RETURN_PASSWORD_CHANGE_REQUIRED: i32 = 19;
+/// The command went quiet in the middle of the login dialog.
+pub const RETURN_STALLED: i32 = 21;
+
+/// Marks that sshpass did not decide the exit code itself.
+const NO_EXIT_CODE: i32 = -1;
+
+/// Time a hung up or signalled child gets to exit before it is killed.
+const HANGUP_GRACE: Duration = Duration::from_secs(2);
+const WAIT_POLL: Duration = Duration::from_millis(20);
+
+/// Ctrl-D, used when the slave's termios cannot be read.
+const DEFAULT_VEOF: u8 = 0x04;
+
+const DEFAULT_ROWS: u16 = 24;
+const DEFAULT_COLS: u16 = 80;
 
 type SharedWriter = Arc<Mutex<Option<Box<dyn Write + Send>>>>;
 type SharedMaster = Arc<Mutex<Option<Box<dyn MasterPty + Send>>>>;
+type SharedHangup = Arc<Mutex<Hangup>>;
+
+struct Hangup {
+    killer: Box<dyn ChildKiller + Send + Sync>,
+    /// Process group of the child, which leads its own session.
+    #[cfg(unix)]
+    pgid: Option<i32>,
+    since: Option<Instant>,
+    /// The first signal forwarded to the child.
+    signal: Option<i32>,
+}
 
 #[derive(Debug, thiserror::Error)]
 pub enum PtyError {
@@ -26,36 +67,90 @@ pub enum PtyError {
 }
 
 pub struct RunConfig {
-    pub command: Vec<String>,
-    pub password: String,
+    pub command: Vec<OsString>,
+    pub password: Vec<u8>,
     pub prompt: String,
+    /// Also answer the prompts of localised PAM modules.
+    pub localized_prompts: bool,
+    /// Replaces the built-in prompt handling when set.
+    pub script: Option<ScriptRunner>,
+    /// Close the session with exit code 0 once this matches the output,
+    /// signalling a successful login.
+    pub login_marker: Option<Regex>,
+    /// Answers for private key passphrase prompts.
+    pub passphrases: Passphrases,
+    /// Passwords for the jump hosts of a ProxyJump chain.
+    pub hop_passwords: HopPasswords,
+    /// Handles a forced password change during login.
+    pub password_change: Option<PasswordChange>,
+    /// Give up if the login dialog shows no progress for this long.
+    pub stall_timeout: Option<Duration>,
+    /// Environment and working directory of the command.
+    pub env: ChildEnv,
+    /// Fixed PTY width; the terminal size is no longer followed if set.
+    pub cols: Option<u16>,
+    /// Fixed PTY height; the terminal size is no longer followed if set.
+    pub rows: Option<u16>,
+    /// Let the command use our stdin, stdout and stderr and give it the
+    /// PTY only as its controlling terminal, where ssh asks for passwords.
+    /// Needed when the command carries binary data, as for git or rsync.
+    pub inherit_stdio: bool,
 }
 
 pub fn run(config: RunConfig) -> Result<i32, PtyError> {
     let pty_system = native_pty_system();
 
-    let initial_size = get_terminal_size().unwrap_or(PtySize {
-        rows: 24,
-        cols: 80,
-        pixel_width: 0,
-        pixel_height: 0,
-    });
+    // Without a terminal to follow, network devices would paginate at 24
+    // lines; honour the geometry the caller asked for instead.
+    let terminal = get_terminal_size();
+    let follow_terminal = terminal.is_some() && config.cols.is_none() && config.rows.is_none();
+    let initial_size = match terminal {
+        Some(size) if follow_terminal => size,
+        _ => PtySize {
+            rows: config
+                .rows
+                .or(terminal.map(|t| t.rows))
+                .or_else(|| size_from_env("LINES"))
+                .unwrap_or(DEFAULT_ROWS),
+            cols: config
+                .cols
+                .or(terminal.map(|t| t.cols))
+                .or_else(|| size_from_env("COLUMNS"))
+                .unwrap_or(DEFAULT_COLS),
+            pixel_width: 0,
+            pixel_height: 0,
+        },
+    };
 
     let pair = pty_system
         .openpty(initial_size)
         .map_err(|e| PtyError::Open(e.to_string()))?;
 
-    let mut cmd = CommandBuilder::new(&config.command[0]);
-    for arg in &config.command[1..] {
-        cmd.arg(arg);
-    }
-
-    let mut child = pair
-        .slave
-        .spawn_command(cmd)
-        .map_err(|e| PtyError::Spawn(e.to_string()))?;
+    let mut child = if config.inherit_stdio {
+        let cmd = config
+            .env
+            .std_command(&config.command)
+            .map_err(|e| PtyError::Spawn(e.to_string()))?;
+        spawn_with_controlling_tty(cmd, &*pair.master)?
+    } else {
+        let cmd = config
+            .env
+            .command(&config.command)
+            .map_err(|e| PtyError::Spawn(e.to_string()))?;
+        pair.slave
+            .spawn_command(cmd)
+            .map_err(|e| PtyError::Spawn(e.to_string()))?
+    };
 
-    drop(pair.slave);
+    // A pty whose slave side is closed everywhere reports EIO to the
+    // reader. With inherited stdio the child only opens the slave when it
+    // needs the terminal, so keep it open until the child is gone.
+    let slave = if config.inherit_stdio {
+        Some(pair.slave)
+    } else {
+        drop(pair.slave);
+        None
+    };
 
     let mut reader = pair
         .master
@@ -68,12 +163,31 @@ pub fn run(config: RunConfig) -> Result<i32, PtyError> {
             .map_err(|e| PtyError::Writer(e.to_string()))?,
     )));
     let master: SharedMaster = Arc::new(Mutex::new(Some(pair.master)));
-    let exit_code = Arc::new(AtomicI32::new(0));
-
-    let _raw_guard = RawModeGuard::enter();
+    let hangup: SharedHangup = Arc::new(Mutex::new(Hangup {
+        killer: child.clone_killer(),
+        #[cfg(unix)]
+        pgid: child.process_id().and_then(|pid| i32::try_from(pid).ok()),
+        since: None,
+        signal: None,
+    }));
+    let exit_code = Arc::new(AtomicI32::new(NO_EXIT_CODE));
+    let password_changed = Arc::new(AtomicBool::new(false));
+
+    // With inherited stdio the command drives our terminal itself.
+    let _raw_guard = if config.inherit_stdio {
+        RawModeGuard::inactive()
+    } else {
+        RawModeGuard::enter()
+    };
 
     #[cfg(unix)]
-    let _signal_handle = setup_unix_signals(Arc::clone(&writer), Arc::clone(&master));
+    let _signal_handle = setup_unix_signals(
+        Arc::clone(&writer),
+        Arc::clone(&master),
+        Arc::clone(&hangup),
+        _raw_guard.modes(),
+        follow_terminal,
+    );
 
     #[cfg(not(unix))]
     {
@@ -83,90 +197,121 @@ pub fn run(config: RunConfig) -> Result<i32, PtyError> {
         });
     }
 
-    let stdin_handle = {
-        let writer = Arc::clone(&writer);
-        thread::spawn(move || {
-            let mut stdin = std::io::stdin();
-            let mut buf = [0u8; 1024];
-            loop {
-                match stdin.read(&mut buf) {
-                    Ok(0) => break,
-                    Ok(n) => write_to_pty(&writer, &buf[..n]),
-                    Err(_) => break,
+    let gate = Arc::new(StdinGate::default());
+    let script = config.script;
+    let stdin_handle = match script {
+        _ if config.inherit_stdio => None,
+        Some(_) => None,
+        None => Some(spawn_stdin_forwarder(
+            Arc::clone(&writer),
+            Arc::clone(&master),
+            Some(Arc::clone(&gate)),
+        )),
+    };
+
+    let (output_tx, output_rx) = mpsc::channel::<Vec<u8>>();
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if output_tx.send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
                 }
             }
-        })
-    };
+        }
+    });
 
     let read_handle = {
-        let password = config.password;
-        let prompt = config.prompt;
+        let mut session = Session::new(SessionConfig {
+            password: config.password,
+            prompt: config.prompt,
+            localized_prompts: config.localized_prompts,
+            script,
+            login_marker: config.login_marker,
+            passphrases: config.passphrases,
+            hop_passwords: config.hop_passwords,
+            password_change: config.password_change,
+            stall_timeout: config.stall_timeout,
+        });
+        let password_changed = Arc::clone(&password_changed);
         let exit_code = Arc::clone(&exit_code);
         let writer = Arc::clone(&writer);
         let master = Arc::clone(&master);
+        let hangup = Arc::clone(&hangup);
+        let gate = Arc::clone(&gate);
+        let inherit_stdio = config.inherit_stdio;
 
         thread::spawn(move || {
-            let mut stdout = std::io::stdout();
-            let mut pw_matcher = Matcher::new(&prompt);
-            let mut hk_matcher = Matcher::new("The authenticity of host ");
-            let mut hkc_matcher = Matcher::new("differs from the key for the IP address");
-            let mut password_sent = false;
-            let mut suppress_until_newline = false;
-            let mut buf = [0u8; 4096];
-
-            loop {
-                match reader.read(&mut buf) {
-                    Ok(0) => break,
-                    Ok(n) => {
-                        let data = &buf[..n];
-
-                        if pw_matcher.feed(data) {
-                            if !password_sent {
-                                let payload = format!("{}\n", password);
-                                write_to_pty(&writer, payload.as_bytes());
-                                password_sent = true;
-                                suppress_until_newline = true;
-                                pw_matcher.reset();
-                            } else {
-                                exit_code.store(RETURN_INCORRECT_PASSWORD, Ordering::SeqCst);
-                                close_pty(&writer, &master);
-                                break;
+            // Only terminal chatter reaches the PTY with inherited stdio;
+            // it must not end up in the command's data stream.
+            let mut output: Box<dyn Write> = if inherit_stdio {
+                Box::new(std::io::stderr())
+            } else {
+                Box::new(std::io::stdout())
+            };
+            let mut apply = |steps: Vec<Step>| -> bool {
+                for step in steps {
+                    match step {
+                        Step::Matched(_) => {}
+                        Step::Send { data, what } => {
+                            if !matches!(what, Sent::Script { .. } | Sent::ChangeAnswer) {
+                                gate.prompt_answered(&master, what == Sent::Password);
                             }
+                            write_to_pty(&writer, &data);
                         }
-
-                        if hk_matcher.feed(data) {
-                            exit_code.store(RETURN_HOST_KEY_UNKNOWN, Ordering::SeqCst);
-                            close_pty(&writer, &master);
-                            break;
+                        Step::Interact => {
+                            spawn_stdin_forwarder(Arc::clone(&writer), Arc::clone(&master), None);
                         }
-
-                        if hkc_matcher.feed(data) {
-                            exit_code.store(RETURN_HOST_KEY_CHANGED, Ordering::SeqCst);
-                            close_pty(&writer, &master);
-                            break;
+                        Step::Output(data) => {
+                            let _ = output.write_all(&data);
+                            let _ = output.flush();
+                        }
+                        Step::Notice(message) => eprintln!("SSHPASS: {message}"),
+                        Step::PasswordChanged => password_changed.store(true, Ordering::SeqCst),
+                        Step::Exit(code) => exit_code.store(code, Ordering::SeqCst),
+                        Step::Close(code) => {
+                            exit_code.store(code, Ordering::SeqCst);
+                            close_pty(&writer, &master, &hangup);
+                            return true;
                         }
+                    }
+                }
+                false
+            };
 
-                        if suppress_until_newline {
-                            if let Some(pos) = data.iter().position(|&b| b == b'\n') {
-                                suppress_until_newline = false;
-                                let remaining = &data[pos + 1..];
-                                if !remaining.is_empty() {
-                                    let _ = stdout.write_all(remaining);
-                                    let _ = stdout.flush();
-                                }
-                            }
-                        } else {
-                            let _ = stdout.write_all(data);
-                            let _ = stdout.flush();
+            if !apply(session.start()) {
+                loop {
+                    let received = match session.deadline() {
+                        Some(deadline) => output_rx
+                            .recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                        None => output_rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    };
+                    let steps = match received {
+                        Ok(data) => session.feed(&data),
+                        Err(RecvTimeoutError::Timeout) => session.finish(false),
+                        Err(RecvTimeoutError::Disconnected) => {
+                            apply(session.finish(true));
+                            break;
                         }
+                    };
+                    if apply(steps) {
+                        break;
                     }
-                    Err(_) => break,
                 }
             }
+
+            gate.close();
+            if let Some(message) = session.settle() {
+                eprintln!("SSHPASS: {message}");
+            }
         })
     };
 
-    let child_status = child.wait().ok();
+    let child_status = wait_child(&mut *child, &hangup);
+    drop(slave);
 
     #[cfg(unix)]
     if let Some(handle) = _signal_handle {
@@ -176,17 +321,282 @@ pub fn run(config: RunConfig) -> Result<i32, PtyError> {
     let _ = read_handle.join();
     drop(stdin_handle);
 
+    if let Some(signal) = hangup.lock().ok().and_then(|h| h.signal) {
+        return Ok(128 + signal);
+    }
+
     let sshpass_code = exit_code.load(Ordering::SeqCst);
-    if sshpass_code != 0 {
+    if sshpass_code != NO_EXIT_CODE {
         return Ok(sshpass_code);
     }
 
-    match child_status {
-        Some(status) => Ok(status.exit_code().try_into().unwrap_or(255)),
-        None => Ok(255),
+    let code = match child_status {
+        Some(status) => status.exit_code().try_into().unwrap_or(255),
+        None => 255,
+    };
+    if code != 0 && password_changed.load(Ordering::SeqCst) {
+        return Ok(RETURN_PASSWORD_CHANGED);
+    }
+    Ok(code)
+}
+
+/// Forwards our stdin to the child, piped input through `gate` if given.
+fn spawn_stdin_forwarder(
+    writer: SharedWriter,
+    master: SharedMaster,
+    gate: Option<Arc<StdinGate>>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut gate = gate
+            .filter(|_| !std::io::stdin().is_terminal())
+            .map(|gate| GatedInput::new(gate, &writer, &master));
+        let mut forward = |data: &[u8]| match gate {
+            Some(ref mut gate) => gate.forward(data),
+            None => write_to_pty(&writer, data),
+        };
+        let mut stdin = std::io::stdin();
+        let mut buf = [0u8; 1024];
+        let mut line_open = false;
+        loop {
+            match stdin.read(&mut buf) {
+                Ok(0) => {
+                    forward(&eof_sequence(&master, line_open));
+                    break;
+                }
+                Ok(n) => {
+                    forward(&buf[..n]);
+                    line_open = buf[n - 1] != b'\n';
+                }
+                Err(_) => break,
+            }
+        }
+        if let Some(mut gate) = gate {
+            gate.finish();
+        }
+    })
+}
+
+/// What the output side tells the stdin forwarder about the login.
+#[derive(Default)]
+struct StdinGate {
+    state: Mutex<GateState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct GateState {
+    /// The password was answered; the login dialog is over.
+    answered: bool,
+    /// A prompt had echo off when it was answered. ssh flushes pending
+    /// input when it turns echo off, so input sent before it is gone.
+    flushed: bool,
+    /// The session is over.
+    closed: bool,
+}
+
+impl StdinGate {
+    /// Records that a prompt is about to be answered; `last` if it ends
+    /// the login dialog.
+    fn prompt_answered(&self, master: &SharedMaster, last: bool) {
+        let echo_off = slave_modes(master).is_some_and(|m| !m.echo);
+        if let Ok(mut state) = self.state.lock() {
+            state.flushed |= echo_off;
+            state.answered |= last;
+        }
+        self.changed.notify_all();
+    }
+
+    fn close(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.closed = true;
+        }
+        self.changed.notify_all();
+    }
+
+    /// Whether the password was answered, and whether input was flushed.
+    /// `None` once the session is over.
+    fn answered(&self) -> Option<(bool, bool)> {
+        let state = self.state.lock().ok()?;
+        (!state.closed).then_some((state.answered, state.flushed))
+    }
+}
+
+/// Piped input on its way to the child during the login dialog. It never
+/// reaches a prompt with echo off, where it would end up in the answer,
+/// and is sent again if a password prompt flushed it.
+struct GatedInput<'a> {
+    gate: Arc<StdinGate>,
+    writer: &'a SharedWriter,
+    master: &'a SharedMaster,
+    /// Input sent during the login dialog; `None` once it is over.
+    sent: Option<Vec<u8>>,
+}
+
+impl<'a> GatedInput<'a> {
+    fn new(gate: Arc<StdinGate>, writer: &'a SharedWriter, master: &'a SharedMaster) -> Self {
+        Self {
+            gate,
+            writer,
+            master,
+            sent: Some(Vec::new()),
+        }
+    }
+
+    fn forward(&mut self, data: &[u8]) {
+        if self.sent.is_some() {
+            // A prompt is reading a secret until echo is back on.
+            while let Some((answered, _)) = self.gate.answered() {
+                if answered {
+                    self.settle();
+                    break;
+                }
+                match slave_modes(self.master) {
+                    Some(modes) if !modes.echo => thread::sleep(WAIT_POLL),
+                    _ => break,
+                }
+            }
+        }
+        if let Some(ref mut sent) = self.sent {
+            sent.extend_from_slice(data);
+        }
+        write_to_pty(self.writer, data);
+    }
+
+    /// After the last input, waits for the login dialog to end so that
+    /// flushed input can be sent again.
+    fn finish(&mut self) {
+        if self.sent.is_none() {
+            return;
+        }
+        let Ok(mut state) = self.gate.state.lock() else {
+            return;
+        };
+        while !state.answered && !state.closed {
+            state = match self.gate.changed.wait(state) {
+                Ok(state) => state,
+                Err(_) => return,
+            };
+        }
+        drop(state);
+        self.settle();
+    }
+
+    /// Ends the login dialog: once echo is on again, resends what a
+    /// prompt flushed.
+    fn settle(&mut self) {
+        let Some(sent) = self.sent.take() else {
+            return;
+        };
+        let Some((_, flushed)) = self.gate.answered() else {
+            return;
+        };
+        if !flushed || sent.is_empty() {
+            return;
+        }
+        loop {
+            match slave_modes(self.master) {
+                Some(modes) if modes.echo => break,
+                Some(_) if self.gate.answered().is_some() => thread::sleep(WAIT_POLL),
+                _ => return,
+            }
+        }
+        write_to_pty(self.writer, &sent);
     }
 }
 
+/// What passes end of input on to the child: the terminal's EOF
+/// character. In canonical mode the first one only flushes an unfinished
+/// line, so a second one is needed to signal end of file.
+fn eof_sequence(master: &SharedMaster, line_open: bool) -> Vec<u8> {
+    let (veof, canonical) =
+        slave_modes(master).map_or((DEFAULT_VEOF, true), |m| (m.veof, m.canonical));
+    let count = if canonical && line_open { 2 } else { 1 };
+    vec![veof; count]
+}
+
+/// The parts of the slave's termios that stdin forwarding cares about.
+struct SlaveModes {
+    echo: bool,
+    canonical: bool,
+    veof: u8,
+}
+
+#[cfg(unix)]
+fn slave_modes(master: &SharedMaster) -> Option<SlaveModes> {
+    let fd = master.lock().ok()?.as_ref()?.as_raw_fd()?;
+    unsafe {
+        let mut termios = std::mem::MaybeUninit::<libc::termios>::zeroed().assume_init();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return None;
+        }
+        Some(SlaveModes {
+            echo: termios.c_lflag & libc::ECHO != 0,
+            canonical: termios.c_lflag & libc::ICANON != 0,
+            veof: termios.c_cc[libc::VEOF],
+        })
+    }
+}
+
+#[cfg(not(unix))]
+fn slave_modes(_master: &SharedMaster) -> Option<SlaveModes> {
+    None
+}
+
+/// Spawns `cmd` with our stdio in a new session whose controlling
+/// terminal is the slave side of `master`.
+#[cfg(unix)]
+fn spawn_with_controlling_tty(
+    mut cmd: std::process::Command,
+    master: &dyn MasterPty,
+) -> Result<Box<dyn Child + Send + Sync>, PtyError> {
+    use std::os::unix::process::CommandExt;
+
+    let fd = master
+        .as_raw_fd()
+        .ok_or_else(|| PtyError::Spawn("no pty file descriptor".into()))?;
+    let slave = unsafe {
+        let name = libc::ptsname(fd);
+        if name.is_null() {
+            return Err(PtyError::Spawn(std::io::Error::last_os_error().to_string()));
+        }
+        std::ffi::CStr::from_ptr(name).to_owned()
+    };
+
+    unsafe {
+        cmd.pre_exec(move || {
+            for signo in [libc::SIGHUP, libc::SIGINT, libc::SIGQUIT, libc::SIGTERM] {
+                libc::signal(signo, libc::SIG_DFL);
+            }
+            if libc::setsid() == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let tty = libc::open(slave.as_ptr(), libc::O_RDWR);
+            if tty == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            #[allow(clippy::cast_lossless)]
+            if libc::ioctl(tty, libc::TIOCSCTTY as _, 0) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            libc::close(tty);
+            Ok(())
+        });
+    }
+    let child = cmd.spawn().map_err(|e| PtyError::Spawn(e.to_string()))?;
+    Ok(Box::new(child))
+}
+
+/// Controlling terminals only exist on unix.
+#[cfg(not(unix))]
+fn spawn_with_controlling_tty(
+    _cmd: std::process::Command,
+    _master: &dyn MasterPty,
+) -> Result<Box<dyn Child + Send + Sync>, PtyError> {
+    Err(PtyError::Spawn(
+        "inheriting stdio is only supported on unix".into(),
+    ))
+}
+
 fn write_to_pty(writer: &SharedWriter, data: &[u8]) {
     if let Ok(mut guard) = writer.lock()
         && let Some(ref mut w) = *guard
@@ -196,17 +606,52 @@ fn write_to_pty(writer: &SharedWriter, data: &[u8]) {
     }
 }
 
-fn close_pty(writer: &SharedWriter, master: &SharedMaster) {
+/// Closes our side of the PTY and hangs up the child. The output reader
+/// thread still holds a clone of the master, so closing alone would not
+/// deliver SIGHUP.
+fn close_pty(writer: &SharedWriter, master: &SharedMaster, hangup: &SharedHangup) {
     if let Ok(mut w) = writer.lock() {
         w.take();
     }
     if let Ok(mut m) = master.lock() {
         m.take();
     }
+    if let Ok(mut h) = hangup.lock() {
+        let _ = h.killer.kill();
+        h.since.get_or_insert_with(Instant::now);
+    }
+}
+
+/// Waits for the child, killing its process group if it outlives a hangup
+/// or forwarded signal by more than [`HANGUP_GRACE`].
+fn wait_child(child: &mut (dyn Child + Send + Sync), hangup: &SharedHangup) -> Option<ExitStatus> {
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Some(status),
+            Ok(None) => {}
+            Err(_) => return None,
+        }
+        let overdue = hangup
+            .lock()
+            .ok()
+            .and_then(|h| h.since)
+            .is_some_and(|since| since.elapsed() >= HANGUP_GRACE);
+        if overdue {
+            #[cfg(unix)]
+            if let Some(pgid) = hangup.lock().ok().and_then(|h| h.pgid) {
+                unsafe {
+                    libc::killpg(pgid, libc::SIGKILL);
+                }
+            }
+            let _ = child.kill();
+            return child.wait().ok();
+        }
+        thread::sleep(WAIT_POLL);
+    }
 }
 
 #[cfg(unix)]
-fn get_terminal_size() -> Option<PtySize> {
+pub fn get_terminal_size() -> Option<PtySize> {
     unsafe {
         let mut ws = std::mem::MaybeUninit::<libc::winsize>::zeroed().assume_init();
         if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut ws) == 0 {
@@ -223,47 +668,113 @@ fn get_terminal_size() -> Option<PtySize> {
 }
 
 #[cfg(not(unix))]
-fn get_terminal_size() -> Option<PtySize> {
+pub fn get_terminal_size() -> Option<PtySize> {
     None
 }
 
-struct RawModeGuard {
+/// Reads a terminal dimension such as `COLUMNS` from our environment.
+fn size_from_env(var: &str) -> Option<u16> {
+    std::env::var(var)
+        .ok()?
+        .parse()
+        .ok()
+        .filter(|&n: &u16| n > 0)
+}
+
+/// The terminal settings sshpass found and the raw ones it switches to.
+#[cfg(unix)]
+#[derive(Clone, Copy)]
+struct TerminalModes {
+    original: libc::termios,
+    raw: libc::termios,
+}
+
+#[cfg(unix)]
+impl TerminalModes {
+    fn restore(&self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+
+    /// Switches back to raw mode, unless sshpass was resumed in the
+    /// background, where changing the terminal would stop it again.
+    fn make_raw(&self) {
+        unsafe {
+            if libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp() {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.raw);
+            }
+        }
+    }
+}
+
+pub struct RawModeGuard {
     #[cfg(unix)]
-    original: Option<libc::termios>,
+    modes: Option<TerminalModes>,
 }
 
 impl RawModeGuard {
     fn enter() -> Self {
+        Self::with_signals(true)
+    }
+
+    /// Like [`RawModeGuard::enter`], but Ctrl-C and friends arrive as
+    /// input instead of raising signals, as with ssh.
+    #[cfg(feature = "native-ssh")]
+    pub fn enter_passthrough() -> Self {
+        Self::with_signals(false)
+    }
+
+    fn with_signals(signals: bool) -> Self {
         #[cfg(unix)]
         {
-            let original = unsafe {
+            let modes = unsafe {
                 let mut termios = std::mem::MaybeUninit::<libc::termios>::zeroed().assume_init();
                 if libc::isatty(libc::STDIN_FILENO) != 0
                     && libc::tcgetattr(libc::STDIN_FILENO, &mut termios) == 0
                 {
                     let original = termios;
                     libc::cfmakeraw(&mut termios);
-                    termios.c_lflag |= libc::ISIG;
+                    if signals {
+                        termios.c_lflag |= libc::ISIG;
+                    }
                     libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
-                    Some(original)
+                    Some(TerminalModes {
+                        original,
+                        raw: termios,
+                    })
                 } else {
                     None
                 }
             };
-            Self { original }
+            Self { modes }
         }
         #[cfg(not(unix))]
-        Self {}
+        {
+            let _ = signals;
+            Self {}
+        }
+    }
+
+    /// Leaves the terminal alone.
+    fn inactive() -> Self {
+        Self {
+            #[cfg(unix)]
+            modes: None,
+        }
+    }
+
+    #[cfg(unix)]
+    fn modes(&self) -> Option<TerminalModes> {
+        self.modes
     }
 }
 
 impl Drop for RawModeGuard {
     fn drop(&mut self) {
         #[cfg(unix)]
-        if let Some(ref original) = self.original {
-            unsafe {
-                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original);
-            }
+        if let Some(ref modes) = self.modes {
+            modes.restore();
         }
     }
 }
@@ -272,27 +783,37 @@ impl Drop for RawModeGuard {
 fn setup_unix_signals(
     writer: SharedWriter,
     master: SharedMaster,
+    hangup: SharedHangup,
+    modes: Option<TerminalModes>,
+    follow_terminal: bool,
 ) -> Option<signal_hook::iterator::backend::Handle> {
     use signal_hook::consts::*;
     use signal_hook::iterator::Signals;
 
-    let mut signals = Signals::new([SIGWINCH, SIGTERM, SIGHUP, SIGINT, SIGTSTP]).ok()?;
+    let mut signals = Signals::new([
+        SIGWINCH, SIGTERM, SIGHUP, SIGQUIT, SIGUSR1, SIGUSR2, SIGINT, SIGTSTP, SIGCONT,
+    ])
+    .ok()?;
     let handle = signals.handle();
 
     thread::spawn(move || {
         for sig in signals.forever() {
             match sig {
-                SIGWINCH => {
-                    if let Some(size) = get_terminal_size()
-                        && let Ok(m) = master.lock()
-                        && let Some(ref m) = *m
-                    {
-                        let _ = m.resize(size);
+                SIGWINCH if follow_terminal => resize_to_terminal(&master),
+                SIGINT => write_to_pty(&writer, b"\x03"),
+                SIGTSTP => {
+                    // The child runs in its own session and keeps going;
+                    // only sshpass and the terminal are handed back.
+                    if let Some(ref modes) = modes {
+                        modes.restore();
+                    }
+                    unsafe {
+                        libc::raise(libc::SIGSTOP);
                     }
+                    resume(modes.as_ref(), &master, follow_terminal);
                 }
-                SIGINT => write_to_pty(&writer, b"\x03"),
-                SIGTSTP => write_to_pty(&writer, b"\x1a"),
-                SIGTERM | SIGHUP => break,
+                SIGCONT => resume(modes.as_ref(), &master, follow_terminal),
+                SIGTERM | SIGHUP | SIGQUIT | SIGUSR1 | SIGUSR2 => forward_signal(&hangup, sig),
                 _ => {}
             }
         }
@@ -300,3 +821,39 @@ fn setup_unix_signals(
 
     Some(handle)
 }
+
+/// Restores raw mode and the window size after sshpass was stopped.
+#[cfg(unix)]
+fn resume(modes: Option<&TerminalModes>, master: &SharedMaster, follow_terminal: bool) {
+    if let Some(modes) = modes {
+        modes.make_raw();
+    }
+    if follow_terminal {
+        resize_to_terminal(master);
+    }
+}
+
+#[cfg(unix)]
+fn resize_to_terminal(master: &SharedMaster) {
+    if let Some(size) = get_terminal_size()
+        && let Ok(m) = master.lock()
+        && let Some(ref m) = *m
+    {
+        let _ = m.resize(size);
+    }
+}
+
+/// Sends `signal` to the child's process group and starts the grace period
+/// after which [`wait_child`] kills it.
+#[cfg(unix)]
+fn forward_signal(hangup: &SharedHangup, signal: i32) {
+    if let Ok(mut h) = hangup.lock() {
+        if let Some(pgid) = h.pgid {
+            unsafe {
+                libc::killpg(pgid, signal);
+            }
+        }
+        h.since.get_or_insert_with(Instant::now);
+        h.signal.get_or_insert(signal);
+    }
+}
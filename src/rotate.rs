@@ -0,0 +1,175 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use crate::environment::ChildEnv;
+use crate::generate::{self, PasswordPolicy};
+use crate::hops::HopPasswords;
+use crate::passphrase::Passphrases;
+use crate::passwd::{PasswordChange, PendingPassword};
+use crate::password::{FileFormat, PasswordError, PasswordSource, resolve_password};
+use crate::pty::{self, RETURN_INCORRECT_PASSWORD, RETURN_PASSWORD_REJECTED};
+
+/// The password was changed but logging in with it could not be
+/// verified. The new password is kept next to the password file.
+pub const RETURN_ROTATION_UNVERIFIED: i32 = 20;
+
+/// Command run after logging in to check that the new password works.
+const VERIFY_COMMAND: &str = "true";
+
+#[derive(Debug, thiserror::Error)]
+pub enum RotateError {
+    #[error("{0}")]
+    Password(#[from] PasswordError),
+    #[error("{0}")]
+    Generate(#[from] generate::GenerateError),
+    #[error("failed to write \"{path}\": {source}")]
+    Stage {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("PTY error: {0}")]
+    Pty(#[from] pty::PtyError),
+}
+
+/// Everything needed to change a password on a remote host.
+pub struct Rotation {
+    /// ssh command and arguments used to log in.
+    pub login: Vec<String>,
+    /// Password file holding the current password; receives the new one.
+    pub file: PathBuf,
+    pub prompt: String,
+    pub localized_prompts: bool,
+    /// Command run on the remote host to change the password.
+    pub change_command: Vec<String>,
+    pub policy: PasswordPolicy,
+    pub passphrases: Passphrases,
+    pub hop_passwords: HopPasswords,
+    pub env: ChildEnv,
+}
+
+/// Changes the password, logs in again with the new one and only then
+/// replaces the password file. Returns the exit code for sshpass.
+pub fn run(rotation: Rotation) -> Result<i32, RotateError> {
+    let current = resolve_password(&PasswordSource::File(
+        rotation.file.clone(),
+        FileFormat::default(),
+    ))?;
+    let new = generate::generate(&rotation.policy)?.into_bytes();
+    let pending =
+        PendingPassword::stage(&rotation.file, &new).map_err(|source| RotateError::Stage {
+            path: rotation.file.clone(),
+            source,
+        })?;
+
+    let change = PasswordChange::new(current.clone(), new.clone()).during_session();
+    let changed = pty::run(pty::RunConfig {
+        command: remote_command(&rotation.login, &rotation.change_command),
+        password: current,
+        prompt: rotation.prompt.clone(),
+        localized_prompts: rotation.localized_prompts,
+        script: None,
+        login_marker: None,
+        passphrases: rotation.passphrases.clone(),
+        hop_passwords: rotation.hop_passwords.clone(),
+        password_change: Some(change),
+        stall_timeout: None,
+        env: rotation.env.clone(),
+        cols: None,
+        rows: None,
+        inherit_stdio: false,
+    })?;
+    if changed == RETURN_PASSWORD_REJECTED || changed == RETURN_INCORRECT_PASSWORD {
+        pending.discard();
+        return Ok(changed);
+    }
+
+    // Verify even if the change looked like a failure: the password may
+    // have been changed anyway and must not get lost.
+    let verified = pty::run(pty::RunConfig {
+        command: remote_command(&rotation.login, &[VERIFY_COMMAND.to_string()]),
+        password: new,
+        prompt: rotation.prompt,
+        localized_prompts: rotation.localized_prompts,
+        script: None,
+        login_marker: None,
+        passphrases: rotation.passphrases,
+        hop_passwords: rotation.hop_passwords,
+        password_change: None,
+        stall_timeout: None,
+        env: rotation.env,
+        cols: None,
+        rows: None,
+        inherit_stdio: false,
+    })?;
+    let staged = pending.staged().display().to_string();
+    match verified {
+        0 => match pending.commit() {
+            Ok(()) => {
+                eprintln!("SSHPASS: password rotated");
+                Ok(0)
+            }
+            Err(e) => {
+                eprintln!(
+                    "SSHPASS: failed to save the new password: {e}; it is kept in \"{staged}\""
+                );
+                Ok(RETURN_ROTATION_UNVERIFIED)
+            }
+        },
+        RETURN_INCORRECT_PASSWORD => {
+            pending.discard();
+            eprintln!("SSHPASS: the new password does not work; the password file is unchanged");
+            Ok(if changed != 0 {
+                changed
+            } else {
+                RETURN_ROTATION_UNVERIFIED
+            })
+        }
+        _ => {
+            eprintln!("SSHPASS: could not verify the new password; it is kept in \"{staged}\"");
+            Ok(RETURN_ROTATION_UNVERIFIED)
+        }
+    }
+}
+
+/// Appends a remote command to the login command. ssh only allocates a
+/// terminal for a command when asked to, and `passwd` needs one.
+fn remote_command(login: &[String], remote: &[String]) -> Vec<OsString> {
+    let mut command: Vec<OsString> = login.iter().map(OsString::from).collect();
+    let is_ssh = command
+        .first()
+        .and_then(|program| std::path::Path::new(program).file_name())
+        .is_some_and(|name| name == "ssh");
+    if is_ssh {
+        command.insert(1, "-t".into());
+    }
+    command.extend(remote.iter().map(OsString::from));
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn ssh_gets_a_terminal() {
+        assert_eq!(
+            remote_command(
+                &strings(&["/usr/bin/ssh", "me@host"]),
+                &strings(&["passwd"])
+            ),
+            ["/usr/bin/ssh", "-t", "me@host", "passwd"]
+        );
+    }
+
+    #[test]
+    fn other_commands_are_left_alone() {
+        assert_eq!(
+            remote_command(&strings(&["mosh", "host", "--"]), &strings(&["passwd"])),
+            ["mosh", "host", "--", "passwd"]
+        );
+    }
+}
@@ -0,0 +1,453 @@
+use regex::bytes::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::password::{FileFormat, PasswordSource};
+
+/// Name under which the main password is available to `send-secret`.
+pub const PASSWORD_SECRET: &str = "password";
+
+/// Output kept around while waiting for an `expect` to match.
+const MAX_BUFFERED: usize = 64 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ScriptError {
+    #[error("failed to read script \"{path}\": {source}")]
+    Read { path: PathBuf, source: io::Error },
+    #[error("script line {line}: {message}")]
+    Parse { line: usize, message: String },
+    #[error("script line {line}: timed out waiting for /{pattern}/")]
+    Timeout { line: usize, pattern: String },
+    #[error("script line {line}: end of output while waiting for /{pattern}/")]
+    Eof { line: usize, pattern: String },
+}
+
+#[derive(Debug)]
+enum Statement {
+    Expect {
+        pattern: Regex,
+        timeout: Option<Duration>,
+    },
+    Send(Vec<u8>),
+    SendSecret(String),
+    Interact,
+    Exit(i32),
+}
+
+/// A parsed script file.
+///
+/// ```text
+/// secret pin env DEVICE_PIN
+/// expect /assword:/ timeout 10
+/// send-secret password
+/// expect /PIN:/
+/// send-secret pin
+/// expect /Press any key/
+/// send " "
+/// interact
+/// ```
+#[derive(Debug)]
+pub struct Script {
+    statements: Vec<(usize, Statement)>,
+    secrets: Vec<(String, PasswordSource)>,
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Self, ScriptError> {
+        let content = fs::read_to_string(path).map_err(|e| ScriptError::Read {
+            path: path.to_path_buf(),
+            source: e,
+        })?;
+        Self::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> Result<Self, ScriptError> {
+        let mut statements = Vec::new();
+        let mut secrets: Vec<(String, PasswordSource)> = Vec::new();
+        let mut used_secrets = Vec::new();
+
+        for (index, raw) in content.lines().enumerate() {
+            let line = index + 1;
+            let text = raw.trim();
+            if text.is_empty() || text.starts_with('#') {
+                continue;
+            }
+            let err = |message: String| ScriptError::Parse { line, message };
+            let (command, rest) = text
+                .split_once(char::is_whitespace)
+                .map_or((text, ""), |(c, r)| (c, r.trim_start()));
+
+            let statement = match command {
+                "expect" => parse_expect(rest).map_err(err)?,
+                "send" => Statement::Send(parse_quoted(rest).map_err(err)?),
+                "send-secret" => {
+                    let name = single_word(rest).map_err(err)?;
+                    used_secrets.push((line, name.to_string()));
+                    Statement::SendSecret(name.to_string())
+                }
+                "interact" => {
+                    no_arguments(rest).map_err(err)?;
+                    Statement::Interact
+                }
+                "exit" => {
+                    let code = single_word(rest).map_err(err)?;
+                    Statement::Exit(
+                        code.parse()
+                            .map_err(|_| err(format!("invalid exit code \"{code}\"")))?,
+                    )
+                }
+                "secret" => {
+                    let (name, source) = parse_secret(rest).map_err(err)?;
+                    if name == PASSWORD_SECRET || secrets.iter().any(|(n, _)| *n == name) {
+                        return Err(err(format!("secret \"{name}\" is already defined")));
+                    }
+                    secrets.push((name, source));
+                    continue;
+                }
+                other => return Err(err(format!("unknown command \"{other}\""))),
+            };
+            statements.push((line, statement));
+        }
+
+        for (line, name) in used_secrets {
+            if name != PASSWORD_SECRET && !secrets.iter().any(|(n, _)| *n == name) {
+                return Err(ScriptError::Parse {
+                    line,
+                    message: format!("secret \"{name}\" is not defined"),
+                });
+            }
+        }
+
+        Ok(Self {
+            statements,
+            secrets,
+        })
+    }
+
+    /// Whether the script sends the main password at any point.
+    pub fn uses_password(&self) -> bool {
+        self.statements
+            .iter()
+            .any(|(_, s)| matches!(s, Statement::SendSecret(n) if n == PASSWORD_SECRET))
+    }
+
+    /// Additional secrets declared with `secret NAME env|file|fd VALUE`.
+    pub fn secrets(&self) -> &[(String, PasswordSource)] {
+        &self.secrets
+    }
+}
+
+fn parse_expect(rest: &str) -> Result<Statement, String> {
+    let body = rest
+        .strip_prefix('/')
+        .ok_or("expect needs a /regex/ argument")?;
+    let mut pattern = String::new();
+    let mut chars = body.char_indices();
+    let mut end = None;
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, '/')) => pattern.push('/'),
+                Some((_, other)) => {
+                    pattern.push('\\');
+                    pattern.push(other);
+                }
+                None => break,
+            },
+            '/' => {
+                end = Some(i);
+                break;
+            }
+            _ => pattern.push(c),
+        }
+    }
+    let end = end.ok_or("unterminated /regex/")?;
+    let regex = Regex::new(&pattern).map_err(|e| format!("invalid regex: {e}"))?;
+
+    let timeout = match body[end + 1..].split_whitespace().collect::<Vec<_>>()[..] {
+        [] => None,
+        ["timeout", secs] => Some(Duration::from_secs(
+            secs.parse()
+                .map_err(|_| format!("invalid timeout \"{secs}\""))?,
+        )),
+        _ => return Err("expected \"timeout SECONDS\" after the regex".into()),
+    };
+
+    Ok(Statement::Expect {
+        pattern: regex,
+        timeout,
+    })
+}
+
+fn parse_quoted(rest: &str) -> Result<Vec<u8>, String> {
+    let body = rest
+        .strip_prefix('"')
+        .and_then(|r| r.strip_suffix('"'))
+        .ok_or("send needs a \"quoted\" argument")?;
+    let mut out = Vec::new();
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut utf8 = [0u8; 4];
+            out.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('r') => out.push(b'\r'),
+            Some('n') => out.push(b'\n'),
+            Some('t') => out.push(b'\t'),
+            Some('e') => out.push(0x1b),
+            Some('\\') => out.push(b'\\'),
+            Some('"') => out.push(b'"'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let byte = u8::from_str_radix(&hex, 16)
+                    .map_err(|_| format!("invalid escape \"\\x{hex}\""))?;
+                out.push(byte);
+            }
+            Some(other) => return Err(format!("invalid escape \"\\{other}\"")),
+            None => return Err("trailing backslash".into()),
+        }
+    }
+    Ok(out)
+}
+
+fn parse_secret(rest: &str) -> Result<(String, PasswordSource), String> {
+    let mut words = rest.splitn(3, char::is_whitespace);
+    let (Some(name), Some(kind), Some(value)) = (words.next(), words.next(), words.next()) else {
+        return Err("expected \"secret NAME env|file|fd VALUE\"".into());
+    };
+    let value = value.trim();
+    let source = match kind {
+        "env" => PasswordSource::Env(value.to_string()),
+        "file" => PasswordSource::File(PathBuf::from(value), FileFormat::default()),
+        #[cfg(unix)]
+        "fd" => PasswordSource::Fd(
+            value
+                .parse()
+                .map_err(|_| format!("invalid file descriptor \"{value}\""))?,
+        ),
+        other => return Err(format!("unknown secret source \"{other}\"")),
+    };
+    Ok((name.to_string(), source))
+}
+
+fn single_word(rest: &str) -> Result<&str, String> {
+    match rest.split_whitespace().collect::<Vec<_>>()[..] {
+        [word] => Ok(word),
+        _ => Err("expected exactly one argument".into()),
+    }
+}
+
+fn no_arguments(rest: &str) -> Result<(), String> {
+    if rest.is_empty() {
+        Ok(())
+    } else {
+        Err("unexpected arguments".into())
+    }
+}
+
+/// What the PTY loop has to do on behalf of the script.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    /// Write bytes to the child. `secret` output is not echoed back.
+    Send { data: Vec<u8>, secret: bool },
+    /// Hand the session over to the user.
+    Interact,
+    /// Close the session and exit with the given code.
+    Exit(i32),
+}
+
+/// Executes a [`Script`] against the output of the child.
+pub struct ScriptRunner {
+    script: Script,
+    secrets: HashMap<String, Vec<u8>>,
+    pc: usize,
+    buffer: Vec<u8>,
+    deadline: Option<Instant>,
+}
+
+impl ScriptRunner {
+    pub fn new(script: Script, secrets: HashMap<String, Vec<u8>>) -> Self {
+        Self {
+            script,
+            secrets,
+            pc: 0,
+            buffer: Vec::new(),
+            deadline: None,
+        }
+    }
+
+    /// Feeds child output and runs statements until the script blocks on
+    /// an `expect` or ends.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Action> {
+        if self.is_done() {
+            return Vec::new();
+        }
+        self.buffer.extend_from_slice(data);
+        if self.buffer.len() > MAX_BUFFERED {
+            self.buffer.drain(..self.buffer.len() - MAX_BUFFERED);
+        }
+        self.advance()
+    }
+
+    /// Runs statements until the script blocks on an `expect` or ends.
+    pub fn advance(&mut self) -> Vec<Action> {
+        let mut actions = Vec::new();
+        while let Some((_, statement)) = self.script.statements.get(self.pc) {
+            match statement {
+                Statement::Expect { pattern, timeout } => {
+                    let Some(found) = pattern.find(&self.buffer) else {
+                        if self.deadline.is_none() {
+                            self.deadline = timeout.map(|t| Instant::now() + t);
+                        }
+                        return actions;
+                    };
+                    self.buffer.drain(..found.end());
+                    self.deadline = None;
+                }
+                Statement::Send(data) => actions.push(Action::Send {
+                    data: data.clone(),
+                    secret: false,
+                }),
+                Statement::SendSecret(name) => {
+                    let mut data = self.secrets.get(name).cloned().unwrap_or_default();
+                    data.push(b'\n');
+                    actions.push(Action::Send { data, secret: true });
+                }
+                Statement::Interact => {
+                    self.pc = self.script.statements.len();
+                    actions.push(Action::Interact);
+                    return actions;
+                }
+                Statement::Exit(code) => {
+                    self.pc = self.script.statements.len();
+                    actions.push(Action::Exit(*code));
+                    return actions;
+                }
+            }
+            self.pc += 1;
+        }
+        actions
+    }
+
+    /// The point in time at which the pending `expect` gives up.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Whether the script has reached `interact`, `exit` or its end.
+    ///
+    /// A script that ends without `interact` keeps passing output through
+    /// until the command exits, but does not forward stdin.
+    pub fn is_done(&self) -> bool {
+        self.pc >= self.script.statements.len()
+    }
+
+    /// Describes the pending `expect` after a timeout or end of output.
+    pub fn failure(&self, eof: bool) -> Option<ScriptError> {
+        let (line, Statement::Expect { pattern, .. }) = self.script.statements.get(self.pc)? else {
+            return None;
+        };
+        let (line, pattern) = (*line, pattern.as_str().to_string());
+        Some(if eof {
+            ScriptError::Eof { line, pattern }
+        } else {
+            ScriptError::Timeout { line, pattern }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runner(script: &str) -> ScriptRunner {
+        let mut secrets = HashMap::new();
+        secrets.insert(PASSWORD_SECRET.to_string(), b"hunter2".to_vec());
+        ScriptRunner::new(Script::parse(script).unwrap(), secrets)
+    }
+
+    fn sent(data: &[u8], secret: bool) -> Action {
+        Action::Send {
+            data: data.to_vec(),
+            secret,
+        }
+    }
+
+    #[test]
+    fn parses_all_commands() {
+        let script = Script::parse(
+            "# login\nsecret pin env PIN\nexpect /a\\/b/ timeout 5\nsend \"y\\r\"\nsend-secret pin\ninteract\nexit 3\n",
+        )
+        .unwrap();
+        assert_eq!(script.statements.len(), 5);
+        assert_eq!(script.secrets().len(), 1);
+        assert!(!script.uses_password());
+    }
+
+    #[test]
+    fn rejects_undefined_secret() {
+        let err = Script::parse("send-secret pin\n").unwrap_err();
+        assert!(matches!(err, ScriptError::Parse { line: 1, .. }));
+    }
+
+    #[test]
+    fn rejects_unknown_command() {
+        let err = Script::parse("expect /x/\nfrobnicate\n").unwrap_err();
+        assert!(matches!(err, ScriptError::Parse { line: 2, .. }));
+    }
+
+    #[test]
+    fn rejects_bad_escape() {
+        assert!(Script::parse("send \"\\q\"\n").is_err());
+        assert!(Script::parse("send unquoted\n").is_err());
+    }
+
+    #[test]
+    fn send_escapes() {
+        assert_eq!(
+            parse_quoted("\"a\\r\\n\\x1b\\\"\"").unwrap(),
+            b"a\r\n\x1b\""
+        );
+    }
+
+    #[test]
+    fn waits_for_expect_across_chunks() {
+        let mut r = runner("expect /assword:/\nsend-secret password\n");
+        assert!(r.advance().is_empty());
+        assert!(r.feed(b"user@host's pass").is_empty());
+        assert_eq!(r.feed(b"word: "), vec![sent(b"hunter2\n", true)]);
+        assert!(r.is_done());
+    }
+
+    #[test]
+    fn consumes_output_up_to_match() {
+        let mut r = runner("expect /> /\nsend \"1\\r\"\nexpect /> /\nexit 4\n");
+        assert_eq!(r.feed(b"menu> "), vec![sent(b"1\r", false)]);
+        assert!(r.feed(b"working").is_empty());
+        assert_eq!(r.feed(b"\r\nmenu> "), vec![Action::Exit(4)]);
+    }
+
+    #[test]
+    fn interact_stops_script() {
+        let mut r = runner("send \"x\"\ninteract\nsend \"never\"\n");
+        assert_eq!(r.advance(), vec![sent(b"x", false), Action::Interact]);
+        assert!(r.is_done());
+        assert!(r.feed(b"anything").is_empty());
+    }
+
+    #[test]
+    fn deadline_set_while_waiting() {
+        let mut r = runner("expect /never/ timeout 10\n");
+        r.advance();
+        assert!(r.deadline().is_some());
+        assert!(matches!(
+            r.failure(false),
+            Some(ScriptError::Timeout { line: 1, .. })
+        ));
+    }
+}
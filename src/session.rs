@@ -0,0 +1,604 @@
+use regex::bytes::Regex;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::classify::{Classifier, Event};
+use crate::hops::{self, HopPasswords};
+use crate::matcher::{LastLine, Matcher};
+use crate::passphrase::{self, Passphrases};
+use crate::passwd::{self, ChangeEvent, PasswordChange};
+use crate::prompts;
+use crate::pty::{
+    RETURN_CONNECTION_FAILED, RETURN_HOST_KEY_CHANGED, RETURN_HOST_KEY_UNKNOWN,
+    RETURN_INCORRECT_PASSPHRASE, RETURN_INCORRECT_PASSWORD, RETURN_PASSWORD_CHANGE_REQUIRED,
+    RETURN_PASSWORD_REJECTED, RETURN_SCRIPT_FAILED, RETURN_STALLED,
+};
+use crate::script::{Action, ScriptRunner};
+
+/// Matches a typical shell prompt at the end of the current line.
+pub const DEFAULT_LOGIN_MARKER: &str = r"[$#>%] ?$";
+
+/// Something the session decided to do about the output of the child.
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    /// A prompt or message was recognised.
+    Matched(String),
+    /// Write to the child.
+    Send { data: Vec<u8>, what: Sent },
+    /// Hand the session over to the user.
+    Interact,
+    /// Output to pass on to the user.
+    Output(Vec<u8>),
+    /// Message for the user.
+    Notice(String),
+    /// The password change went through.
+    PasswordChanged,
+    /// Exit with this code once the command is gone.
+    Exit(i32),
+    /// Close the session and exit with this code.
+    Close(i32),
+}
+
+/// What a [`Step::Send`] answers, for messages that must not show secrets.
+#[derive(Debug, PartialEq)]
+pub enum Sent {
+    Password,
+    HopPassword(String),
+    Passphrase(String),
+    ChangeAnswer,
+    Script { secret: bool },
+}
+
+impl fmt::Display for Sent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sent::Password => write!(f, "password"),
+            Sent::HopPassword(host) => write!(f, "password for {host}"),
+            Sent::Passphrase(key) => write!(f, "passphrase for {key}"),
+            Sent::ChangeAnswer => write!(f, "password change answer"),
+            Sent::Script { secret: true } => write!(f, "script secret"),
+            Sent::Script { secret: false } => write!(f, "script text"),
+        }
+    }
+}
+
+pub struct SessionConfig {
+    pub password: Vec<u8>,
+    pub prompt: String,
+    /// Also take the prompts of localised PAM modules for password prompts.
+    pub localized_prompts: bool,
+    pub script: Option<ScriptRunner>,
+    pub login_marker: Option<Regex>,
+    pub passphrases: Passphrases,
+    pub hop_passwords: HopPasswords,
+    pub password_change: Option<PasswordChange>,
+    /// Give up if the login dialog shows no progress for this long.
+    pub stall_timeout: Option<Duration>,
+}
+
+/// Decides how to answer the output of the child. Knows nothing about
+/// PTYs, so that recorded output can be replayed through it.
+pub struct Session {
+    password: Vec<u8>,
+    script: Option<ScriptRunner>,
+    login_marker: Option<Regex>,
+    passphrases: Passphrases,
+    hop_passwords: HopPasswords,
+    password_change: Option<PasswordChange>,
+    pw_matcher: Matcher,
+    localized_prompts: bool,
+    hk_matcher: Matcher,
+    hkc_matcher: Matcher,
+    password_sent: bool,
+    suppress_until_newline: bool,
+    last_line: LastLine,
+    classifier: Classifier,
+    answered_keys: Vec<String>,
+    /// Hosts whose `user@host's password:` prompt was answered.
+    answered_hosts: Vec<String>,
+    stall_timeout: Option<Duration>,
+    last_output: Instant,
+    /// Set once any prompt was answered.
+    answered: bool,
+}
+
+impl Session {
+    pub fn new(config: SessionConfig) -> Self {
+        Self {
+            password: config.password,
+            pw_matcher: Matcher::new(&config.prompt),
+            localized_prompts: config.localized_prompts,
+            script: config.script,
+            login_marker: config.login_marker,
+            passphrases: config.passphrases,
+            hop_passwords: config.hop_passwords,
+            password_change: config.password_change,
+            hk_matcher: Matcher::new("The authenticity of host "),
+            hkc_matcher: Matcher::new("differs from the key for the IP address"),
+            password_sent: false,
+            suppress_until_newline: false,
+            last_line: LastLine::new(),
+            classifier: Classifier::new(),
+            answered_keys: Vec::new(),
+            answered_hosts: Vec::new(),
+            stall_timeout: config.stall_timeout,
+            last_output: Instant::now(),
+            answered: false,
+        }
+    }
+
+    /// Steps to take before any output, for scripts that start by sending.
+    pub fn start(&mut self) -> Vec<Step> {
+        let mut steps = Vec::new();
+        if let Some(ref mut runner) = self.script {
+            let actions = runner.advance();
+            script_steps(actions, &mut steps, &mut self.suppress_until_newline);
+        }
+        steps
+    }
+
+    /// When a pending script `expect` times out or the login dialog stalls.
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.script {
+            Some(ref runner) => runner.deadline(),
+            None => self.stall_deadline(),
+        }
+    }
+
+    /// Only an unanswered partial line can be a prompt waiting for input.
+    /// Scripts have their own timeouts; once a prompt was answered, a quiet
+    /// command is nothing unusual.
+    fn stall_deadline(&self) -> Option<Instant> {
+        let waiting = !self.answered && !self.last_line.as_bytes().trim_ascii().is_empty();
+        let timeout = self.stall_timeout.filter(|_| waiting)?;
+        Some(self.last_output + timeout)
+    }
+
+    /// Feeds child output. Nothing follows a [`Step::Close`].
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Step> {
+        let mut steps = Vec::new();
+        let window = self.last_line.prepend_to(data);
+        self.last_line.feed(data);
+        self.last_output = Instant::now();
+
+        let in_login = self.script.is_none() && self.classifier.in_login_phase();
+        let change_event = match self.password_change {
+            Some(ref mut change) if in_login || (self.script.is_none() && change.in_session()) => {
+                change.feed(&window)
+            }
+            _ => None,
+        };
+        let change_required =
+            in_login && self.password_change.is_none() && passwd::detect_prompt(&window).is_some();
+
+        if let Some(ref mut runner) = self.script {
+            let actions = runner.feed(data);
+            if actions
+                .iter()
+                .any(|a| matches!(a, Action::Send { secret: true, .. }))
+            {
+                self.classifier.password_sent();
+            }
+            if script_steps(actions, &mut steps, &mut self.suppress_until_newline) {
+                return steps;
+            }
+        } else if change_required {
+            steps.push(Step::Matched("password change prompt".into()));
+            steps.push(Step::Close(RETURN_PASSWORD_CHANGE_REQUIRED));
+            return steps;
+        } else if let Some(event) = change_event {
+            match event {
+                ChangeEvent::Answer(payload) => {
+                    steps.push(Step::Matched("password change prompt".into()));
+                    steps.push(Step::Send {
+                        data: payload,
+                        what: Sent::ChangeAnswer,
+                    });
+                    self.suppress_until_newline = true;
+                    self.last_line.clear();
+                    self.pw_matcher.reset();
+                    self.classifier.password_sent();
+                    self.answered = true;
+                }
+                ChangeEvent::Changed => {
+                    steps.push(Step::Notice("password changed".into()));
+                    steps.push(Step::PasswordChanged);
+                }
+                ChangeEvent::Rejected(reason) => {
+                    steps.push(Step::Notice(format!("new password rejected: {reason}")));
+                    steps.push(Step::Close(RETURN_PASSWORD_REJECTED));
+                    return steps;
+                }
+            }
+        } else if self.pw_matcher.feed(data)
+            || (self.localized_prompts && prompts::is_localized_prompt(&window))
+        {
+            steps.push(Step::Matched("password prompt".into()));
+            // Each hop of a ProxyJump chain asks once; a prompt that names
+            // no host can only be the destination asking again.
+            let host = hops::prompt_host(&window);
+            let repeated = match host {
+                Some(ref host) => self.answered_hosts.contains(host),
+                None => self.password_sent,
+            };
+            if repeated {
+                steps.push(Step::Close(RETURN_INCORRECT_PASSWORD));
+                return steps;
+            }
+            let hop = host
+                .as_deref()
+                .and_then(|h| Some((h, self.hop_passwords.for_host(h)?)));
+            let (password, what) = match hop {
+                Some((host, password)) => (password, Sent::HopPassword(host.to_string())),
+                None => (&self.password[..], Sent::Password),
+            };
+            steps.push(Step::Send {
+                data: [password, b"\n"].concat(),
+                what,
+            });
+            self.answered_hosts.extend(host);
+            self.password_sent = true;
+            self.answered = true;
+            self.suppress_until_newline = true;
+            self.classifier.password_sent();
+            self.pw_matcher.reset();
+        }
+
+        if self.script.is_none()
+            && !self.passphrases.is_empty()
+            && let Some(key) = passphrase::prompt_key(&window)
+        {
+            steps.push(Step::Matched(format!("passphrase prompt for {key}")));
+            if self.answered_keys.contains(&key) {
+                steps.push(Step::Close(RETURN_INCORRECT_PASSPHRASE));
+                return steps;
+            }
+            if let Some(secret) = self.passphrases.for_key(&key) {
+                steps.push(Step::Send {
+                    data: [secret, b"\n"].concat(),
+                    what: Sent::Passphrase(key.clone()),
+                });
+                self.suppress_until_newline = true;
+                self.last_line.clear();
+                self.answered_keys.push(key);
+                self.answered = true;
+                self.classifier.password_sent();
+            }
+        }
+
+        if self.script.is_none() && self.hk_matcher.feed(data) {
+            steps.push(Step::Matched("unknown host key".into()));
+            steps.push(Step::Close(RETURN_HOST_KEY_UNKNOWN));
+            return steps;
+        }
+
+        if self.script.is_none() && self.hkc_matcher.feed(data) {
+            steps.push(Step::Matched("changed host key".into()));
+            steps.push(Step::Close(RETURN_HOST_KEY_CHANGED));
+            return steps;
+        }
+
+        if self.suppress_until_newline {
+            if let Some(pos) = data.iter().position(|&b| b == b'\n') {
+                self.suppress_until_newline = false;
+                let remaining = &data[pos + 1..];
+                if !remaining.is_empty() {
+                    steps.push(Step::Output(remaining.to_vec()));
+                }
+            }
+        } else {
+            steps.push(Step::Output(data.to_vec()));
+        }
+
+        let mut failure = None;
+        for event in self.classifier.feed(data) {
+            match event {
+                Event::Fail(code) => failure = failure.or(Some(code)),
+                Event::Warn(message) => steps.push(Step::Notice(format!("warning: {message}"))),
+            }
+        }
+        if let Some(code) = failure {
+            steps.push(Step::Matched("failure message".into()));
+            steps.push(Step::Close(code));
+            return steps;
+        }
+
+        if let Some(ref marker) = self.login_marker
+            && marker.is_match(&window)
+        {
+            self.classifier.logged_in();
+            steps.push(Step::Matched("login marker".into()));
+            steps.push(Step::Close(0));
+            return steps;
+        }
+        steps
+    }
+
+    /// Steps after the output ended (`eof`) or the [`Session::deadline`]
+    /// passed.
+    pub fn finish(&mut self, eof: bool) -> Vec<Step> {
+        if let Some(err) = self.script.as_ref().and_then(|r| r.failure(eof)) {
+            vec![
+                Step::Notice(err.to_string()),
+                Step::Close(RETURN_SCRIPT_FAILED),
+            ]
+        } else if !eof {
+            match self.stall_deadline() {
+                Some(deadline) if deadline <= Instant::now() => vec![
+                    Step::Notice(format!(
+                        "stalled at: \"{}\"",
+                        String::from_utf8_lossy(self.last_line.as_bytes()).escape_debug()
+                    )),
+                    Step::Close(RETURN_STALLED),
+                ],
+                _ => Vec::new(),
+            }
+        } else if self.login_marker.is_some() {
+            vec![Step::Exit(if self.password_sent {
+                RETURN_INCORRECT_PASSWORD
+            } else {
+                RETURN_CONNECTION_FAILED
+            })]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Settles a pending password change; see [`PasswordChange::settle`].
+    pub fn settle(&mut self) -> Option<String> {
+        self.password_change.as_mut()?.settle()
+    }
+}
+
+/// Turns script actions into steps. Returns true if the session was closed.
+fn script_steps(
+    actions: Vec<Action>,
+    steps: &mut Vec<Step>,
+    suppress_until_newline: &mut bool,
+) -> bool {
+    for action in actions {
+        match action {
+            Action::Send { data, secret } => {
+                steps.push(Step::Send {
+                    data,
+                    what: Sent::Script { secret },
+                });
+                *suppress_until_newline |= secret;
+            }
+            Action::Interact => steps.push(Step::Interact),
+            Action::Exit(code) => {
+                steps.push(Step::Close(code));
+                return true;
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(login_marker: Option<&str>) -> Session {
+        Session::new(config(login_marker))
+    }
+
+    fn config(login_marker: Option<&str>) -> SessionConfig {
+        SessionConfig {
+            password: b"hunter2".to_vec(),
+            prompt: "assword:".into(),
+            localized_prompts: false,
+            script: None,
+            login_marker: login_marker.map(|m| Regex::new(m).unwrap()),
+            passphrases: Passphrases::default(),
+            hop_passwords: HopPasswords::default(),
+            password_change: None,
+            stall_timeout: None,
+        }
+    }
+
+    fn stalling_session() -> Session {
+        let mut s = session(None);
+        s.stall_timeout = Some(Duration::ZERO);
+        s
+    }
+
+    #[test]
+    fn answers_the_password_prompt_once() {
+        let mut s = session(None);
+        assert_eq!(
+            s.feed(b"user@host's password: "),
+            [
+                Step::Matched("password prompt".into()),
+                Step::Send {
+                    data: b"hunter2\n".to_vec(),
+                    what: Sent::Password,
+                },
+            ]
+        );
+        assert_eq!(
+            s.feed(b"\r\nWelcome\r\n"),
+            [Step::Output(b"Welcome\r\n".to_vec())]
+        );
+        assert_eq!(
+            s.feed(b"user@host's password: "),
+            [
+                Step::Matched("password prompt".into()),
+                Step::Close(RETURN_INCORRECT_PASSWORD),
+            ]
+        );
+    }
+
+    #[test]
+    fn each_hop_is_answered_once() {
+        let mut hop_passwords = HopPasswords::default();
+        hop_passwords.add("bastion".into(), b"jump".to_vec());
+        let mut s = Session::new(SessionConfig {
+            hop_passwords,
+            ..config(None)
+        });
+        assert_eq!(
+            s.feed(b"me@bastion's password: ")[1],
+            Step::Send {
+                data: b"jump\n".to_vec(),
+                what: Sent::HopPassword("bastion".into()),
+            }
+        );
+        assert_eq!(
+            s.feed(b"\r\nme@target's password: ")[1],
+            Step::Send {
+                data: b"hunter2\n".to_vec(),
+                what: Sent::Password,
+            }
+        );
+        assert_eq!(
+            s.feed(b"\r\nme@target's password: ").last(),
+            Some(&Step::Close(RETURN_INCORRECT_PASSWORD))
+        );
+    }
+
+    #[test]
+    fn localized_prompts_on_request() {
+        let mut s = session(None);
+        assert_eq!(
+            s.feed(b"Passwort: "),
+            [Step::Output(b"Passwort: ".to_vec())]
+        );
+
+        let mut s = Session::new(SessionConfig {
+            localized_prompts: true,
+            ..config(None)
+        });
+        assert_eq!(
+            s.feed(b"Passwort: ")[0],
+            Step::Matched("password prompt".into())
+        );
+        assert_eq!(s.feed(b"\r\nMot de passe : ").len(), 2);
+    }
+
+    #[test]
+    fn localized_change_prompt_is_not_the_password_prompt() {
+        let mut s = Session::new(SessionConfig {
+            localized_prompts: true,
+            ..config(None)
+        });
+        assert_eq!(
+            s.feed(b"Passwort: ")[1],
+            Step::Send {
+                data: b"hunter2\n".to_vec(),
+                what: Sent::Password,
+            }
+        );
+        assert_eq!(
+            s.feed(b"\r\nSie m\xc3\xbcssen Ihr Passwort sofort \xc3\xa4ndern.\r\nNeues Passwort: "),
+            [
+                Step::Matched("password change prompt".into()),
+                Step::Close(RETURN_PASSWORD_CHANGE_REQUIRED),
+            ]
+        );
+    }
+
+    #[test]
+    fn login_marker_and_eof() {
+        let mut s = session(Some(r"\$ $"));
+        s.feed(b"password: ");
+        assert_eq!(s.feed(b"\r\n").len(), 0);
+        assert_eq!(s.feed(b"me@host:~$ ").last(), Some(&Step::Close(0)));
+
+        let mut s = session(Some(r"\$ $"));
+        s.feed(b"password: ");
+        assert_eq!(s.finish(true), [Step::Exit(RETURN_INCORRECT_PASSWORD)]);
+    }
+
+    #[test]
+    fn host_key_prompt_closes_without_output() {
+        let mut s = session(None);
+        assert_eq!(
+            s.feed(b"The authenticity of host 'h (1.2.3.4)' can't be established.\r\n"),
+            [
+                Step::Matched("unknown host key".into()),
+                Step::Close(RETURN_HOST_KEY_UNKNOWN),
+            ]
+        );
+    }
+
+    #[test]
+    fn stall_reports_the_last_line() {
+        let mut s = stalling_session();
+        s.feed(b"Smartcard inserted\r\nEnter PIN for token: ");
+        assert_eq!(
+            s.finish(false),
+            [
+                Step::Notice("stalled at: \"Enter PIN for token: \"".into()),
+                Step::Close(RETURN_STALLED),
+            ]
+        );
+    }
+
+    #[test]
+    fn no_stall_once_answered() {
+        let mut s = stalling_session();
+        s.feed(b"password: ");
+        assert!(s.deadline().is_none());
+        assert!(s.finish(false).is_empty());
+    }
+
+    #[test]
+    fn no_stall_without_a_prompt() {
+        let mut s = stalling_session();
+        assert!(s.deadline().is_none());
+        s.feed(b"Working...\r\n");
+        assert!(s.deadline().is_none());
+        assert!(s.finish(false).is_empty());
+    }
+
+    #[test]
+    fn key_login_output_is_not_classified() {
+        let mut s = session(None);
+        for _ in 0..50 {
+            s.feed(b"motd line\r\n");
+        }
+        assert!(
+            !s.feed(b"curl: (7) Failed to connect: Connection refused\r\n")
+                .iter()
+                .any(|step| matches!(step, Step::Close(_)))
+        );
+    }
+
+    #[test]
+    fn passphrase_answer_ends_the_login_phase() {
+        let mut passphrases = Passphrases::default();
+        passphrases.add(None, b"open sesame".to_vec());
+        let mut s = Session::new(SessionConfig {
+            passphrases,
+            ..config(None)
+        });
+        assert!(matches!(
+            s.feed(b"Enter passphrase for key '/home/me/.ssh/id_ed25519': ")[1],
+            Step::Send {
+                what: Sent::Passphrase(_),
+                ..
+            }
+        ));
+        for _ in 0..10 {
+            s.feed(b"\r\nmotd line");
+        }
+        assert!(
+            !s.feed(b"\r\ncurl: (7) Failed to connect: Connection refused\r\n")
+                .iter()
+                .any(|step| matches!(step, Step::Close(_)))
+        );
+    }
+
+    #[test]
+    fn change_prompt_after_the_login_phase_is_session_output() {
+        let mut s = session(None);
+        for _ in 0..50 {
+            s.feed(b"motd line\r\n");
+        }
+        assert_eq!(
+            s.feed(b"Neues Passwort: "),
+            [Step::Output(b"Neues Passwort: ".to_vec())]
+        );
+    }
+}
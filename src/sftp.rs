@@ -0,0 +1,454 @@
+use std::cell::RefCell;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::BitOr;
+use std::path::{Path, PathBuf};
+
+use crate::connection::{Channel, Event};
+use crate::transport::{Decoder, Encoder};
+
+pub const FXP_INIT: u8 = 1;
+pub const FXP_VERSION: u8 = 2;
+pub const FXP_OPEN: u8 = 3;
+pub const FXP_CLOSE: u8 = 4;
+pub const FXP_READ: u8 = 5;
+pub const FXP_WRITE: u8 = 6;
+pub const FXP_FSTAT: u8 = 8;
+pub const FXP_SETSTAT: u8 = 9;
+pub const FXP_OPENDIR: u8 = 11;
+pub const FXP_READDIR: u8 = 12;
+pub const FXP_MKDIR: u8 = 14;
+pub const FXP_STAT: u8 = 17;
+pub const FXP_STATUS: u8 = 101;
+pub const FXP_HANDLE: u8 = 102;
+pub const FXP_DATA: u8 = 103;
+pub const FXP_NAME: u8 = 104;
+pub const FXP_ATTRS: u8 = 105;
+
+pub const FX_OK: u32 = 0;
+pub const FX_EOF: u32 = 1;
+pub const FX_NO_SUCH_FILE: u32 = 2;
+pub const FX_PERMISSION_DENIED: u32 = 3;
+
+const VERSION: u32 = 3;
+const ATTR_SIZE: u32 = 0x1;
+const ATTR_UIDGID: u32 = 0x2;
+const ATTR_PERMISSIONS: u32 = 0x4;
+const ATTR_ACMODTIME: u32 = 0x8;
+const ATTR_EXTENDED: u32 = 0x8000_0000;
+/// The most data asked for or sent in one request; servers accept this
+/// much at least.
+const MAX_CHUNK: usize = 32 * 1024;
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+
+/// Attributes of a remote file; what the server did not send is `None`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FileStat {
+    pub size: Option<u64>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Mode including the file type bits.
+    pub perm: Option<u32>,
+    pub atime: Option<u64>,
+    pub mtime: Option<u64>,
+}
+
+impl FileStat {
+    pub fn is_dir(&self) -> bool {
+        self.perm.is_some_and(|perm| perm & S_IFMT == S_IFDIR)
+    }
+
+    pub fn decode(d: &mut Decoder) -> io::Result<Self> {
+        let flags = d.u32()?;
+        let mut stat = Self::default();
+        if flags & ATTR_SIZE != 0 {
+            stat.size = Some(d.u64()?);
+        }
+        if flags & ATTR_UIDGID != 0 {
+            stat.uid = Some(d.u32()?);
+            stat.gid = Some(d.u32()?);
+        }
+        if flags & ATTR_PERMISSIONS != 0 {
+            stat.perm = Some(d.u32()?);
+        }
+        if flags & ATTR_ACMODTIME != 0 {
+            stat.atime = Some(d.u32()?.into());
+            stat.mtime = Some(d.u32()?.into());
+        }
+        if flags & ATTR_EXTENDED != 0 {
+            for _ in 0..d.u32()? {
+                d.string()?;
+                d.string()?;
+            }
+        }
+        Ok(stat)
+    }
+
+    /// Appends the attributes; owner and times only go as pairs.
+    pub fn encode(&self, e: Encoder) -> Encoder {
+        let owner = self.uid.zip(self.gid);
+        let times = self.atime.zip(self.mtime);
+        let mut flags = 0;
+        if self.size.is_some() {
+            flags |= ATTR_SIZE;
+        }
+        if owner.is_some() {
+            flags |= ATTR_UIDGID;
+        }
+        if self.perm.is_some() {
+            flags |= ATTR_PERMISSIONS;
+        }
+        if times.is_some() {
+            flags |= ATTR_ACMODTIME;
+        }
+        let mut e = e.u32(flags);
+        if let Some(size) = self.size {
+            e = e.u64(size);
+        }
+        if let Some((uid, gid)) = owner {
+            e = e.u32(uid).u32(gid);
+        }
+        if let Some(perm) = self.perm {
+            e = e.u32(perm);
+        }
+        if let Some((atime, mtime)) = times {
+            // SFTP version 3 has 32-bit times.
+            e = e.u32(atime as u32).u32(mtime as u32);
+        }
+        e
+    }
+}
+
+/// SSH_FXF_* flags of an open request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpenFlags(pub u32);
+
+impl OpenFlags {
+    pub const READ: Self = Self(0x01);
+    pub const WRITE: Self = Self(0x02);
+    pub const CREATE: Self = Self(0x08);
+    pub const TRUNCATE: Self = Self(0x10);
+}
+
+impl BitOr for OpenFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+pub fn path_bytes(path: &Path) -> Vec<u8> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes().to_vec()
+    }
+    #[cfg(not(unix))]
+    {
+        path.to_string_lossy().replace('\\', "/").into_bytes()
+    }
+}
+
+pub fn bytes_path(bytes: &[u8]) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+/// The io::Error for an SSH_FXP_STATUS that is not OK.
+pub fn status_error(code: u32, message: String) -> io::Error {
+    let kind = match code {
+        FX_NO_SUCH_FILE => io::ErrorKind::NotFound,
+        FX_PERMISSION_DENIED => io::ErrorKind::PermissionDenied,
+        FX_EOF => io::ErrorKind::UnexpectedEof,
+        _ => io::ErrorKind::Other,
+    };
+    let message = if message.is_empty() {
+        format!("sftp status {code}")
+    } else {
+        message
+    };
+    io::Error::new(kind, message)
+}
+
+struct Inner {
+    channel: Channel,
+    buf: Vec<u8>,
+    next_id: u32,
+}
+
+impl Inner {
+    fn read_packet(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(len) = self.buf.get(..4) {
+                let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+                if self.buf.len() >= 4 + len {
+                    let packet = self.buf[4..4 + len].to_vec();
+                    self.buf.drain(..4 + len);
+                    return Ok(packet);
+                }
+            }
+            match self.channel.poll(None)? {
+                Some(Event::Data(data)) => self.buf.extend(data),
+                Some(Event::Eof | Event::Closed) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the sftp server went away",
+                    ));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// An SFTP version 3 client, one request at a time.
+pub struct Sftp {
+    inner: RefCell<Inner>,
+}
+
+impl Sftp {
+    /// Starts the sftp subsystem on `channel`.
+    pub fn new(mut channel: Channel) -> io::Result<Self> {
+        channel.subsystem("sftp")?;
+        let init = Encoder::default().string(Encoder::new(FXP_INIT).u32(VERSION).into_bytes());
+        channel.write(&init.into_bytes())?;
+        let mut inner = Inner {
+            channel,
+            buf: Vec::new(),
+            next_id: 0,
+        };
+        let packet = inner.read_packet()?;
+        if packet.first() != Some(&FXP_VERSION) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no sftp version from the server",
+            ));
+        }
+        Ok(Self {
+            inner: RefCell::new(inner),
+        })
+    }
+
+    /// Sends a request and gives the type and body of the response.
+    fn call(&self, kind: u8, body: Encoder) -> io::Result<(u8, Vec<u8>)> {
+        let mut inner = self.inner.borrow_mut();
+        let id = inner.next_id;
+        inner.next_id = id.wrapping_add(1);
+        let request = Encoder::new(kind).u32(id).raw(&body.into_bytes());
+        let framed = Encoder::default().string(request.into_bytes());
+        inner.channel.write(&framed.into_bytes())?;
+        loop {
+            let packet = inner.read_packet()?;
+            let mut d = Decoder::new(&packet);
+            let (kind, reply_id) = (d.byte()?, d.u32()?);
+            if reply_id == id {
+                return Ok((kind, packet[5..].to_vec()));
+            }
+        }
+    }
+
+    /// A request answered by a status only.
+    fn call_status(&self, kind: u8, body: Encoder) -> io::Result<()> {
+        let (reply, body) = self.call(kind, body)?;
+        expect(FXP_STATUS, reply, &body)
+    }
+
+    pub fn stat(&self, path: &Path) -> io::Result<FileStat> {
+        let (reply, body) = self.call(FXP_STAT, Encoder::default().string(path_bytes(path)))?;
+        expect(FXP_ATTRS, reply, &body)?;
+        FileStat::decode(&mut Decoder::new(&body))
+    }
+
+    pub fn setstat(&self, path: &Path, stat: FileStat) -> io::Result<()> {
+        let body = stat.encode(Encoder::default().string(path_bytes(path)));
+        self.call_status(FXP_SETSTAT, body)
+    }
+
+    pub fn mkdir(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let stat = FileStat {
+            perm: Some(mode),
+            ..FileStat::default()
+        };
+        let body = stat.encode(Encoder::default().string(path_bytes(path)));
+        self.call_status(FXP_MKDIR, body)
+    }
+
+    pub fn open(&self, path: &Path) -> io::Result<File<'_>> {
+        self.open_mode(path, OpenFlags::READ, None)
+    }
+
+    /// Opens `path`, giving it `mode` if it is created.
+    pub fn open_mode(
+        &self,
+        path: &Path,
+        flags: OpenFlags,
+        mode: Option<u32>,
+    ) -> io::Result<File<'_>> {
+        let stat = FileStat {
+            perm: mode,
+            ..FileStat::default()
+        };
+        let body = stat.encode(Encoder::default().string(path_bytes(path)).u32(flags.0));
+        let handle = self.handle(FXP_OPEN, body)?;
+        Ok(File {
+            sftp: self,
+            handle,
+            offset: 0,
+        })
+    }
+
+    fn handle(&self, kind: u8, body: Encoder) -> io::Result<Vec<u8>> {
+        let (reply, body) = self.call(kind, body)?;
+        expect(FXP_HANDLE, reply, &body)?;
+        Ok(Decoder::new(&body).string()?.to_vec())
+    }
+
+    fn close(&self, handle: &[u8]) -> io::Result<()> {
+        self.call_status(FXP_CLOSE, Encoder::default().string(handle))
+    }
+
+    /// The entries of the directory `path` but `.` and `..`, as paths in
+    /// it.
+    pub fn readdir(&self, path: &Path) -> io::Result<Vec<(PathBuf, FileStat)>> {
+        let handle = self.handle(FXP_OPENDIR, Encoder::default().string(path_bytes(path)))?;
+        let mut entries = Vec::new();
+        let result = loop {
+            let (reply, body) = match self.call(FXP_READDIR, Encoder::default().string(&handle)) {
+                Ok(r) => r,
+                Err(e) => break Err(e),
+            };
+            match names(reply, &body) {
+                Ok(Some(names)) => entries.extend(
+                    names
+                        .into_iter()
+                        .filter(|(name, _)| name != b"." && name != b"..")
+                        .map(|(name, stat)| (path.join(bytes_path(&name)), stat)),
+                ),
+                Ok(None) => break Ok(entries),
+                Err(e) => break Err(e),
+            }
+        };
+        self.close(&handle)?;
+        result
+    }
+}
+
+/// Names with their attributes, as in an SSH_FXP_NAME reply.
+type Names = Vec<(Vec<u8>, FileStat)>;
+
+/// The names of an SSH_FXP_NAME reply, or None at the end of a directory.
+fn names(reply: u8, body: &[u8]) -> io::Result<Option<Names>> {
+    let mut d = Decoder::new(body);
+    if reply == FXP_STATUS && d.u32()? == FX_EOF {
+        return Ok(None);
+    }
+    expect(FXP_NAME, reply, body)?;
+    (0..d.u32()?)
+        .map(|_| {
+            let name = d.string()?.to_vec();
+            let _long_name = d.string()?;
+            Ok((name, FileStat::decode(&mut d)?))
+        })
+        .collect::<io::Result<_>>()
+        .map(Some)
+}
+
+/// Checks that a reply is `expected`, turning a status into its error.
+fn expect(expected: u8, reply: u8, body: &[u8]) -> io::Result<()> {
+    if reply == FXP_STATUS {
+        let mut d = Decoder::new(body);
+        let code = d.u32()?;
+        if code == FX_OK && expected == FXP_STATUS {
+            return Ok(());
+        }
+        return Err(status_error(code, d.text().unwrap_or_default()));
+    }
+    if reply != expected {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected sftp reply {reply}"),
+        ));
+    }
+    Ok(())
+}
+
+/// An open remote file, closed when dropped.
+pub struct File<'a> {
+    sftp: &'a Sftp,
+    handle: Vec<u8>,
+    offset: u64,
+}
+
+impl Read for File<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(MAX_CHUNK) as u32;
+        let body = Encoder::default()
+            .string(&self.handle)
+            .u64(self.offset)
+            .u32(len);
+        let (reply, body) = self.sftp.call(FXP_READ, body)?;
+        let mut d = Decoder::new(&body);
+        if reply == FXP_STATUS && d.u32()? == FX_EOF {
+            return Ok(0);
+        }
+        expect(FXP_DATA, reply, &body)?;
+        let data = d.string()?;
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for File<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(MAX_CHUNK);
+        let body = Encoder::default()
+            .string(&self.handle)
+            .u64(self.offset)
+            .string(&buf[..n]);
+        self.sftp.call_status(FXP_WRITE, body)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for File<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => {
+                let (reply, body) = self
+                    .sftp
+                    .call(FXP_FSTAT, Encoder::default().string(&self.handle))?;
+                expect(FXP_ATTRS, reply, &body)?;
+                let size = FileStat::decode(&mut Decoder::new(&body))?
+                    .size
+                    .unwrap_or(0);
+                size.checked_add_signed(delta)
+            }
+        };
+        self.offset = offset
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.offset)
+    }
+}
+
+impl Drop for File<'_> {
+    fn drop(&mut self) {
+        let _ = self.sftp.close(&self.handle);
+    }
+}
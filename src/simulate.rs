@@ -0,0 +1,231 @@
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::session::{Sent, Session, Step};
+
+#[derive(Debug, thiserror::Error)]
+pub enum SimulateError {
+    #[error("failed to read transcript \"{path}\": {source}")]
+    Read { path: PathBuf, source: io::Error },
+    #[error("{path}:{line}: not an asciicast event: {message}")]
+    Asciicast {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+}
+
+/// Loads a recorded session as the chunks of output the child would have
+/// produced.
+///
+/// Asciicast recordings (v2 or v3) keep the chunks they were recorded in.
+/// Anything else is taken as raw output and split at every line ending, as
+/// a device pausing at a prompt would send it.
+pub fn load(path: &Path) -> Result<Vec<Vec<u8>>, SimulateError> {
+    let content = std::fs::read(path).map_err(|source| SimulateError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    if !is_asciicast(&content) {
+        return Ok(raw_chunks(&content));
+    }
+    let text = String::from_utf8_lossy(&content);
+    let mut chunks = Vec::new();
+    for (index, line) in text.lines().enumerate().skip(1) {
+        let err = |message: String| SimulateError::Asciicast {
+            path: path.to_path_buf(),
+            line: index + 1,
+            message,
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (code, data) = parse_event(line).map_err(err)?;
+        if code == "o" {
+            chunks.push(data.into_bytes());
+        }
+    }
+    Ok(chunks)
+}
+
+fn is_asciicast(content: &[u8]) -> bool {
+    let header = content.split(|&b| b == b'\n').next().unwrap_or_default();
+    header.starts_with(b"{")
+        && header
+            .windows(b"\"version\"".len())
+            .any(|w| w == b"\"version\"")
+}
+
+/// Splits raw output before every line ending.
+fn raw_chunks(content: &[u8]) -> Vec<Vec<u8>> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    for i in 1..content.len() {
+        let is_break = |b: u8| b == b'\r' || b == b'\n';
+        if is_break(content[i]) && !is_break(content[i - 1]) {
+            chunks.push(content[start..i].to_vec());
+            start = i;
+        }
+    }
+    if start < content.len() {
+        chunks.push(content[start..].to_vec());
+    }
+    chunks
+}
+
+/// Parses an asciicast event, `[time, "code", "data"]`.
+fn parse_event(line: &str) -> Result<(String, String), String> {
+    let (_time, code, data): (f64, String, String) =
+        serde_json::from_str(line).map_err(|e| e.to_string())?;
+    Ok((code, data))
+}
+
+/// Feeds the chunks through `session` and reports every decision with the
+/// transcript offset it was made at. Returns the exit code sshpass would
+/// choose, or `None` if it would be the command's.
+pub fn replay(
+    mut session: Session,
+    chunks: &[Vec<u8>],
+    out: &mut impl Write,
+) -> io::Result<Option<i32>> {
+    let mut code = None;
+    if report(0, session.start(), &mut code, out)? {
+        return Ok(code);
+    }
+    let mut offset = 0;
+    for chunk in chunks {
+        offset += chunk.len();
+        if report(offset, session.feed(chunk), &mut code, out)? {
+            return Ok(code);
+        }
+    }
+    report(offset, session.finish(true), &mut code, out)?;
+    Ok(code)
+}
+
+/// Writes one line per step. Returns true once the session was closed.
+fn report(
+    offset: usize,
+    steps: Vec<Step>,
+    code: &mut Option<i32>,
+    out: &mut impl Write,
+) -> io::Result<bool> {
+    for step in steps {
+        match step {
+            Step::Matched(what) => writeln!(out, "{offset:>8}  matched {what}")?,
+            Step::Send {
+                data,
+                what: Sent::Script { secret: false },
+            } => writeln!(out, "{offset:>8}  send \"{}\"", data.escape_ascii())?,
+            Step::Send { what, .. } => writeln!(out, "{offset:>8}  send {what}")?,
+            Step::Interact => writeln!(out, "{offset:>8}  interact")?,
+            Step::Output(_) => {}
+            Step::Notice(message) => writeln!(out, "{offset:>8}  {message}")?,
+            Step::PasswordChanged => {}
+            Step::Exit(exit) => {
+                writeln!(out, "{offset:>8}  exit code {exit} when the command exits")?;
+                *code = Some(exit);
+            }
+            Step::Close(exit) => {
+                writeln!(out, "{offset:>8}  close with exit code {exit}")?;
+                *code = Some(exit);
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hops::HopPasswords;
+    use crate::passphrase::Passphrases;
+    use crate::session::SessionConfig;
+
+    fn session(login_marker: Option<&str>) -> Session {
+        Session::new(SessionConfig {
+            password: b"secret".to_vec(),
+            prompt: "assword:".into(),
+            localized_prompts: false,
+            script: None,
+            login_marker: login_marker.map(|m| regex::bytes::Regex::new(m).unwrap()),
+            passphrases: Passphrases::default(),
+            hop_passwords: HopPasswords::default(),
+            password_change: None,
+            stall_timeout: None,
+        })
+    }
+
+    fn simulate(login_marker: Option<&str>, transcript: &[u8]) -> (String, Option<i32>) {
+        let mut out = Vec::new();
+        let code = replay(session(login_marker), &raw_chunks(transcript), &mut out).unwrap();
+        (String::from_utf8(out).unwrap(), code)
+    }
+
+    #[test]
+    fn raw_output_is_split_before_line_endings() {
+        assert_eq!(
+            raw_chunks(b"Password: \r\nWelcome\r\n\r\nhost# "),
+            [&b"Password: "[..], b"\r\nWelcome", b"\r\n\r\nhost# "]
+        );
+    }
+
+    #[test]
+    fn successful_login() {
+        let (report, code) = simulate(
+            Some(r"[$#>%] ?$"),
+            b"me@switch's password: \r\nWelcome to switch\r\nswitch# ",
+        );
+        assert_eq!(
+            report.lines().collect::<Vec<_>>(),
+            [
+                "      22  matched password prompt",
+                "      22  send password",
+                "      51  matched login marker",
+                "      51  close with exit code 0",
+            ]
+        );
+        assert_eq!(code, Some(0));
+    }
+
+    #[test]
+    fn wrong_password() {
+        let (report, code) = simulate(None, b"Password: \r\nPassword: ");
+        assert!(report.ends_with("close with exit code 5\n"));
+        assert_eq!(code, Some(5));
+    }
+
+    #[test]
+    fn command_decides_the_exit_code() {
+        assert_eq!(simulate(None, b"no prompt here\r\n"), (String::new(), None));
+    }
+
+    #[test]
+    fn asciicast_events() {
+        assert_eq!(
+            parse_event(r#"[0.25, "o", "Passé \"x\"\r\n😀"]"#).unwrap(),
+            ("o".into(), "Pass\u{e9} \"x\"\r\n\u{1f600}".into())
+        );
+        assert!(parse_event(r#"[0.25, "o"]"#).is_err());
+        assert!(parse_event(r#"[0.25, "o", "\ud83d\u0041"]"#).is_err());
+        assert!(parse_event(r#"{"version": 2}"#).is_err());
+    }
+
+    #[test]
+    fn asciicast_file() {
+        let path = std::env::temp_dir().join("sshpass_test_cast");
+        std::fs::write(
+            &path,
+            "{\"version\": 2, \"width\": 80, \"height\": 24}\n\
+             [0.1, \"o\", \"Password: \"]\n[0.2, \"i\", \"x\"]\n[0.5, \"o\", \"\\r\\n$ \"]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            load(&path).unwrap(),
+            [b"Password: ".to_vec(), b"\r\n$ ".to_vec()]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
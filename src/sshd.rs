@@ -0,0 +1,454 @@
+use ed25519_dalek::SigningKey;
+use ssh_key::PublicKey;
+use ssh_key::public::Ed25519PublicKey;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+
+use crate::connection::*;
+use crate::sftp::*;
+use crate::transport::{
+    CIPHERS, Decoder, Encoder, MACS, MSG_SERVICE_ACCEPT, MSG_SERVICE_REQUEST, Transport,
+};
+
+const WINDOW: u32 = 0x7fff_ffff;
+const MAX_PACKET: usize = 32 * 1024;
+const FX_FAILURE: u32 = 4;
+const FX_OP_UNSUPPORTED: u32 = 8;
+
+/// How the test server behaves.
+#[derive(Clone)]
+pub struct Config {
+    pub password: Vec<u8>,
+    /// Offer keyboard-interactive instead of the password method.
+    pub keyboard_interactive: bool,
+    pub ciphers: &'static [&'static str],
+    pub macs: &'static [&'static str],
+}
+
+impl Config {
+    pub fn new(password: &[u8]) -> Self {
+        Self {
+            password: password.to_vec(),
+            keyboard_interactive: false,
+            ciphers: CIPHERS,
+            macs: MACS,
+        }
+    }
+}
+
+/// An SSH server on localhost for the tests: it runs commands with `sh -c`
+/// once their input is complete and serves SFTP from the local files.
+pub struct TestServer {
+    pub port: u16,
+    pub host_key: PublicKey,
+}
+
+impl TestServer {
+    pub fn start(config: Config) -> Self {
+        let mut seed = [0u8; 32];
+        getrandom::fill(&mut seed).unwrap();
+        let key = SigningKey::from_bytes(&seed);
+        let host_key = PublicKey::new(Ed25519PublicKey(key.verifying_key().to_bytes()).into(), "");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (key, config) = (key.clone(), config.clone());
+                thread::spawn(move || serve(stream, key, &config));
+            }
+        });
+        Self { port, host_key }
+    }
+
+    /// The known_hosts line for the server.
+    pub fn known_hosts_line(&self) -> String {
+        format!(
+            "[127.0.0.1]:{} {}\n",
+            self.port,
+            self.host_key.to_openssh().unwrap()
+        )
+    }
+}
+
+fn serve(stream: TcpStream, key: SigningKey, config: &Config) -> io::Result<()> {
+    let mut t = Transport::accept(stream, key, config.ciphers, config.macs)?;
+    let request = t.recv()?;
+    assert_eq!(request[0], MSG_SERVICE_REQUEST);
+    t.send(
+        &Encoder::new(MSG_SERVICE_ACCEPT)
+            .string("ssh-userauth")
+            .into_bytes(),
+    )?;
+    authenticate(&mut t, config)?;
+
+    let mut client_id = 0;
+    let mut command = None;
+    let mut input = Vec::new();
+    let mut sftp: Option<SftpServer> = None;
+    loop {
+        let packet = t.recv()?;
+        let mut d = Decoder::new(&packet[1..]);
+        match packet[0] {
+            MSG_CHANNEL_OPEN => {
+                let _kind = d.string()?;
+                client_id = d.u32()?;
+                let confirmation = Encoder::new(MSG_CHANNEL_OPEN_CONFIRMATION)
+                    .u32(client_id)
+                    .u32(0)
+                    .u32(WINDOW)
+                    .u32(MAX_PACKET as u32);
+                t.send(&confirmation.into_bytes())?;
+            }
+            MSG_CHANNEL_REQUEST => {
+                let _id = d.u32()?;
+                let name = d.string()?;
+                let want_reply = d.bool()?;
+                match name {
+                    b"exec" => command = Some(d.text()?),
+                    b"subsystem" => sftp = Some(SftpServer::default()),
+                    _ => {}
+                }
+                if want_reply {
+                    t.send(
+                        &Encoder::new(MSG_CHANNEL_SUCCESS)
+                            .u32(client_id)
+                            .into_bytes(),
+                    )?;
+                }
+            }
+            MSG_CHANNEL_DATA => {
+                let _id = d.u32()?;
+                let data = d.string()?;
+                match &mut sftp {
+                    Some(server) => {
+                        for reply in server.feed(data) {
+                            send_data(&mut t, client_id, &reply)?;
+                        }
+                    }
+                    None => input.extend(data),
+                }
+            }
+            MSG_CHANNEL_EOF => {
+                if let Some(command) = command.take() {
+                    run(&mut t, client_id, &command, &input)?;
+                }
+            }
+            MSG_CHANNEL_CLOSE => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+fn authenticate(t: &mut Transport, config: &Config) -> io::Result<()> {
+    let methods = if config.keyboard_interactive {
+        "keyboard-interactive"
+    } else {
+        "password"
+    };
+    loop {
+        let request = t.recv()?;
+        let mut d = Decoder::new(&request[1..]);
+        let (_user, _service, method) = (d.string()?, d.string()?, d.string()?);
+        let accepted = match method {
+            b"password" if !config.keyboard_interactive => {
+                let _change = d.bool()?;
+                d.string()? == config.password
+            }
+            b"keyboard-interactive" if config.keyboard_interactive => {
+                let prompts = Encoder::new(MSG_USERAUTH_INFO_REQUEST)
+                    .string("")
+                    .string("")
+                    .string("")
+                    .u32(2)
+                    .string("Name: ")
+                    .bool(true)
+                    .string("Password: ")
+                    .bool(false);
+                t.send(&prompts.into_bytes())?;
+                let response = t.recv()?;
+                let mut d = Decoder::new(&response[1..]);
+                d.u32()? == 2 && d.string()?.is_empty() && d.string()? == config.password
+            }
+            _ => false,
+        };
+        if accepted {
+            return t.send(&[MSG_USERAUTH_SUCCESS]);
+        }
+        let failure = Encoder::new(MSG_USERAUTH_FAILURE)
+            .string(methods)
+            .bool(false);
+        t.send(&failure.into_bytes())?;
+    }
+}
+
+fn send_data(t: &mut Transport, client_id: u32, data: &[u8]) -> io::Result<()> {
+    for chunk in data.chunks(MAX_PACKET) {
+        let message = Encoder::new(MSG_CHANNEL_DATA).u32(client_id).string(chunk);
+        t.send(&message.into_bytes())?;
+    }
+    Ok(())
+}
+
+/// Runs `command` on `input` and sends its output and how it ended.
+fn run(t: &mut Transport, client_id: u32, command: &str, input: &[u8]) -> io::Result<()> {
+    let mut child = Command::new("sh")
+        .args(["-c", command])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(input)?;
+    let output = child.wait_with_output()?;
+    send_data(t, client_id, &output.stdout)?;
+    let stderr = Encoder::new(MSG_CHANNEL_EXTENDED_DATA)
+        .u32(client_id)
+        .u32(STDERR)
+        .string(&output.stderr);
+    t.send(&stderr.into_bytes())?;
+    t.send(&Encoder::new(MSG_CHANNEL_EOF).u32(client_id).into_bytes())?;
+    let end = Encoder::new(MSG_CHANNEL_REQUEST).u32(client_id);
+    let end = match (output.status.code(), output.status.signal()) {
+        (Some(code), _) => end.string("exit-status").bool(false).u32(code as u32),
+        (None, signal) => {
+            let name = match signal {
+                Some(9) => "KILL",
+                _ => "TERM",
+            };
+            end.string("exit-signal")
+                .bool(false)
+                .string(name)
+                .bool(false)
+                .string("")
+                .string("")
+        }
+    };
+    t.send(&end.into_bytes())?;
+    t.send(&Encoder::new(MSG_CHANNEL_CLOSE).u32(client_id).into_bytes())
+}
+
+enum Handle {
+    File(fs::File),
+    /// The entries not listed yet.
+    Dir(Option<Vec<(Vec<u8>, FileStat)>>),
+}
+
+/// SFTP version 3 on the local files, as much as the client uses.
+#[derive(Default)]
+struct SftpServer {
+    buf: Vec<u8>,
+    handles: HashMap<u32, Handle>,
+    next_handle: u32,
+}
+
+fn stat_of(meta: &fs::Metadata) -> FileStat {
+    FileStat {
+        size: Some(meta.len()),
+        uid: Some(meta.uid()),
+        gid: Some(meta.gid()),
+        perm: Some(meta.mode()),
+        atime: Some(meta.atime() as u64),
+        mtime: Some(meta.mtime() as u64),
+    }
+}
+
+fn status(id: u32, result: io::Result<()>) -> Encoder {
+    let (code, message) = match result {
+        Ok(()) => (FX_OK, String::new()),
+        Err(e) => (
+            match e.kind() {
+                io::ErrorKind::NotFound => FX_NO_SUCH_FILE,
+                io::ErrorKind::PermissionDenied => FX_PERMISSION_DENIED,
+                _ => FX_FAILURE,
+            },
+            e.to_string(),
+        ),
+    };
+    Encoder::new(FXP_STATUS)
+        .u32(id)
+        .u32(code)
+        .string(message)
+        .string("")
+}
+
+fn set_stat(path: &Path, stat: &FileStat) -> io::Result<()> {
+    if let Some((atime, mtime)) = stat.atime.zip(stat.mtime) {
+        let times = [
+            libc::timeval {
+                tv_sec: atime as libc::time_t,
+                tv_usec: 0,
+            },
+            libc::timeval {
+                tv_sec: mtime as libc::time_t,
+                tv_usec: 0,
+            },
+        ];
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+        if unsafe { libc::utimes(path.as_ptr(), times.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    if let Some(perm) = stat.perm {
+        fs::set_permissions(path, fs::Permissions::from_mode(perm & 0o7777))?;
+    }
+    Ok(())
+}
+
+impl SftpServer {
+    /// Takes channel data and gives the replies to the complete requests.
+    fn feed(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.buf.extend(data);
+        let mut replies = Vec::new();
+        while let Some(len) = self.buf.get(..4) {
+            let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+            if self.buf.len() < 4 + len {
+                break;
+            }
+            let request: Vec<u8> = self.buf.drain(..4 + len).skip(4).collect();
+            let reply = self.handle(&request).unwrap_or_else(|e| status(0, Err(e)));
+            replies.push(Encoder::default().string(reply.into_bytes()).into_bytes());
+        }
+        replies
+    }
+
+    fn add(&mut self, handle: Handle) -> Vec<u8> {
+        self.next_handle += 1;
+        self.handles.insert(self.next_handle, handle);
+        self.next_handle.to_be_bytes().to_vec()
+    }
+
+    fn handle(&mut self, request: &[u8]) -> io::Result<Encoder> {
+        let mut d = Decoder::new(&request[1..]);
+        if request[0] == FXP_INIT {
+            return Ok(Encoder::new(FXP_VERSION).u32(3));
+        }
+        let id = d.u32()?;
+        let handle_id = |d: &mut Decoder| -> io::Result<u32> {
+            Ok(u32::from_be_bytes(
+                d.string()?.try_into().unwrap_or_default(),
+            ))
+        };
+        Ok(match request[0] {
+            FXP_STAT => match fs::metadata(bytes_path(d.string()?)) {
+                Ok(meta) => stat_of(&meta).encode(Encoder::new(FXP_ATTRS).u32(id)),
+                Err(e) => status(id, Err(e)),
+            },
+            FXP_OPEN => {
+                let path = bytes_path(d.string()?);
+                let flags = d.u32()?;
+                let stat = FileStat::decode(&mut d)?;
+                let opened = fs::OpenOptions::new()
+                    .read(flags & OpenFlags::READ.0 != 0)
+                    .write(flags & OpenFlags::WRITE.0 != 0)
+                    .create(flags & OpenFlags::CREATE.0 != 0)
+                    .truncate(flags & OpenFlags::TRUNCATE.0 != 0)
+                    .mode(stat.perm.unwrap_or(0o666))
+                    .open(path);
+                match opened {
+                    Ok(file) => {
+                        let handle = self.add(Handle::File(file));
+                        Encoder::new(FXP_HANDLE).u32(id).string(handle)
+                    }
+                    Err(e) => status(id, Err(e)),
+                }
+            }
+            FXP_READ => {
+                let handle = handle_id(&mut d)?;
+                let (offset, len) = (d.u64()?, d.u32()?);
+                let Some(Handle::File(file)) = self.handles.get_mut(&handle) else {
+                    return Ok(status(id, Err(io::ErrorKind::InvalidInput.into())));
+                };
+                let mut data = vec![0u8; len as usize];
+                file.seek(SeekFrom::Start(offset))?;
+                let n = file.read(&mut data)?;
+                if n == 0 {
+                    Encoder::new(FXP_STATUS)
+                        .u32(id)
+                        .u32(FX_EOF)
+                        .string("")
+                        .string("")
+                } else {
+                    Encoder::new(FXP_DATA).u32(id).string(&data[..n])
+                }
+            }
+            FXP_WRITE => {
+                let handle = handle_id(&mut d)?;
+                let (offset, data) = (d.u64()?, d.string()?);
+                let Some(Handle::File(file)) = self.handles.get_mut(&handle) else {
+                    return Ok(status(id, Err(io::ErrorKind::InvalidInput.into())));
+                };
+                status(
+                    id,
+                    file.seek(SeekFrom::Start(offset))
+                        .and_then(|_| file.write_all(data)),
+                )
+            }
+            FXP_FSTAT => match self.handles.get(&handle_id(&mut d)?) {
+                Some(Handle::File(file)) => {
+                    stat_of(&file.metadata()?).encode(Encoder::new(FXP_ATTRS).u32(id))
+                }
+                _ => status(id, Err(io::ErrorKind::InvalidInput.into())),
+            },
+            FXP_CLOSE => {
+                self.handles.remove(&handle_id(&mut d)?);
+                status(id, Ok(()))
+            }
+            FXP_SETSTAT => {
+                let path = bytes_path(d.string()?);
+                let stat = FileStat::decode(&mut d)?;
+                status(id, set_stat(&path, &stat))
+            }
+            FXP_MKDIR => {
+                let path = bytes_path(d.string()?);
+                let stat = FileStat::decode(&mut d)?;
+                status(
+                    id,
+                    fs::create_dir(&path).and_then(|_| set_stat(&path, &stat)),
+                )
+            }
+            FXP_OPENDIR => {
+                let path = bytes_path(d.string()?);
+                let mut entries = vec![
+                    (b".".to_vec(), stat_of(&fs::metadata(&path)?)),
+                    (b"..".to_vec(), stat_of(&fs::metadata(&path)?)),
+                ];
+                for entry in fs::read_dir(&path)? {
+                    let entry = entry?;
+                    let name = entry.file_name().as_bytes().to_vec();
+                    entries.push((name, stat_of(&entry.metadata()?)));
+                }
+                let handle = self.add(Handle::Dir(Some(entries)));
+                Encoder::new(FXP_HANDLE).u32(id).string(handle)
+            }
+            FXP_READDIR => match self.handles.get_mut(&handle_id(&mut d)?) {
+                Some(Handle::Dir(entries)) => match entries.take() {
+                    Some(entries) => {
+                        let mut reply = Encoder::new(FXP_NAME).u32(id).u32(entries.len() as u32);
+                        for (name, stat) in entries {
+                            reply = stat.encode(reply.string(&name).string(&name));
+                        }
+                        reply
+                    }
+                    None => Encoder::new(FXP_STATUS)
+                        .u32(id)
+                        .u32(FX_EOF)
+                        .string("")
+                        .string(""),
+                },
+                _ => status(id, Err(io::ErrorKind::InvalidInput.into())),
+            },
+            _ => Encoder::new(FXP_STATUS)
+                .u32(id)
+                .u32(FX_OP_UNSUPPORTED)
+                .string("")
+                .string(""),
+        })
+    }
+}
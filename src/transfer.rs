@@ -0,0 +1,479 @@
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::sftp::{FileStat, OpenFlags, Sftp};
+
+const BUFFER_SIZE: usize = 32 * 1024;
+/// Progress lines are written at most this often per file.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+const FILE_MODE: u32 = 0o644;
+const DIR_MODE: u32 = 0o755;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransferError {
+    #[error("\"{path}\": {source}")]
+    Local { path: PathBuf, source: io::Error },
+    #[error("remote \"{path}\": {source}")]
+    Remote { path: PathBuf, source: io::Error },
+    #[error("\"{0}\" is a directory (use -r)")]
+    IsDirectory(PathBuf),
+    #[error("\"{0}\" is not a directory")]
+    NotADirectory(PathBuf),
+    #[error("expected a remote path, [user@]host:path: \"{0}\"")]
+    NotRemote(String),
+}
+
+/// How to copy.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Options {
+    pub recursive: bool,
+    /// Keep the permissions and times of the source.
+    pub preserve: bool,
+    /// Continue files that are shorter at the destination.
+    pub resume: bool,
+    /// Report progress on stderr, a line at a time.
+    pub progress: bool,
+}
+
+/// A remote path as scp takes it, `[user@]host:path`.
+#[derive(Debug, PartialEq)]
+pub struct RemotePath {
+    /// `[user@]host`
+    pub destination: String,
+    pub path: PathBuf,
+}
+
+/// Splits `[user@]host:path`. Like scp, a `/` before the colon makes it a
+/// local path; IPv6 addresses go in brackets.
+pub fn remote_path(arg: &str) -> Result<RemotePath, TransferError> {
+    let mut in_brackets = false;
+    let colon = arg.char_indices().find_map(|(i, c)| match c {
+        '[' => {
+            in_brackets = true;
+            None
+        }
+        ']' => {
+            in_brackets = false;
+            None
+        }
+        ':' if !in_brackets => Some(i),
+        _ => None,
+    });
+    match colon {
+        Some(colon) if colon > 0 && !arg[..colon].contains('/') => {
+            let path = &arg[colon + 1..];
+            Ok(RemotePath {
+                destination: arg[..colon].to_string(),
+                path: PathBuf::from(if path.is_empty() { "." } else { path }),
+            })
+        }
+        _ => Err(TransferError::NotRemote(arg.to_string())),
+    }
+}
+
+/// Copies local `sources` to `target` on the remote host. With several
+/// sources, or if `target` is a directory, they are placed in it.
+pub fn upload(
+    sftp: &Sftp,
+    sources: &[PathBuf],
+    target: &Path,
+    options: Options,
+) -> Result<(), TransferError> {
+    let into_dir = sftp.stat(target).is_ok_and(|s| s.is_dir());
+    if sources.len() > 1 && !into_dir {
+        return Err(TransferError::NotADirectory(target.to_path_buf()));
+    }
+    for source in sources {
+        let dest = match source.file_name() {
+            Some(name) if into_dir => target.join(name),
+            _ => target.to_path_buf(),
+        };
+        put(sftp, source, &dest, options)?;
+    }
+    Ok(())
+}
+
+fn put(sftp: &Sftp, source: &Path, dest: &Path, options: Options) -> Result<(), TransferError> {
+    let meta = fs::metadata(source).map_err(|e| local_error(source, e))?;
+    let mode = if options.preserve {
+        permissions(&meta)
+    } else {
+        FILE_MODE
+    };
+
+    if meta.is_dir() {
+        if !options.recursive {
+            return Err(TransferError::IsDirectory(source.to_path_buf()));
+        }
+        // A preserved mode is set once the contents are in place.
+        if !sftp.stat(dest).is_ok_and(|s| s.is_dir()) {
+            sftp.mkdir(dest, DIR_MODE)
+                .map_err(|e| remote_error(dest, e))?;
+        }
+        for entry in fs::read_dir(source).map_err(|e| local_error(source, e))? {
+            let entry = entry.map_err(|e| local_error(source, e))?;
+            put(sftp, &entry.path(), &dest.join(entry.file_name()), options)?;
+        }
+    } else {
+        let total = meta.len();
+        let offset = if options.resume {
+            sftp.stat(dest)
+                .ok()
+                .and_then(|s| s.size)
+                .filter(|&size| size <= total)
+                .unwrap_or(0)
+        } else {
+            0
+        };
+        let mut from = fs::File::open(source).map_err(|e| local_error(source, e))?;
+        from.seek(SeekFrom::Start(offset))
+            .map_err(|e| local_error(source, e))?;
+        let flags = if offset > 0 {
+            OpenFlags::WRITE
+        } else {
+            OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE
+        };
+        let mut to = sftp
+            .open_mode(dest, flags, Some(mode))
+            .map_err(|e| remote_error(dest, e))?;
+        to.seek(SeekFrom::Start(offset))
+            .map_err(|e| remote_error(dest, e))?;
+        copy(
+            (&mut from, source, false),
+            (&mut to, dest, true),
+            Progress::new(dest, offset, total, options.progress),
+        )?;
+    }
+
+    if options.preserve {
+        let times = |time: io::Result<SystemTime>| {
+            time.ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+        };
+        let stat = FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: Some(permissions(&meta)),
+            atime: times(meta.accessed()),
+            mtime: times(meta.modified()),
+        };
+        sftp.setstat(dest, stat)
+            .map_err(|e| remote_error(dest, e))?;
+    }
+    Ok(())
+}
+
+/// Copies remote `sources` to local `target`. With several sources, or if
+/// `target` is a directory, they are placed in it.
+pub fn download(
+    sftp: &Sftp,
+    sources: &[PathBuf],
+    target: &Path,
+    options: Options,
+) -> Result<(), TransferError> {
+    let into_dir = target.is_dir();
+    if sources.len() > 1 && !into_dir {
+        return Err(TransferError::NotADirectory(target.to_path_buf()));
+    }
+    for source in sources {
+        let dest = match source.file_name() {
+            Some(name) if into_dir => target.join(name),
+            _ => target.to_path_buf(),
+        };
+        get(sftp, source, &dest, options)?;
+    }
+    Ok(())
+}
+
+fn get(sftp: &Sftp, source: &Path, dest: &Path, options: Options) -> Result<(), TransferError> {
+    let stat = sftp.stat(source).map_err(|e| remote_error(source, e))?;
+
+    let written = if stat.is_dir() {
+        if !options.recursive {
+            return Err(TransferError::IsDirectory(source.to_path_buf()));
+        }
+        if !dest.is_dir() {
+            fs::create_dir(dest).map_err(|e| local_error(dest, e))?;
+        }
+        for (path, _) in sftp.readdir(source).map_err(|e| remote_error(source, e))? {
+            if let Some(name) = path.file_name() {
+                get(sftp, &path, &dest.join(name), options)?;
+            }
+        }
+        None
+    } else {
+        let total = stat.size.unwrap_or(0);
+        let offset = if options.resume {
+            fs::metadata(dest)
+                .ok()
+                .map(|m| m.len())
+                .filter(|&len| len <= total)
+                .unwrap_or(0)
+        } else {
+            0
+        };
+        let mut from = sftp.open(source).map_err(|e| remote_error(source, e))?;
+        from.seek(SeekFrom::Start(offset))
+            .map_err(|e| remote_error(source, e))?;
+        let mut to = fs::File::options()
+            .write(true)
+            .create(true)
+            .truncate(offset == 0)
+            .open(dest)
+            .map_err(|e| local_error(dest, e))?;
+        to.seek(SeekFrom::Start(offset))
+            .map_err(|e| local_error(dest, e))?;
+        copy(
+            (&mut from, source, true),
+            (&mut to, dest, false),
+            Progress::new(source, offset, total, options.progress),
+        )?;
+        Some(to)
+    };
+
+    if options.preserve {
+        // Times go through the handle still open for writing and the mode
+        // comes last, so modes like 0200 or 0000 do not lock us out.
+        let time = |secs: Option<u64>| secs.map(|s| UNIX_EPOCH + Duration::from_secs(s));
+        let mut times = fs::FileTimes::new();
+        if let Some(atime) = time(stat.atime) {
+            times = times.set_accessed(atime);
+        }
+        if let Some(mtime) = time(stat.mtime) {
+            times = times.set_modified(mtime);
+        }
+        match written {
+            Some(file) => file.set_times(times),
+            None => fs::File::open(dest).and_then(|f| f.set_times(times)),
+        }
+        .map_err(|e| local_error(dest, e))?;
+        #[cfg(unix)]
+        if let Some(perm) = stat.perm {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(dest, fs::Permissions::from_mode(perm & 0o7777))
+                .map_err(|e| local_error(dest, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies until the end of `from`. Each side comes with its path and
+/// whether it is remote, for error messages.
+fn copy(
+    (from, from_path, from_remote): (&mut impl Read, &Path, bool),
+    (to, to_path, to_remote): (&mut impl Write, &Path, bool),
+    mut progress: Progress,
+) -> Result<(), TransferError> {
+    let error = |path: &Path, remote: bool, e: io::Error| {
+        if remote {
+            TransferError::Remote {
+                path: path.to_path_buf(),
+                source: e,
+            }
+        } else {
+            local_error(path, e)
+        }
+    };
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    loop {
+        let n = match from.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(error(from_path, from_remote, e)),
+        };
+        to.write_all(&buf[..n])
+            .map_err(|e| error(to_path, to_remote, e))?;
+        progress.advance(n as u64);
+    }
+    to.flush().map_err(|e| error(to_path, to_remote, e))?;
+    progress.finish();
+    Ok(())
+}
+
+/// Progress of one file, reported as whole lines so that logs stay readable.
+struct Progress {
+    label: String,
+    done: u64,
+    total: u64,
+    last: Option<Instant>,
+    enabled: bool,
+}
+
+impl Progress {
+    fn new(path: &Path, offset: u64, total: u64, enabled: bool) -> Self {
+        let progress = Self {
+            label: path.display().to_string(),
+            done: offset,
+            total,
+            last: None,
+            enabled,
+        };
+        if enabled && offset > 0 {
+            eprintln!("SSHPASS: {}: resuming at {offset} bytes", progress.label);
+        }
+        progress
+    }
+
+    fn advance(&mut self, n: u64) {
+        self.done += n;
+        if self.enabled && self.last.is_none_or(|t| t.elapsed() >= PROGRESS_INTERVAL) {
+            self.report();
+        }
+    }
+
+    fn finish(&mut self) {
+        if self.enabled {
+            self.report();
+        }
+    }
+
+    fn report(&mut self) {
+        self.last = Some(Instant::now());
+        eprintln!("SSHPASS: {}", self.line());
+    }
+
+    fn line(&self) -> String {
+        let percent = match self.total {
+            0 => 100,
+            total => self.done.min(total) * 100 / total,
+        };
+        format!(
+            "{}: {}/{} bytes ({percent}%)",
+            self.label, self.done, self.total
+        )
+    }
+}
+
+fn permissions(meta: &fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        meta.permissions().mode() & 0o7777
+    }
+    #[cfg(not(unix))]
+    {
+        let mode = if meta.is_dir() { DIR_MODE } else { FILE_MODE };
+        if meta.permissions().readonly() {
+            mode & !0o222
+        } else {
+            mode
+        }
+    }
+}
+
+fn local_error(path: &Path, source: io::Error) -> TransferError {
+    TransferError::Local {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+fn remote_error(path: &Path, source: impl Into<io::Error>) -> TransferError {
+    TransferError::Remote {
+        path: path.to_path_buf(),
+        source: source.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use crate::connection::Channel;
+    #[cfg(unix)]
+    use crate::native;
+    #[cfg(unix)]
+    use crate::sshd::{Config, TestServer};
+
+    #[test]
+    fn remote_paths() {
+        assert_eq!(
+            remote_path("me@host:/srv/data").unwrap(),
+            RemotePath {
+                destination: "me@host".into(),
+                path: "/srv/data".into(),
+            }
+        );
+        assert_eq!(remote_path("[fd00::1]:").unwrap().path, Path::new("."));
+        assert_eq!(
+            remote_path("me@[fd00::1]:x").unwrap().destination,
+            "me@[fd00::1]"
+        );
+        assert!(remote_path("./a:b").is_err());
+        assert!(remote_path("plain").is_err());
+        assert!(remote_path(":x").is_err());
+    }
+
+    #[test]
+    fn progress_lines() {
+        let mut progress = Progress::new(Path::new("f"), 512, 2048, false);
+        progress.advance(512);
+        assert_eq!(progress.line(), "f: 1024/2048 bytes (50%)");
+        assert_eq!(
+            Progress::new(Path::new("empty"), 0, 0, false).line(),
+            "empty: 0/0 bytes (100%)"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn put_and_get_round_trip() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let server = TestServer::start(Config::new(b"pw"));
+        let command: Vec<String> = [
+            "ssh",
+            "-oStrictHostKeyChecking=no",
+            "-oUserKnownHostsFile=/dev/null",
+            "me@127.0.0.1",
+        ]
+        .map(String::from)
+        .to_vec();
+        let mut target = native::target(&command).unwrap();
+        target.port = server.port;
+        let transport = native::connect(&target, b"pw").unwrap().unwrap();
+        let sftp = Sftp::new(Channel::open_session(transport).unwrap()).unwrap();
+
+        let dir = std::env::temp_dir().join("sshpass_test_transfer");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("tree/sub")).unwrap();
+        let payload: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        fs::write(dir.join("tree/sub/file"), &payload).unwrap();
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        fs::File::options()
+            .write(true)
+            .open(dir.join("tree/sub/file"))
+            .and_then(|f| f.set_modified(mtime))
+            .unwrap();
+        // Neither mode may get in the way of finishing the copy.
+        fs::set_permissions(dir.join("tree/sub/file"), fs::Permissions::from_mode(0o200)).unwrap();
+        fs::set_permissions(dir.join("tree/sub"), fs::Permissions::from_mode(0o555)).unwrap();
+
+        let options = Options {
+            recursive: true,
+            preserve: true,
+            ..Options::default()
+        };
+        upload(&sftp, &[dir.join("tree")], &dir.join("remote"), options).unwrap();
+        download(&sftp, &[dir.join("remote")], &dir.join("back"), options).unwrap();
+
+        for copy in ["remote", "back"] {
+            let sub = dir.join(copy).join("sub");
+            let meta = fs::metadata(sub.join("file")).unwrap();
+            assert_eq!(meta.permissions().mode() & 0o7777, 0o200, "{copy}");
+            assert_eq!(meta.modified().unwrap(), mtime, "{copy}");
+            let meta = fs::metadata(&sub).unwrap();
+            assert_eq!(meta.permissions().mode() & 0o7777, 0o555, "{copy}");
+            fs::set_permissions(&sub, fs::Permissions::from_mode(0o755)).unwrap();
+            fs::set_permissions(sub.join("file"), fs::Permissions::from_mode(0o644)).unwrap();
+            assert_eq!(fs::read(sub.join("file")).unwrap(), payload, "{copy}");
+        }
+        fs::set_permissions(dir.join("tree/sub"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,874 @@
+use aes::{Aes128, Aes256};
+use chacha20::ChaCha20Legacy;
+use ctr::Ctr128BE;
+use ctr::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use hmac::{Hmac, Mac};
+use poly1305::Poly1305;
+use poly1305::universal_hash::KeyInit;
+use sha2::{Digest, Sha256, Sha512};
+use signature::Verifier;
+use ssh_key::{Algorithm, PublicKey, Signature};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use x25519_dalek::StaticSecret;
+
+pub const MSG_DISCONNECT: u8 = 1;
+const MSG_IGNORE: u8 = 2;
+const MSG_UNIMPLEMENTED: u8 = 3;
+const MSG_DEBUG: u8 = 4;
+pub const MSG_SERVICE_REQUEST: u8 = 5;
+pub const MSG_SERVICE_ACCEPT: u8 = 6;
+const MSG_EXT_INFO: u8 = 7;
+const MSG_KEXINIT: u8 = 20;
+const MSG_NEWKEYS: u8 = 21;
+const MSG_KEX_ECDH_INIT: u8 = 30;
+const MSG_KEX_ECDH_REPLY: u8 = 31;
+
+const VERSION: &str = concat!("SSH-2.0-sshpass_rs_", env!("CARGO_PKG_VERSION"));
+/// Lines a server may send before its version, as OpenSSH allows.
+const MAX_BANNER_LINES: usize = 1024;
+/// Largest packet accepted, as in OpenSSH.
+const MAX_PACKET: usize = 256 * 1024;
+
+const KEX: &[&str] = &["curve25519-sha256", "curve25519-sha256@libssh.org"];
+/// Strict key exchange, against prefix truncation (CVE-2023-48795).
+const STRICT_CLIENT: &str = "kex-strict-c-v00@openssh.com";
+const STRICT_SERVER: &str = "kex-strict-s-v00@openssh.com";
+/// Host key algorithms in the order asked for when none is known.
+pub const HOST_KEY_ALGORITHMS: &[&str] = &[
+    "ssh-ed25519",
+    "ecdsa-sha2-nistp256",
+    "rsa-sha2-512",
+    "rsa-sha2-256",
+];
+pub const CIPHERS: &[&str] = &["chacha20-poly1305@openssh.com", "aes256-ctr", "aes128-ctr"];
+pub const MACS: &[&str] = &[
+    "hmac-sha2-256-etm@openssh.com",
+    "hmac-sha2-512-etm@openssh.com",
+    "hmac-sha2-256",
+    "hmac-sha2-512",
+];
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed ssh packet")
+}
+
+fn protocol(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    getrandom::fill(&mut bytes).expect("no system random number generator");
+    bytes
+}
+
+/// Builds data in the SSH wire format.
+#[derive(Default)]
+pub struct Encoder(Vec<u8>);
+
+impl Encoder {
+    /// Starts a message with its number.
+    pub fn new(message: u8) -> Self {
+        Self(vec![message])
+    }
+
+    pub fn byte(mut self, value: u8) -> Self {
+        self.0.push(value);
+        self
+    }
+
+    pub fn bool(self, value: bool) -> Self {
+        self.byte(value.into())
+    }
+
+    pub fn u32(mut self, value: u32) -> Self {
+        self.0.extend(value.to_be_bytes());
+        self
+    }
+
+    pub fn u64(mut self, value: u64) -> Self {
+        self.0.extend(value.to_be_bytes());
+        self
+    }
+
+    pub fn raw(mut self, bytes: &[u8]) -> Self {
+        self.0.extend(bytes);
+        self
+    }
+
+    pub fn string(self, bytes: impl AsRef<[u8]>) -> Self {
+        let bytes = bytes.as_ref();
+        self.u32(bytes.len() as u32).raw(bytes)
+    }
+
+    pub fn name_list(self, names: &[&str]) -> Self {
+        self.string(names.join(","))
+    }
+
+    /// An unsigned big-endian number as an mpint.
+    pub fn mpint(self, bytes: &[u8]) -> Self {
+        let start = bytes.iter().position(|&b| b != 0).unwrap_or(bytes.len());
+        let bytes = &bytes[start..];
+        if bytes.first().is_some_and(|&b| b & 0x80 != 0) {
+            self.u32(bytes.len() as u32 + 1).byte(0).raw(bytes)
+        } else {
+            self.string(bytes)
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+/// Reads the fields of a message in the SSH wire format.
+pub struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self(data)
+    }
+
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(malformed());
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    pub fn byte(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.byte()? != 0)
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn string(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    /// A string meant for humans; invalid UTF-8 is replaced.
+    pub fn text(&mut self) -> io::Result<String> {
+        Ok(String::from_utf8_lossy(self.string()?).into_owned())
+    }
+
+    pub fn name_list(&mut self) -> io::Result<Vec<String>> {
+        let names = self.text()?;
+        Ok(names
+            .split(',')
+            .filter(|n| !n.is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+}
+
+/// The error for a DISCONNECT message from the peer.
+fn disconnected(payload: &[u8]) -> io::Error {
+    let mut d = Decoder::new(&payload[1..]);
+    let reason = d.u32().and_then(|_| d.text()).unwrap_or_default();
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        format!("disconnected by the server: {reason}"),
+    )
+}
+
+enum Stream {
+    Aes128(Box<Ctr128BE<Aes128>>),
+    Aes256(Box<Ctr128BE<Aes256>>),
+}
+
+impl Stream {
+    fn apply(&mut self, buf: &mut [u8]) {
+        match self {
+            Self::Aes128(c) => c.apply_keystream(buf),
+            Self::Aes256(c) => c.apply_keystream(buf),
+        }
+    }
+}
+
+struct MacKey {
+    key: Vec<u8>,
+    /// SHA-512 rather than SHA-256.
+    sha512: bool,
+    /// Encrypt-then-MAC: the length stays in the clear and the tag covers
+    /// the encrypted packet.
+    etm: bool,
+}
+
+impl MacKey {
+    fn len(&self) -> usize {
+        if self.sha512 { 64 } else { 32 }
+    }
+
+    fn tag(&self, seq: u32, packet: &[u8]) -> Vec<u8> {
+        if self.sha512 {
+            let mut mac = <Hmac<Sha512> as Mac>::new_from_slice(&self.key).unwrap();
+            mac.update(&seq.to_be_bytes());
+            mac.update(packet);
+            mac.finalize().into_bytes().to_vec()
+        } else {
+            let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.key).unwrap();
+            mac.update(&seq.to_be_bytes());
+            mac.update(packet);
+            mac.finalize().into_bytes().to_vec()
+        }
+    }
+}
+
+/// How the packets of one direction are protected.
+enum Protection {
+    None,
+    /// chacha20-poly1305@openssh.com, with separate keys for the length
+    /// and the rest.
+    ChaCha {
+        main: [u8; 32],
+        header: [u8; 32],
+    },
+    Ctr {
+        stream: Stream,
+        mac: MacKey,
+    },
+}
+
+impl Protection {
+    fn new(
+        cipher: &str,
+        mac: Option<&str>,
+        derive: impl Fn(u8, usize) -> Vec<u8>,
+        letters: [u8; 3],
+    ) -> Self {
+        let [iv_letter, key_letter, mac_letter] = letters;
+        let stream = match cipher {
+            "chacha20-poly1305@openssh.com" => {
+                let key = derive(key_letter, 64);
+                return Self::ChaCha {
+                    main: key[..32].try_into().unwrap(),
+                    header: key[32..].try_into().unwrap(),
+                };
+            }
+            "aes256-ctr" => Stream::Aes256(Box::new(
+                Ctr128BE::new_from_slices(&derive(key_letter, 32), &derive(iv_letter, 16)).unwrap(),
+            )),
+            _ => Stream::Aes128(Box::new(
+                Ctr128BE::new_from_slices(&derive(key_letter, 16), &derive(iv_letter, 16)).unwrap(),
+            )),
+        };
+        let mac = mac.unwrap_or_default();
+        let sha512 = mac.starts_with("hmac-sha2-512");
+        Self::Ctr {
+            stream,
+            mac: MacKey {
+                key: derive(mac_letter, if sha512 { 64 } else { 32 }),
+                sha512,
+                etm: mac.ends_with("-etm@openssh.com"),
+            },
+        }
+    }
+
+    fn block_size(&self) -> usize {
+        match self {
+            Self::Ctr { .. } => 16,
+            _ => 8,
+        }
+    }
+
+    /// Whether the length field is left out of the block alignment.
+    fn length_apart(&self) -> bool {
+        match self {
+            Self::None => false,
+            Self::ChaCha { .. } => true,
+            Self::Ctr { mac, .. } => mac.etm,
+        }
+    }
+
+    fn tag_len(&self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::ChaCha { .. } => 16,
+            Self::Ctr { mac, .. } => mac.len(),
+        }
+    }
+}
+
+fn chacha(key: &[u8; 32], seq: u32) -> ChaCha20Legacy {
+    ChaCha20Legacy::new(key.into(), &u64::from(seq).to_be_bytes().into())
+}
+
+/// The Poly1305 tag of a chacha20-poly1305 packet, keyed from the first
+/// block of the main key stream.
+fn poly1305_tag(main: &[u8; 32], seq: u32, packet: &[u8]) -> [u8; 16] {
+    let mut poly_key = [0u8; 32];
+    chacha(main, seq).apply_keystream(&mut poly_key);
+    Poly1305::new(&poly_key.into())
+        .compute_unpadded(packet)
+        .into()
+}
+
+/// Packets one side sends.
+struct Outgoing {
+    keys: Protection,
+    seq: u32,
+}
+
+impl Outgoing {
+    fn seal(&mut self, payload: &[u8]) -> Vec<u8> {
+        let block = self.keys.block_size();
+        let aligned = payload.len() + if self.keys.length_apart() { 1 } else { 5 };
+        let mut padding = block - aligned % block;
+        if padding < 4 {
+            padding += block;
+        }
+        let len = 1 + payload.len() + padding;
+        let mut packet = Vec::with_capacity(4 + len + self.keys.tag_len());
+        packet.extend((len as u32).to_be_bytes());
+        packet.push(padding as u8);
+        packet.extend(payload);
+        packet.extend(&random::<32>()[..padding]);
+
+        let seq = self.seq;
+        match &mut self.keys {
+            Protection::None => {}
+            Protection::ChaCha { main, header } => {
+                chacha(header, seq).apply_keystream(&mut packet[..4]);
+                let mut cipher = chacha(main, seq);
+                cipher.seek(64u64);
+                cipher.apply_keystream(&mut packet[4..]);
+                let tag = poly1305_tag(main, seq, &packet);
+                packet.extend(tag);
+            }
+            Protection::Ctr { stream, mac } if mac.etm => {
+                stream.apply(&mut packet[4..]);
+                let tag = mac.tag(seq, &packet);
+                packet.extend(tag);
+            }
+            Protection::Ctr { stream, mac } => {
+                let tag = mac.tag(seq, &packet);
+                stream.apply(&mut packet);
+                packet.extend(tag);
+            }
+        }
+        self.seq = seq.wrapping_add(1);
+        packet
+    }
+}
+
+/// Packets the other side sent, opened as they become complete.
+struct Incoming {
+    keys: Protection,
+    seq: u32,
+    buf: Vec<u8>,
+    /// The decrypted first block of an AES-CTR packet that has not fully
+    /// arrived; the key stream cannot be rewound.
+    head: Option<Vec<u8>>,
+}
+
+impl Incoming {
+    fn next(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let seq = self.seq;
+        let len = match &mut self.keys {
+            Protection::ChaCha { header, .. } => {
+                let Some(head) = self.buf.get(..4) else {
+                    return Ok(None);
+                };
+                let mut len = [0u8; 4];
+                len.copy_from_slice(head);
+                chacha(header, seq).apply_keystream(&mut len);
+                u32::from_be_bytes(len)
+            }
+            Protection::Ctr { stream, mac } if !mac.etm => {
+                if self.head.is_none() {
+                    let Some(head) = self.buf.get(..16) else {
+                        return Ok(None);
+                    };
+                    let mut head = head.to_vec();
+                    stream.apply(&mut head);
+                    self.head = Some(head);
+                }
+                u32::from_be_bytes(self.head.as_ref().unwrap()[..4].try_into().unwrap())
+            }
+            _ => match self.buf.get(..4) {
+                Some(head) => u32::from_be_bytes(head.try_into().unwrap()),
+                None => return Ok(None),
+            },
+        } as usize;
+        let aligned = if self.keys.length_apart() {
+            len
+        } else {
+            len + 4
+        };
+        if !(5..=MAX_PACKET).contains(&len) || aligned % self.keys.block_size() != 0 {
+            return Err(protocol("corrupt ssh packet"));
+        }
+        let tag_len = self.keys.tag_len();
+        if self.buf.len() < 4 + len + tag_len {
+            return Ok(None);
+        }
+
+        let mut packet: Vec<u8> = self.buf.drain(..4 + len + tag_len).collect();
+        let tag = packet.split_off(4 + len);
+        let verified = match &mut self.keys {
+            Protection::None => true,
+            Protection::ChaCha { main, .. } => {
+                let verified = poly1305_tag(main, seq, &packet).ct_eq(&tag).into();
+                let mut cipher = chacha(main, seq);
+                cipher.seek(64u64);
+                cipher.apply_keystream(&mut packet[4..]);
+                verified
+            }
+            Protection::Ctr { stream, mac } if mac.etm => {
+                let verified = mac.tag(seq, &packet).ct_eq(&tag).into();
+                stream.apply(&mut packet[4..]);
+                verified
+            }
+            Protection::Ctr { stream, mac } => {
+                let head = self.head.take().unwrap();
+                packet[..16].copy_from_slice(&head);
+                stream.apply(&mut packet[16..]);
+                mac.tag(seq, &packet).ct_eq(&tag).into()
+            }
+        };
+        if !verified {
+            return Err(protocol("ssh packet failed authentication"));
+        }
+        self.seq = seq.wrapping_add(1);
+
+        let padding = packet[4] as usize;
+        if padding + 1 > len {
+            return Err(malformed());
+        }
+        Ok(Some(packet[5..4 + len - padding].to_vec()))
+    }
+}
+
+#[derive(Clone)]
+enum Role {
+    Client,
+    #[cfg(test)]
+    Server(Box<ed25519_dalek::SigningKey>),
+}
+
+/// The SSH transport layer over TCP: key exchange, encryption and
+/// integrity. Sends and receives the messages of the layers above.
+pub struct Transport {
+    stream: TcpStream,
+    role: Role,
+    outgoing: Outgoing,
+    incoming: Incoming,
+    local_version: Vec<u8>,
+    remote_version: Vec<u8>,
+    host_key_algorithms: Vec<&'static str>,
+    ciphers: Vec<&'static str>,
+    macs: Vec<&'static str>,
+    session_id: Vec<u8>,
+    host_key: Vec<u8>,
+    strict: bool,
+    read_timeout: Option<Duration>,
+}
+
+impl Transport {
+    fn new(stream: TcpStream, role: Role) -> Self {
+        Self {
+            stream,
+            role,
+            outgoing: Outgoing {
+                keys: Protection::None,
+                seq: 0,
+            },
+            incoming: Incoming {
+                keys: Protection::None,
+                seq: 0,
+                buf: Vec::new(),
+                head: None,
+            },
+            local_version: VERSION.into(),
+            remote_version: Vec::new(),
+            host_key_algorithms: HOST_KEY_ALGORITHMS.to_vec(),
+            ciphers: CIPHERS.to_vec(),
+            macs: MACS.to_vec(),
+            session_id: Vec::new(),
+            host_key: Vec::new(),
+            strict: false,
+            read_timeout: None,
+        }
+    }
+
+    /// Starts a client session on `stream`, offering the host key
+    /// algorithms in the order given.
+    pub fn connect(stream: TcpStream, host_key_algorithms: Vec<&'static str>) -> io::Result<Self> {
+        let mut transport = Self::new(stream, Role::Client);
+        transport.host_key_algorithms = host_key_algorithms;
+        transport.exchange_versions()?;
+        transport.exchange_keys(None)?;
+        Ok(transport)
+    }
+
+    /// Starts a server session on `stream` with an Ed25519 host key,
+    /// offering only `ciphers` and `macs`.
+    #[cfg(test)]
+    pub fn accept(
+        stream: TcpStream,
+        host_key: ed25519_dalek::SigningKey,
+        ciphers: &[&'static str],
+        macs: &[&'static str],
+    ) -> io::Result<Self> {
+        let mut transport = Self::new(stream, Role::Server(Box::new(host_key)));
+        transport.host_key_algorithms = vec!["ssh-ed25519"];
+        transport.ciphers = ciphers.to_vec();
+        transport.macs = macs.to_vec();
+        transport.exchange_versions()?;
+        transport.exchange_keys(None)?;
+        Ok(transport)
+    }
+
+    /// The server's host key in the SSH wire format.
+    pub fn host_key(&self) -> &[u8] {
+        &self.host_key
+    }
+
+    pub fn send(&mut self, payload: &[u8]) -> io::Result<()> {
+        let packet = self.outgoing.seal(payload);
+        self.stream.write_all(&packet)
+    }
+
+    /// Waits for the next message of the layers above.
+    pub fn recv(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(payload) = self.read_message(None)? {
+                return Ok(payload);
+            }
+        }
+    }
+
+    /// Like `recv`, but gives up after about `timeout` without data.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        self.read_message(Some(timeout.max(Duration::from_millis(1))))
+    }
+
+    fn read_message(&mut self, timeout: Option<Duration>) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            while let Some(payload) = self.incoming.next()? {
+                match payload.first() {
+                    None => return Err(malformed()),
+                    Some(&(MSG_IGNORE | MSG_DEBUG | MSG_UNIMPLEMENTED | MSG_EXT_INFO)) => {}
+                    Some(&MSG_DISCONNECT) => return Err(disconnected(&payload)),
+                    Some(&MSG_KEXINIT) => self.exchange_keys(Some(payload))?,
+                    Some(_) => return Ok(Some(payload)),
+                }
+            }
+            if !self.fill(timeout)? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Reads what the socket has, or returns false after `timeout`.
+    fn fill(&mut self, timeout: Option<Duration>) -> io::Result<bool> {
+        if self.read_timeout != timeout {
+            self.stream.set_read_timeout(timeout)?;
+            self.read_timeout = timeout;
+        }
+        let mut buf = [0u8; 16 * 1024];
+        match self.stream.read(&mut buf) {
+            Ok(0) => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed",
+            )),
+            Ok(n) => {
+                self.incoming.buf.extend(&buf[..n]);
+                Ok(true)
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Ok(false)
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn exchange_versions(&mut self) -> io::Result<()> {
+        self.stream.write_all(&self.local_version)?;
+        self.stream.write_all(b"\r\n")?;
+        for _ in 0..MAX_BANNER_LINES {
+            let mut line = Vec::new();
+            let mut byte = [0u8];
+            while line.len() < 255 {
+                self.stream.read_exact(&mut byte)?;
+                if byte[0] == b'\n' {
+                    break;
+                }
+                line.push(byte[0]);
+            }
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            if line.starts_with(b"SSH-2.0-") || line.starts_with(b"SSH-1.99-") {
+                self.remote_version = line;
+                return Ok(());
+            }
+            if line.starts_with(b"SSH-") {
+                return Err(protocol(format!(
+                    "unsupported protocol version {}",
+                    String::from_utf8_lossy(&line)
+                )));
+            }
+        }
+        Err(protocol("no ssh version from the server"))
+    }
+
+    fn kexinit(&self, first: bool) -> Vec<u8> {
+        let mut kex = KEX.to_vec();
+        if first {
+            kex.push(match self.role {
+                Role::Client => STRICT_CLIENT,
+                #[cfg(test)]
+                Role::Server(_) => STRICT_SERVER,
+            });
+        }
+        Encoder::new(MSG_KEXINIT)
+            .raw(&random::<16>())
+            .name_list(&kex)
+            .name_list(&self.host_key_algorithms)
+            .name_list(&self.ciphers)
+            .name_list(&self.ciphers)
+            .name_list(&self.macs)
+            .name_list(&self.macs)
+            .name_list(&["none"])
+            .name_list(&["none"])
+            .name_list(&[])
+            .name_list(&[])
+            .bool(false)
+            .u32(0)
+            .into_bytes()
+    }
+
+    /// Reads the next key exchange message, which must be `expected`.
+    fn kex_message(&mut self, expected: u8) -> io::Result<Vec<u8>> {
+        loop {
+            match self.incoming.next()? {
+                Some(payload) => match payload.first() {
+                    Some(&m) if m == expected => return Ok(payload),
+                    Some(&(MSG_IGNORE | MSG_DEBUG)) if !self.strict => {}
+                    Some(&MSG_DISCONNECT) => return Err(disconnected(&payload)),
+                    _ => return Err(protocol("unexpected message during key exchange")),
+                },
+                None => {
+                    self.fill(None)?;
+                }
+            }
+        }
+    }
+
+    fn exchange_keys(&mut self, theirs: Option<Vec<u8>>) -> io::Result<()> {
+        let first = self.session_id.is_empty();
+        let ours = self.kexinit(first);
+        self.send(&ours)?;
+        let theirs = match theirs {
+            Some(theirs) => theirs,
+            None => self.kex_message(MSG_KEXINIT)?,
+        };
+        let client = matches!(self.role, Role::Client);
+        let (client_init, server_init) = if client {
+            (&ours, &theirs)
+        } else {
+            (&theirs, &ours)
+        };
+
+        let name_lists = |init: &[u8]| -> io::Result<(Vec<Vec<String>>, bool)> {
+            let mut d = Decoder::new(init.get(17..).ok_or_else(malformed)?);
+            let lists = (0..10).map(|_| d.name_list()).collect::<io::Result<_>>()?;
+            Ok((lists, d.bool()?))
+        };
+        let (client_lists, _) = name_lists(client_init)?;
+        let (server_lists, _) = name_lists(server_init)?;
+        let choose = |i: usize, what: &str| {
+            client_lists[i]
+                .iter()
+                .find(|name| server_lists[i].contains(name))
+                .cloned()
+                .ok_or_else(|| protocol(format!("no {what} in common with the server")))
+        };
+        let kex = choose(0, "key exchange method")?;
+        if !KEX.contains(&kex.as_str()) {
+            return Err(protocol("no key exchange method in common with the server"));
+        }
+        let host_key_algorithm = choose(1, "host key algorithm")?;
+        let cipher_cs = choose(2, "cipher")?;
+        let cipher_sc = choose(3, "cipher")?;
+        // The AEAD cipher brings its own MAC.
+        let mac_cs = (cipher_cs != CIPHERS[0])
+            .then(|| choose(4, "MAC"))
+            .transpose()?;
+        let mac_sc = (cipher_sc != CIPHERS[0])
+            .then(|| choose(5, "MAC"))
+            .transpose()?;
+        choose(6, "compression")?;
+        choose(7, "compression")?;
+
+        let (their_lists, guessed) = name_lists(&theirs)?;
+        if first {
+            let marker = if client { STRICT_SERVER } else { STRICT_CLIENT };
+            self.strict = their_lists[0].iter().any(|name| name == marker);
+            if self.strict && self.incoming.seq != 1 {
+                return Err(protocol("strict key exchange violated"));
+            }
+        }
+        // A wrong guess of the peer is dropped unseen.
+        if guessed && (their_lists[0][0] != kex || their_lists[1][0] != host_key_algorithm) {
+            while self.incoming.next()?.is_none() {
+                self.fill(None)?;
+            }
+        }
+
+        let secret = StaticSecret::from(random::<32>());
+        let public = x25519_dalek::PublicKey::from(&secret);
+        let (v_c, v_s) = if client {
+            (self.local_version.clone(), self.remote_version.clone())
+        } else {
+            (self.remote_version.clone(), self.local_version.clone())
+        };
+        let exchange_hash = |host_key: &[u8], q_c: &[u8], q_s: &[u8], k: &[u8]| {
+            let data = Encoder::default()
+                .string(&v_c)
+                .string(&v_s)
+                .string(client_init)
+                .string(server_init)
+                .string(host_key)
+                .string(q_c)
+                .string(q_s)
+                .raw(k)
+                .into_bytes();
+            Sha256::digest(data).to_vec()
+        };
+        let (host_key, h, k) = match self.role.clone() {
+            Role::Client => {
+                let init = Encoder::new(MSG_KEX_ECDH_INIT).string(public.as_bytes());
+                self.send(&init.into_bytes())?;
+                let reply = self.kex_message(MSG_KEX_ECDH_REPLY)?;
+                let mut d = Decoder::new(&reply[1..]);
+                let (host_key, q_s, signature) = (d.string()?, d.string()?, d.string()?);
+                let k = mpint(&agree(&secret, q_s)?);
+                let h = exchange_hash(host_key, public.as_bytes(), q_s, &k);
+                verify(&host_key_algorithm, host_key, signature, &h)?;
+                if !first && host_key != self.host_key {
+                    return Err(protocol("host key changed during rekeying"));
+                }
+                (host_key.to_vec(), h, k)
+            }
+            #[cfg(test)]
+            Role::Server(key) => {
+                use ed25519_dalek::Signer;
+                let init = self.kex_message(MSG_KEX_ECDH_INIT)?;
+                let q_c = Decoder::new(&init[1..]).string()?;
+                let k = mpint(&agree(&secret, q_c)?);
+                let public_key = ssh_key::public::Ed25519PublicKey(key.verifying_key().to_bytes());
+                let host_key = PublicKey::new(public_key.into(), "").to_bytes().unwrap();
+                let h = exchange_hash(&host_key, q_c, public.as_bytes(), &k);
+                let signature = Encoder::default()
+                    .string("ssh-ed25519")
+                    .string(key.sign(&h).to_bytes());
+                let reply = Encoder::new(MSG_KEX_ECDH_REPLY)
+                    .string(&host_key)
+                    .string(public.as_bytes())
+                    .string(signature.into_bytes());
+                self.send(&reply.into_bytes())?;
+                (host_key, h, k)
+            }
+        };
+        self.host_key = host_key;
+        if first {
+            self.session_id = h.clone();
+        }
+
+        let derive = |letter: u8, len: usize| {
+            let mut key = Sha256::new()
+                .chain_update(&k)
+                .chain_update(&h)
+                .chain_update([letter])
+                .chain_update(&self.session_id)
+                .finalize()
+                .to_vec();
+            while key.len() < len {
+                let more = Sha256::new()
+                    .chain_update(&k)
+                    .chain_update(&h)
+                    .chain_update(&key)
+                    .finalize();
+                key.extend(more);
+            }
+            key.truncate(len);
+            key
+        };
+        let client_keys = Protection::new(&cipher_cs, mac_cs.as_deref(), derive, *b"ACE");
+        let server_keys = Protection::new(&cipher_sc, mac_sc.as_deref(), derive, *b"BDF");
+        let (send_keys, recv_keys) = if client {
+            (client_keys, server_keys)
+        } else {
+            (server_keys, client_keys)
+        };
+
+        self.send(&[MSG_NEWKEYS])?;
+        self.outgoing.keys = send_keys;
+        if self.strict {
+            self.outgoing.seq = 0;
+        }
+        self.kex_message(MSG_NEWKEYS)?;
+        self.incoming.keys = recv_keys;
+        if self.strict {
+            self.incoming.seq = 0;
+        }
+        Ok(())
+    }
+}
+
+/// An unsigned big-endian number encoded as an mpint.
+fn mpint(bytes: &[u8]) -> Vec<u8> {
+    Encoder::default().mpint(bytes).into_bytes()
+}
+
+/// The X25519 shared secret with the peer's public value.
+fn agree(secret: &StaticSecret, peer: &[u8]) -> io::Result<[u8; 32]> {
+    let peer: [u8; 32] = peer.try_into().map_err(|_| malformed())?;
+    let shared = secret.diffie_hellman(&x25519_dalek::PublicKey::from(peer));
+    if !shared.was_contributory() {
+        return Err(protocol("invalid key exchange value from the server"));
+    }
+    Ok(shared.to_bytes())
+}
+
+/// Checks the server's signature of the exchange hash `h` with its host key.
+fn verify(algorithm: &str, host_key: &[u8], signature: &[u8], h: &[u8]) -> io::Result<()> {
+    let key = PublicKey::from_bytes(host_key).map_err(|e| protocol(format!("host key: {e}")))?;
+    let mut d = Decoder::new(signature);
+    let name = d.text()?;
+    if name != algorithm {
+        return Err(protocol(format!(
+            "host key signature uses {name}, not {algorithm}"
+        )));
+    }
+    let data = d.string()?.to_vec();
+    let signature = Algorithm::new(&name)
+        .and_then(|alg| Signature::new(alg, data))
+        .map_err(|e| protocol(format!("host key signature: {e}")))?;
+    Verifier::verify(&key, h, &signature)
+        .map_err(|_| protocol("the server's host key signature does not verify"))
+}
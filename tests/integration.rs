@@ -164,6 +164,11 @@ fn file_password_works() {
 
     let pw_file = std::env::temp_dir().join("sshpass_test_integration_pw");
     std::fs::write(&pw_file, format!("{}\n", TEST_PASS)).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&pw_file, std::fs::Permissions::from_mode(0o600)).unwrap();
+    }
 
     let mut args = vec!["-f".to_string(), pw_file.to_string_lossy().to_string()];
     args.extend(ssh_args());
@@ -412,3 +417,40 @@ fn ctrl_d_closes_session() {
         String::from_utf8_lossy(&output.stderr)
     );
 }
+
+#[cfg(unix)]
+#[test]
+fn non_utf8_arguments_reach_the_command() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let output = Command::new(sshpass_bin())
+        .args(["-p", TEST_PASS, "printf", "%s"])
+        .arg(OsStr::from_bytes(b"caf\xe9"))
+        .stdin(Stdio::null())
+        .output()
+        .expect("failed to run sshpass");
+
+    assert_eq!(
+        output.status.code(),
+        Some(0),
+        "stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(output.stdout, b"caf\xe9");
+}
+
+#[cfg(feature = "native-ssh")]
+#[test]
+fn put_rejects_conflicting_password_sources() {
+    let output = Command::new(sshpass_bin())
+        .args(["put", "-e", "-f", "/dev/null", "Cargo.toml"])
+        .arg(format!("{}@127.0.0.1:/tmp/", TEST_USER))
+        .env("SSHPASS", TEST_PASS)
+        .stdin(Stdio::null())
+        .output()
+        .expect("failed to run sshpass");
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("conflicting password source"));
+}